@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+use crate::audit_entry::AuditEntry;
+use crate::budget::Budget;
+use crate::transaction::Transaction;
+
+/// Format version of `AccountArchive`, bump whenever its layout changes
+pub const ACCOUNT_ARCHIVE_VERSION: i64 = 1;
+
+/// Everything tied to a user's account, as downloaded by the user and
+/// imported into another server by an admin
+///
+/// IDs are those of the server the archive was made on and are replaced on import.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountArchive {
+    pub version: i64,
+    pub exported_at: String,
+    pub user: ArchivedUser,
+    pub budgets: Vec<ArchivedBudget>, // Budgets owned by the user
+    pub shared_budgets: Vec<SharedBudget>, // Budgets of other users shared with the user
+    pub shared_transactions: Vec<Transaction>, // The user's transactions in shared budgets
+    pub activity: Vec<AuditEntry> // Audit log entries of changes made by the user
+}
+
+/// Profile of the archived user, the password is never included
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedUser {
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub is_admin: bool,
+    pub timezone: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedBudget {
+    pub budget: Budget,
+    pub grants: Vec<String>, // Emails of the users the budget is shared with
+    pub transactions: Vec<Transaction> // Including those in the trash
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SharedBudget {
+    pub budget_id: i64,
+    pub name: String,
+    pub owner: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdMapping {
+    pub old_id: i64,
+    pub new_id: i64
+}
+
+/// Outcome of importing an `AccountArchive`
+///
+/// Shared budgets belong to other users so they, and the user's transactions
+/// in them, are not imported. Grants to users that don't exist on this server
+/// are skipped and transactions written by them are attributed to the imported user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountImport {
+    pub email: String,
+    pub temporary_password: String, // Must be changed by the user after signing in
+    pub budget_ids: Vec<IdMapping>,
+    pub transaction_ids: Vec<IdMapping>,
+    pub skipped_grants: Vec<String>,
+    pub reassigned_transactions: i64,
+    pub skipped_shared_transactions: i64
+}
@@ -1,23 +1,69 @@
-use actix_web::{web, Responder, Scope};
+use actix_web::{error, web, HttpRequest, HttpResponse, Responder, Scope};
+use crypto::util::fixed_time_eq;
+use futures::{stream, Stream};
 
 use crate::transaction::Transaction;
 use crate::budget::Budget;
-use crate::database::{User};
+use crate::csv_import::parse_csv;
+use crate::export::{ExportChunks, EXPORT_PAGE_SIZE};
+use crate::ofx_import::parse_ofx;
+use crate::qif_import::parse_qif;
+use crate::transaction::TransactionKind;
+use crate::database::{Database, Error, User};
+use crate::notifier::Message;
 use crate::shared::*;
 use crate::util::*;
 
 use crate::AppState;
 
+use std::path::Path;
+use std::time::Instant;
+
+// Compared against when logging in with an unknown email so that it takes as
+// long as a wrong password, the same length as a real password hash
+const DUMMY_PASSWORD_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
 pub fn get_service() -> Scope {
     web::scope("/api")
         .route("/register_user", web::post().to(register_user))
         .route("/get_access_token", web::post().to(get_access_token))
+        .route("/verify_two_factor", web::post().to(verify_two_factor))
+        .route("/oidc/authorize", web::post().to(oidc_authorize))
+        .route("/oidc/callback", web::post().to(oidc_callback))
+        .route("/oidc/link", web::post().to(oidc_link))
+        .route("/oidc/unlink", web::post().to(oidc_unlink))
+        .route("/two_factor/enroll", web::post().to(enroll_two_factor))
+        .route("/two_factor/confirm", web::post().to(confirm_two_factor))
+        .route("/two_factor/disable", web::post().to(disable_two_factor))
+        .route("/two_factor/recovery_codes", web::post().to(regenerate_recovery_codes))
+        .route("/add/api_key", web::post().to(add_api_key))
+        .route("/list/api_keys", web::post().to(list_api_keys))
+        .route("/revoke/api_key", web::post().to(revoke_api_key))
         .route("/change_password", web::post().to(change_password))
+        .route("/request_password_reset", web::post().to(request_password_reset))
+        .route("/confirm_password_reset", web::post().to(confirm_password_reset))
+        .route("/verify_email", web::post().to(verify_email))
+        .route("/resend_verification", web::post().to(resend_verification))
+        .route("/set/user/timezone", web::post().to(set_user_timezone))
+        .route("/export/account", web::post().to(export_account))
+        .route("/import/account", web::post().to(import_account))
+        .route("/admin/backup", web::post().to(create_backup))
+        .route("/admin/create/invite_code", web::post().to(create_invite_code))
+        .route("/admin/list/pending_users", web::post().to(list_pending_users))
+        .route("/admin/approve_user", web::post().to(approve_user))
+        .route("/admin/reject_user", web::post().to(reject_user))
+        .route("/admin/list/locked_users", web::post().to(list_locked_users))
+        .route("/admin/unlock_user", web::post().to(unlock_user))
         .route("/list/budgets", web::post().to(list_budgets))
         .route("/add/budget", web::post().to(add_budget))
         .route("/delete/budget", web::post().to(delete_budget))
+        .route("/archive/budget", web::post().to(archive_budget))
+        .route("/unarchive/budget", web::post().to(unarchive_budget))
+        .route("/set/budget/timezone", web::post().to(set_budget_timezone))
+        .route("/set/budget/rollover", web::post().to(set_budget_rollover))
         .route("/get/budget", web::post().to(get_budget))
         .route("/get/budget/spent", web::post().to(get_budget_spent))
+        .route("/get/budget/summary", web::post().to(get_budget_summary))
         .route("/get/budget/current_period", web::post().to(get_budget_current_period))
         .route("/get/budget/period", web::post().to(get_budget_period))
         .route("/list/can_access_budget", web::post().to(list_can_access_budget))
@@ -25,22 +71,53 @@ pub fn get_service() -> Scope {
         .route("/delete/can_access_budget", web::post().to(delete_can_access_budget))
         .route("/list/transactions", web::post().to(list_transactions))
         .route("/list/transactions/period", web::post().to(list_transactions_period))
+        .route("/list/transactions/trash", web::post().to(list_transactions_trash))
+        .route("/list/transactions/scheduled", web::post().to(list_transactions_scheduled))
+        .route("/search/transactions", web::post().to(search_transactions))
         .route("/add/transaction", web::post().to(add_transaction))
+        .route("/delete/transaction", web::post().to(delete_transaction))
+        .route("/restore/transaction", web::post().to(restore_transaction))
+        .route("/import/csv", web::post().to(import_csv))
+        .route("/import/ofx", web::post().to(import_ofx))
+        .route("/import/qif", web::post().to(import_qif))
+        .route("/export/budget", web::post().to(export_budget))
         .route("/list/budget_periods", web::post().to(list_budget_periods))
+        .route("/list/budget_periods/balances", web::post().to(list_budget_period_balances))
+        .route("/list/audit", web::post().to(list_audit))
 }
 
 // API Routes
 
 fn register_user(data: web::Data<AppState>, json: web::Json<RegisterAccountForm>) -> impl Responder {
-    let database = data.database.lock().unwrap();
+    let res = {
+        let database = data.database.lock().unwrap();
 
-    let user = User::new(&database, &json.email, &json.first_name, &json.last_name, &json.password, false);
+        let mut user = User::new(&database, &json.email, &json.first_name, &json.last_name, &json.password, false);
+        let lifetime = chrono::Duration::hours(data.config.email_verification_hours);
+        let invite_code = json.invite_code.as_ref().map(|x| x.as_str());
 
-    match database.insert_user(&user) {
-        Ok(_) => web::Json(AccessTokenResult {
-            status: ResultStatus::Success,
-            access_token: Some(user.access_token),
-        }),
+        database
+            .register_user(&mut user, data.config.registration_mode, invite_code)
+            .and_then(|_| database.request_email_verification(&user.email, lifetime))
+            .map(|token| {
+                // Accounts waiting for approval can't sign in yet
+                let access_token = if user.approved { Some(user.access_token) } else { None };
+
+                (access_token, token)
+            })
+    };
+
+    match res {
+        Ok((access_token, token)) => {
+            if let Some(token) = token {
+                send_verification_email(&data, &json.email, &token);
+            }
+
+            web::Json(AccessTokenResult {
+                status: ResultStatus::Success,
+                access_token,
+            })
+        }
         Err(error) => web::Json(AccessTokenResult {
             status: ResultStatus::Error(String::from(format!(
                 "Error occurred while registering user: {:?}",
@@ -51,34 +128,136 @@ fn register_user(data: web::Data<AppState>, json: web::Json<RegisterAccountForm>
     }
 }
 
-fn get_access_token(data: web::Data<AppState>, json: web::Json<CredentialForm>) -> impl Responder {
+fn get_access_token(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    json: web::Json<CredentialForm>,
+) -> impl Responder {
+    let error = |error: Error| {
+        web::Json(LoginResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while getting user access token: {:?}",
+                error
+            ))),
+            access_token: None,
+            two_factor_token: None,
+        })
+    };
+
+    // Behind a reverse proxy every request comes from the proxy's address
+    let ip = req.peer_addr().map(|x| x.ip());
+
+    if let Some(ip) = ip {
+        if data.login_throttle.lock().unwrap().retry_after(&ip, Instant::now()).is_some() {
+            return error(Error::TooManyAttempts);
+        }
+    }
+
     let database = data.database.lock().unwrap();
 
-    let user = database.get_user_by_email(&json.email);
+    // Checked before the password so that locked accounts can't be guessed at
+    if let Err(x) = database.check_login_allowed(&json.email) {
+        return error(x);
+    }
+
+    let user = match database.get_user_by_email(&json.email) {
+        Ok(x) => x,
+        Err(x) => return error(x),
+    };
 
-    match user {
-        Ok(user) => match user {
-            Some(user) => {
-                if user.password == database.hash(&json.password) {
-                    web::Json(AccessTokenResult {
-                        status: ResultStatus::Success,
-                        access_token: Some(user.access_token),
-                    })
-                } else {
-                    web::Json(AccessTokenResult {
-                        status: ResultStatus::InvalidCredentials,
-                        access_token: None,
-                    })
-                }
+    let expected_password = user.as_ref().map_or(DUMMY_PASSWORD_HASH, |x| x.password.as_str());
+    let password_matches = fixed_time_eq(
+        database.hash(&json.password).as_bytes(),
+        expected_password.as_bytes(),
+    );
+
+    let user = match user {
+        Some(x) if password_matches => x,
+        _ => {
+            let lockout = chrono::Duration::minutes(data.config.login_lockout_minutes);
+
+            if let Err(x) = database.record_login_failure(&json.email, data.config.login_lockout_attempts, lockout) {
+                return error(x);
             }
-            None => web::Json(AccessTokenResult {
+
+            if let Some(ip) = ip {
+                data.login_throttle.lock().unwrap().record_failure(ip, Instant::now());
+            }
+
+            return web::Json(LoginResult {
                 status: ResultStatus::InvalidCredentials,
                 access_token: None,
-            }),
-        },
+                two_factor_token: None,
+            });
+        }
+    };
+
+    let email = user.email.clone();
+
+    match finish_login(&database, user) {
+        Ok(x) => {
+            // Accounts with two-factor authentication aren't signed in until
+            // `verify_two_factor`, which clears their failures instead
+            if x.access_token.is_some() {
+                if let Err(x) = database.clear_login_failures(&email) {
+                    return error(x);
+                }
+
+                if let Some(ip) = ip {
+                    data.login_throttle.lock().unwrap().record_success(&ip);
+                }
+            }
+
+            web::Json(x)
+        }
+        Err(x) => error(x),
+    }
+}
+
+fn verify_two_factor(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    json: web::Json<TwoFactorLoginForm>,
+) -> impl Responder {
+    let ip = req.peer_addr().map(|x| x.ip());
+
+    let throttled = ip.map_or(false, |ip| {
+        data.login_throttle.lock().unwrap().retry_after(&ip, Instant::now()).is_some()
+    });
+
+    let res = if throttled {
+        Err(Error::TooManyAttempts)
+    } else {
+        let database = data.database.lock().unwrap();
+        let lockout = chrono::Duration::minutes(data.config.login_lockout_minutes);
+
+        database.finish_two_factor_login(
+            &json.two_factor_token,
+            &json.code,
+            data.config.login_lockout_attempts,
+            lockout,
+        )
+    };
+
+    if let Some(ip) = ip {
+        match &res {
+            Ok(_) => data.login_throttle.lock().unwrap().record_success(&ip),
+            // Wrong codes count towards the same limit as wrong passwords
+            Err(Error::InvalidTwoFactorCode) => {
+                data.login_throttle.lock().unwrap().record_failure(ip, Instant::now())
+            }
+            Err(_) => (),
+        }
+    }
+
+    match res {
+        Ok(access_token) => web::Json(AccessTokenResult {
+            status: ResultStatus::Success,
+            access_token: Some(access_token),
+        }),
         Err(error) => web::Json(AccessTokenResult {
             status: ResultStatus::Error(String::from(format!(
-                "Error occurred while getting user access token: {:?}",
+                "Error occurred while verifying two-factor code: {:?}",
                 error
             ))),
             access_token: None,
@@ -86,6 +265,239 @@ fn get_access_token(data: web::Data<AppState>, json: web::Json<CredentialForm>)
     }
 }
 
+fn oidc_authorize(data: web::Data<AppState>) -> impl Responder {
+    let res = match &data.oidc {
+        Some(oidc) => oidc.authorization_url(),
+        None => Err(String::from("single sign-on isn't configured")),
+    };
+
+    match res {
+        Ok(url) => web::Json(OidcAuthorizeResult {
+            status: ResultStatus::Success,
+            authorization_url: Some(url),
+        }),
+        Err(message) => web::Json(OidcAuthorizeResult {
+            status: ResultStatus::Error(format!(
+                "Error occurred while starting single sign-on: {}",
+                message
+            )),
+            authorization_url: None,
+        }),
+    }
+}
+
+fn oidc_callback(data: web::Data<AppState>, json: web::Json<OidcCallbackForm>) -> impl Responder {
+    let error = |message: String| {
+        web::Json(LoginResult {
+            status: ResultStatus::Error(format!(
+                "Error occurred while signing in with single sign-on: {}",
+                message
+            )),
+            access_token: None,
+            two_factor_token: None,
+        })
+    };
+
+    let oidc = match &data.oidc {
+        Some(x) => x,
+        None => return error(String::from("single sign-on isn't configured")),
+    };
+
+    // The identity provider is contacted without holding the database lock
+    let claims = match oidc.complete(&json.code, &json.state) {
+        Ok(x) => x,
+        Err(message) => return error(message),
+    };
+
+    let database = data.database.lock().unwrap();
+
+    let res = database
+        .login_with_external_identity(
+            &claims,
+            oidc.link_existing_users(),
+            oidc.create_users(),
+            data.config.registration_mode,
+        )
+        .and_then(|user| finish_login(&database, user));
+
+    match res {
+        Ok(x) => web::Json(x),
+        Err(x) => error(format!("{:?}", x)),
+    }
+}
+
+fn oidc_link(data: web::Data<AppState>, json: web::Json<OidcLinkForm>) -> impl Responder {
+    let res = match &data.oidc {
+        Some(oidc) => oidc.complete(&json.code, &json.state).and_then(|claims| {
+            let database = data.database.lock().unwrap();
+
+            database
+                .link_external_identity(&json.access_token, &claims)
+                .map_err(|error| format!("{:?}", error))
+        }),
+        None => Err(String::from("single sign-on isn't configured")),
+    };
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(message) => web::Json(StatusResult {
+            status: ResultStatus::Error(format!(
+                "Error occurred while linking single sign-on account: {}",
+                message
+            ))
+        }),
+    }
+}
+
+fn oidc_unlink(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let res = match &data.oidc {
+        Some(oidc) => {
+            let database = data.database.lock().unwrap();
+
+            database
+                .unlink_external_identity(&json.access_token, oidc.issuer())
+                .map_err(|error| format!("{:?}", error))
+        }
+        None => Err(String::from("single sign-on isn't configured")),
+    };
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(message) => web::Json(StatusResult {
+            status: ResultStatus::Error(format!(
+                "Error occurred while unlinking single sign-on account: {}",
+                message
+            ))
+        }),
+    }
+}
+
+fn enroll_two_factor(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.enroll_two_factor(&json.access_token) {
+        Ok(enrollment) => web::Json(TwoFactorEnrollmentResult {
+            status: ResultStatus::Success,
+            enrollment: Some(enrollment),
+        }),
+        Err(error) => web::Json(TwoFactorEnrollmentResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while enrolling in two-factor authentication: {:?}",
+                error
+            ))),
+            enrollment: None,
+        }),
+    }
+}
+
+fn confirm_two_factor(data: web::Data<AppState>, json: web::Json<TwoFactorCodeForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.confirm_two_factor(&json.access_token, &json.code) {
+        Ok(recovery_codes) => web::Json(RecoveryCodesResult {
+            status: ResultStatus::Success,
+            recovery_codes: Some(recovery_codes),
+        }),
+        Err(error) => web::Json(RecoveryCodesResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while confirming two-factor authentication: {:?}",
+                error
+            ))),
+            recovery_codes: None,
+        }),
+    }
+}
+
+fn disable_two_factor(data: web::Data<AppState>, json: web::Json<TwoFactorCodeForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.disable_two_factor(&json.access_token, &json.code) {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while disabling two-factor authentication: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn regenerate_recovery_codes(data: web::Data<AppState>, json: web::Json<TwoFactorCodeForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.regenerate_recovery_codes(&json.access_token, &json.code) {
+        Ok(recovery_codes) => web::Json(RecoveryCodesResult {
+            status: ResultStatus::Success,
+            recovery_codes: Some(recovery_codes),
+        }),
+        Err(error) => web::Json(RecoveryCodesResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while regenerating recovery codes: {:?}",
+                error
+            ))),
+            recovery_codes: None,
+        }),
+    }
+}
+
+fn add_api_key(data: web::Data<AppState>, json: web::Json<AddApiKeyForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.create_api_key(&json.access_token, &json.name, json.scope, json.budget_ids.clone()) {
+        Ok(api_key) => web::Json(NewApiKeyResult {
+            status: ResultStatus::Success,
+            api_key: Some(api_key),
+        }),
+        Err(error) => web::Json(NewApiKeyResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while creating API key: {:?}",
+                error
+            ))),
+            api_key: None,
+        }),
+    }
+}
+
+fn list_api_keys(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.get_api_keys(&json.access_token) {
+        Ok(api_keys) => web::Json(ApiKeyListResult {
+            status: ResultStatus::Success,
+            api_keys: Some(api_keys),
+        }),
+        Err(error) => web::Json(ApiKeyListResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while getting API keys: {:?}",
+                error
+            ))),
+            api_keys: None,
+        }),
+    }
+}
+
+fn revoke_api_key(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.revoke_api_key(&json.access_token, json.id) {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while revoking API key: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
 fn change_password(
     data: web::Data<AppState>,
     json: web::Json<ChangePasswordForm>,
@@ -97,7 +509,12 @@ fn change_password(
     match user {
         Ok(user) => match user {
             Some(mut user) => {
-                if user.password == database.hash(&json.current_password) {
+                let password_matches = fixed_time_eq(
+                    database.hash(&json.current_password).as_bytes(),
+                    user.password.as_bytes(),
+                );
+
+                if password_matches {
                     // Change password + update access token
                     user.change_password(&database, &database.hash(&json.new_password));
 
@@ -125,18 +542,350 @@ fn change_password(
         },
         Err(error) => web::Json(AccessTokenResult {
             status: ResultStatus::Error(String::from(format!(
-                "Error occurred while getting user access token: {:?}",
+                "Error occurred while getting user access token: {:?}",
+                error
+            ))),
+            access_token: None,
+        }),
+    }
+}
+
+fn request_password_reset(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    json: web::Json<PasswordResetRequestForm>,
+) -> impl Responder {
+    let now = Instant::now();
+    let ip = req.peer_addr().map(|x| x.ip());
+    let email = json.email.trim().to_lowercase();
+
+    // Every request counts, whether or not the email has an account, so that
+    // throttling doesn't reveal accounts either
+    let throttled = {
+        let mut ip_throttle = data.reset_ip_throttle.lock().unwrap();
+        let mut email_throttle = data.reset_email_throttle.lock().unwrap();
+
+        let throttled = ip.map_or(false, |ip| ip_throttle.retry_after(&ip, now).is_some())
+            || email_throttle.retry_after(&email, now).is_some();
+
+        if let Some(ip) = ip {
+            ip_throttle.record_failure(ip, now);
+        }
+        email_throttle.record_failure(email, now);
+
+        throttled
+    };
+
+    let res = if throttled {
+        Err(Error::TooManyAttempts)
+    } else {
+        let database = data.database.lock().unwrap();
+        let lifetime = chrono::Duration::minutes(data.config.password_reset_minutes);
+
+        database.request_password_reset(&json.email, lifetime)
+    };
+
+    // Unknown emails get the same response so that accounts can't be discovered,
+    // and the message is sent in the background so that timing doesn't either
+    match res {
+        Ok(token) => {
+            if let Some(token) = token {
+                let message = Message::password_reset(&json.email, &token, data.config.password_reset_minutes);
+
+                if data.outbox.lock().unwrap().send(message).is_err() {
+                    println!("Error occurred while queueing password reset: outbox closed");
+                }
+            }
+
+            web::Json(StatusResult {
+                status: ResultStatus::Success,
+            })
+        }
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while requesting password reset: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn confirm_password_reset(
+    data: web::Data<AppState>,
+    json: web::Json<PasswordResetConfirmForm>,
+) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.confirm_password_reset(&json.token, &json.new_password) {
+        // Users with two-factor authentication sign in again with their new password
+        Ok(access_token) => web::Json(AccessTokenResult {
+            status: ResultStatus::Success,
+            access_token,
+        }),
+        Err(error) => web::Json(AccessTokenResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while resetting password: {:?}",
+                error
+            ))),
+            access_token: None,
+        }),
+    }
+}
+
+fn verify_email(data: web::Data<AppState>, json: web::Json<TokenForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.verify_email(&json.token) {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while verifying email: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn resend_verification(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let res = {
+        let database = data.database.lock().unwrap();
+        let lifetime = chrono::Duration::hours(data.config.email_verification_hours);
+
+        database.get_user_by_access_token(&json.access_token).and_then(|user| match user {
+            Some(user) => database
+                .request_email_verification(&user.email, lifetime)
+                .map(|token| token.map(|x| (user.email, x))),
+            None => Err(Error::InvalidCredentials),
+        })
+    };
+
+    match res {
+        Ok(sent) => {
+            // Already verified accounts are left alone
+            if let Some((email, token)) = sent {
+                send_verification_email(&data, &email, &token);
+            }
+
+            web::Json(StatusResult {
+                status: ResultStatus::Success,
+            })
+        }
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while resending verification: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn set_user_timezone(data: web::Data<AppState>, json: web::Json<UserTimezoneForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = database.set_user_timezone(&json.access_token, json.timezone.as_ref().map(|x| x.as_str()));
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred setting user timezone: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn export_account(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.export_account(&json.access_token) {
+        Ok(archive) => web::Json(AccountArchiveResult {
+            status: ResultStatus::Success,
+            archive: Some(archive),
+        }),
+        Err(error) => web::Json(AccountArchiveResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while exporting account: {:?}",
+                error
+            ))),
+            archive: None,
+        }),
+    }
+}
+
+fn import_account(data: web::Data<AppState>, json: web::Json<ImportAccountForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.import_account(&json.access_token, &json.archive) {
+        Ok(import) => web::Json(AccountImportResult {
+            status: ResultStatus::Success,
+            import: Some(import),
+        }),
+        Err(error) => web::Json(AccountImportResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while importing account: {:?}",
+                error
+            ))),
+            import: None,
+        }),
+    }
+}
+
+fn create_backup(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = database.create_backup(
+        &json.access_token,
+        Path::new(&data.config.backup_dir),
+        data.config.backup_keep
+    );
+
+    match res {
+        Ok(path) => web::Json(BackupResult {
+            status: ResultStatus::Success,
+            path: Some(path.to_string_lossy().to_string()),
+        }),
+        Err(error) => web::Json(BackupResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while backing up database: {:?}",
+                error
+            ))),
+            path: None,
+        }),
+    }
+}
+
+fn create_invite_code(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let lifetime = chrono::Duration::days(data.config.invite_code_days);
+
+    match database.create_invite_code(&json.access_token, lifetime) {
+        Ok(invite) => web::Json(InviteCodeResult {
+            status: ResultStatus::Success,
+            invite: Some(invite),
+        }),
+        Err(error) => web::Json(InviteCodeResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while creating invite code: {:?}",
+                error
+            ))),
+            invite: None,
+        }),
+    }
+}
+
+fn list_pending_users(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.get_pending_users(&json.access_token) {
+        Ok(users) => web::Json(PendingUserListResult {
+            status: ResultStatus::Success,
+            users: Some(users),
+        }),
+        Err(error) => web::Json(PendingUserListResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while getting pending users: {:?}",
+                error
+            ))),
+            users: None,
+        }),
+    }
+}
+
+fn approve_user(data: web::Data<AppState>, json: web::Json<UserForm>) -> impl Responder {
+    let res = {
+        let database = data.database.lock().unwrap();
+
+        database.approve_user(&json.access_token, &json.email)
+    };
+
+    match res {
+        Ok(_) => {
+            let message = Message {
+                to: json.email.clone(),
+                subject: String::from("Budget Tracker account approved"),
+                body: String::from("Your Budget Tracker account has been approved, you can now sign in."),
+            };
+
+            if let Err(error) = data.notifier.send(&message) {
+                println!("Error occurred while sending account approval: {}", error);
+            }
+
+            web::Json(StatusResult {
+                status: ResultStatus::Success,
+            })
+        }
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while approving user: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn reject_user(data: web::Data<AppState>, json: web::Json<UserForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.reject_user(&json.access_token, &json.email) {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while rejecting user: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn list_locked_users(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.get_locked_accounts(&json.access_token) {
+        Ok(accounts) => web::Json(LockedAccountListResult {
+            status: ResultStatus::Success,
+            accounts: Some(accounts),
+        }),
+        Err(error) => web::Json(LockedAccountListResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while getting locked users: {:?}",
+                error
+            ))),
+            accounts: None,
+        }),
+    }
+}
+
+fn unlock_user(data: web::Data<AppState>, json: web::Json<UserForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    match database.unlock_account(&json.access_token, &json.email) {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while unlocking user: {:?}",
                 error
-            ))),
-            access_token: None,
+            )))
         }),
     }
 }
 
-fn list_budgets(data: web::Data<AppState>, json: web::Json<AccessTokenForm>) -> impl Responder {
+fn list_budgets(data: web::Data<AppState>, json: web::Json<ListBudgetsForm>) -> impl Responder {
     let database = data.database.lock().unwrap();
 
-    let budgets = database.get_available_budgets(&json.access_token);
+    let budgets = database.get_available_budgets(
+        &json.access_token,
+        json.include_archived.unwrap_or(false)
+    );
 
     match budgets {
         Ok(budgets) => web::Json(BudgetListResult {
@@ -158,15 +907,33 @@ fn add_budget(data: web::Data<AppState>, json: web::Json<AddBudgetForm>) -> impl
 
     let start_date = match &json.budget_start_date {
         Some(x) => x.clone(),
-        None => get_current_date()
+        None => match database.get_user_timezone(&json.access_token) {
+            Ok(tz) => get_current_date(&tz),
+            Err(error) => return web::Json(BudgetResult {
+                status: ResultStatus::Error(String::from(format!(
+                    "Error occurred creating budget: {:?}",
+                    error
+                ))),
+                budget: None,
+            })
+        }
     };
 
-    let budget = Budget::new(
+    let mut budget = Budget::new(
         json.budget_name.clone(),
         json.budget_spend_limit,
         json.budget_period_length,
         start_date
     );
+    budget.timezone = json.budget_timezone.clone();
+
+    if let Some(period_rule) = &json.budget_period_rule {
+        budget.period_rule = period_rule.clone();
+    }
+
+    if let Some(rollover_policy) = &json.budget_rollover_policy {
+        budget.rollover_policy = rollover_policy.clone();
+    }
 
     let res = database.add_budget(&json.access_token, &budget);
 
@@ -203,6 +970,82 @@ fn delete_budget(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl
     }
 }
 
+fn archive_budget(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = database.set_budget_archived(&json.access_token, json.id, true);
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred archiving budget: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn unarchive_budget(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = database.set_budget_archived(&json.access_token, json.id, false);
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred unarchiving budget: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn set_budget_timezone(data: web::Data<AppState>, json: web::Json<BudgetTimezoneForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = database.set_budget_timezone(
+        &json.access_token,
+        json.budget_id,
+        json.timezone.as_ref().map(|x| x.as_str())
+    );
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred setting budget timezone: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn set_budget_rollover(data: web::Data<AppState>, json: web::Json<BudgetRolloverForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = database.set_budget_rollover_policy(&json.access_token, json.budget_id, &json.rollover_policy);
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success,
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred setting budget rollover policy: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
 fn get_budget(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
     let database = data.database.lock().unwrap();
 
@@ -243,6 +1086,45 @@ fn get_budget_spent(data: web::Data<AppState>, json: web::Json<BudgetPeriodForm>
     }
 }
 
+fn get_budget_summary(data: web::Data<AppState>, json: web::Json<BudgetSummaryForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let range = match json.period_id {
+        Some(period_id) => Ok((period_id, period_id)),
+        None => match database.get_current_budget_period_id(&json.access_token, json.budget_id) {
+            Ok(current_period_id) => Ok((
+                json.first_period_id.unwrap_or(current_period_id),
+                json.last_period_id.unwrap_or(current_period_id)
+            )),
+            Err(error) => Err(error)
+        }
+    };
+
+    let res = match range {
+        Ok((first_period_id, last_period_id)) => database.get_budget_period_summaries(
+            &json.access_token,
+            json.budget_id,
+            first_period_id,
+            last_period_id
+        ),
+        Err(error) => Err(error)
+    };
+
+    match res {
+        Ok(summaries) => web::Json(BudgetSummaryResult {
+            status: ResultStatus::Success,
+            summaries: Some(summaries)
+        }),
+        Err(error) => web::Json(BudgetSummaryResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred getting budget summary: {:?}",
+                error
+            ))),
+            summaries: None
+        }),
+    }
+}
+
 fn list_can_access_budget(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
     let database = data.database.lock().unwrap();
 
@@ -300,10 +1182,63 @@ fn delete_can_access_budget(data: web::Data<AppState>, json: web::Json<CanAccess
     }
 }
 
-fn list_transactions(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
+fn list_transactions(data: web::Data<AppState>, json: web::Json<ListTransactionsForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let page = database.get_budget_transactions(&json.access_token, json.id, &json.query);
+
+    match page {
+        Ok(page) => web::Json(TransactionPageResult {
+            status: ResultStatus::Success,
+            transactions: Some(page.transactions),
+            total_count: Some(page.total_count),
+            next_cursor: page.next_cursor,
+        }),
+        Err(error) => web::Json(TransactionPageResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while getting transactions: {:?}",
+                error
+            ))),
+            transactions: None,
+            total_count: None,
+            next_cursor: None,
+        }),
+    }
+}
+
+fn list_transactions_period(data: web::Data<AppState>, json: web::Json<ListTransactionsPeriodForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let page = database.get_budget_transactions_in_period(
+        &json.access_token,
+        json.budget_id,
+        json.period_id,
+        &json.query
+    );
+
+    match page {
+        Ok(page) => web::Json(TransactionPageResult {
+            status: ResultStatus::Success,
+            transactions: Some(page.transactions),
+            total_count: Some(page.total_count),
+            next_cursor: page.next_cursor,
+        }),
+        Err(error) => web::Json(TransactionPageResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while getting transactions in period: {:?}",
+                error
+            ))),
+            transactions: None,
+            total_count: None,
+            next_cursor: None,
+        }),
+    }
+}
+
+fn list_transactions_trash(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
     let database = data.database.lock().unwrap();
 
-    let transactions = database.get_budget_transactions(&json.access_token, json.id);
+    let transactions = database.get_budget_deleted_transactions(&json.access_token, json.id);
 
     match transactions {
         Ok(transactions) => web::Json(TransactionListResult {
@@ -312,7 +1247,7 @@ fn list_transactions(data: web::Data<AppState>, json: web::Json<SelectForm>) ->
         }),
         Err(error) => web::Json(TransactionListResult {
             status: ResultStatus::Error(String::from(format!(
-                "Error occurred while getting transactions: {:?}",
+                "Error occurred while getting deleted transactions: {:?}",
                 error
             ))),
             transactions: None,
@@ -320,10 +1255,10 @@ fn list_transactions(data: web::Data<AppState>, json: web::Json<SelectForm>) ->
     }
 }
 
-fn list_transactions_period(data: web::Data<AppState>, json: web::Json<BudgetPeriodForm>) -> impl Responder {
+fn list_transactions_scheduled(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
     let database = data.database.lock().unwrap();
 
-    let transactions = database.get_budget_transactions_in_period(&json.access_token, json.budget_id, json.period_id);
+    let transactions = database.get_budget_scheduled_transactions(&json.access_token, json.id);
 
     match transactions {
         Ok(transactions) => web::Json(TransactionListResult {
@@ -332,7 +1267,7 @@ fn list_transactions_period(data: web::Data<AppState>, json: web::Json<BudgetPer
         }),
         Err(error) => web::Json(TransactionListResult {
             status: ResultStatus::Error(String::from(format!(
-                "Error occurred while getting transactions in period: {:?}",
+                "Error occurred while getting scheduled transactions: {:?}",
                 error
             ))),
             transactions: None,
@@ -340,17 +1275,41 @@ fn list_transactions_period(data: web::Data<AppState>, json: web::Json<BudgetPer
     }
 }
 
+fn search_transactions(data: web::Data<AppState>, json: web::Json<SearchTransactionsForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let results = database.search_transactions(&json.access_token, &json.query, json.limit);
+
+    match results {
+        Ok(results) => web::Json(TransactionSearchResult {
+            status: ResultStatus::Success,
+            results: Some(results),
+        }),
+        Err(error) => web::Json(TransactionSearchResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while searching transactions: {:?}",
+                error
+            ))),
+            results: None,
+        }),
+    }
+}
+
 fn add_transaction(data: web::Data<AppState>, json: web::Json<AddTransactionForm>) -> impl Responder {
     let database = data.database.lock().unwrap();
 
-    let res = database.add_transaction(&json.access_token, &Transaction::new(
+    let mut transaction = Transaction::new(
         json.budget_id,
         json.transaction_name.clone(),
         json.transaction_description.clone(),
         json.transaction_amount,
         json.transaction_recur_days.clone(),
         json.transaction_recur_until.clone()
-    ));
+    );
+    transaction.kind = json.transaction_kind.unwrap_or_default();
+    transaction.date = json.transaction_date.clone();
+
+    let res = database.add_transaction(&json.access_token, &transaction);
 
     match res {
         Ok(transaction) => web::Json(TransactionResult {
@@ -367,6 +1326,158 @@ fn add_transaction(data: web::Data<AppState>, json: web::Json<AddTransactionForm
     }
 }
 
+fn delete_transaction(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = database.delete_transaction(&json.access_token, json.id);
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while deleting transaction: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn restore_transaction(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = database.restore_transaction(&json.access_token, json.id);
+
+    match res {
+        Ok(_) => web::Json(StatusResult {
+            status: ResultStatus::Success
+        }),
+        Err(error) => web::Json(StatusResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while restoring transaction: {:?}",
+                error
+            )))
+        }),
+    }
+}
+
+fn import_csv(data: web::Data<AppState>, json: web::Json<ImportCsvForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let res = parse_csv(&json.csv, &json.mapping).and_then(|rows| {
+        database.import_transactions(&json.access_token, json.budget_id, rows, json.dry_run.unwrap_or(false))
+    });
+
+    match res {
+        Ok(summary) => web::Json(ImportResult {
+            status: ResultStatus::Success,
+            summary: Some(summary)
+        }),
+        Err(error) => web::Json(ImportResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while importing transactions: {:?}",
+                error
+            ))),
+            summary: None
+        }),
+    }
+}
+
+fn import_ofx(data: web::Data<AppState>, json: web::Json<ImportStatementForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let credit_kind = json.credit_kind.unwrap_or(TransactionKind::Refund);
+
+    let res = parse_ofx(&json.statement, credit_kind).and_then(|rows| {
+        database.import_transactions(&json.access_token, json.budget_id, rows, json.dry_run.unwrap_or(false))
+    });
+
+    match res {
+        Ok(summary) => web::Json(ImportResult {
+            status: ResultStatus::Success,
+            summary: Some(summary)
+        }),
+        Err(error) => web::Json(ImportResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while importing transactions: {:?}",
+                error
+            ))),
+            summary: None
+        }),
+    }
+}
+
+fn import_qif(data: web::Data<AppState>, json: web::Json<ImportStatementForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let date_format = json.date_format.clone().unwrap_or(String::from("%m/%d/%Y"));
+    let credit_kind = json.credit_kind.unwrap_or(TransactionKind::Refund);
+
+    let res = parse_qif(&json.statement, &date_format, credit_kind).and_then(|rows| {
+        database.import_transactions(&json.access_token, json.budget_id, rows, json.dry_run.unwrap_or(false))
+    });
+
+    match res {
+        Ok(summary) => web::Json(ImportResult {
+            status: ResultStatus::Success,
+            summary: Some(summary)
+        }),
+        Err(error) => web::Json(ImportResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while importing transactions: {:?}",
+                error
+            ))),
+            summary: None
+        }),
+    }
+}
+
+fn export_budget(data: web::Data<AppState>, json: web::Json<ExportBudgetForm>) -> impl Responder {
+    let export = {
+        let database = data.database.lock().unwrap();
+
+        database.get_budget_export(&json.access_token, json.budget_id, json.period_id)
+    };
+
+    let export = match export {
+        Ok(x) => x,
+        Err(error) => {
+            return HttpResponse::Ok().json(StatusResult {
+                status: ResultStatus::Error(String::from(format!(
+                    "Error occurred while exporting budget: {:?}",
+                    error
+                )))
+            })
+        }
+    };
+
+    let file_name = export.file_name(json.format);
+
+    // The database is only locked while each page is read, not for the whole download
+    let state = data.clone();
+    let access_token = json.access_token.clone();
+    let budget_id = json.budget_id;
+    let period = export.period.clone();
+
+    let chunks = ExportChunks::new(json.format, export, move |after_id| {
+        state.database.lock().unwrap().get_export_transactions(
+            &access_token,
+            budget_id,
+            period.as_ref(),
+            after_id,
+            EXPORT_PAGE_SIZE,
+        )
+    });
+
+    HttpResponse::Ok()
+        .content_type(json.format.content_type())
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name))
+        .streaming(stream::iter_result(chunks).map_err(|err| {
+            error::ErrorInternalServerError(format!("Error occurred while exporting budget: {:?}", err))
+        }))
+}
+
 fn list_budget_periods(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
     let database = data.database.lock().unwrap();
 
@@ -387,6 +1498,26 @@ fn list_budget_periods(data: web::Data<AppState>, json: web::Json<SelectForm>) -
     }
 }
 
+fn list_budget_period_balances(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let balances = database.get_budget_period_balances(&json.access_token, json.id);
+
+    match balances {
+        Ok(balances) => web::Json(BudgetPeriodBalanceListResult {
+            status: ResultStatus::Success,
+            balances: Some(balances),
+        }),
+        Err(error) => web::Json(BudgetPeriodBalanceListResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while getting budget period balances: {:?}",
+                error
+            ))),
+            balances: None,
+        }),
+    }
+}
+
 fn get_budget_current_period(data: web::Data<AppState>, json: web::Json<SelectForm>) -> impl Responder {
     let database = data.database.lock().unwrap();
 
@@ -425,4 +1556,64 @@ fn get_budget_period(data: web::Data<AppState>, json: web::Json<BudgetPeriodForm
             budget_period: None,
         }),
     }
-}
\ No newline at end of file
+}
+
+fn list_audit(data: web::Data<AppState>, json: web::Json<AuditLogForm>) -> impl Responder {
+    let database = data.database.lock().unwrap();
+
+    let entries = database.get_audit_log(&json.access_token, json.budget_id);
+
+    match entries {
+        Ok(entries) => web::Json(AuditLogResult {
+            status: ResultStatus::Success,
+            entries: Some(entries),
+        }),
+        Err(error) => web::Json(AuditLogResult {
+            status: ResultStatus::Error(String::from(format!(
+                "Error occurred while getting audit log: {:?}",
+                error
+            ))),
+            entries: None,
+        }),
+    }
+}
+
+// Helpers
+
+fn send_verification_email(data: &web::Data<AppState>, email: &str, token: &str) {
+    let message = Message {
+        to: email.to_string(),
+        subject: String::from("Verify your Budget Tracker email"),
+        body: format!(
+            "Welcome to Budget Tracker.\n\n\
+            Your verification code is: {}\n\n\
+            The code expires in {} hours. Budgets can't be created or shared until your email is verified.",
+            token, data.config.email_verification_hours
+        ),
+    };
+
+    if let Err(error) = data.notifier.send(&message) {
+        println!("Error occurred while sending email verification: {}", error);
+    }
+}
+
+/// Gives a user who has proven who they are their access token, or a token for
+/// `verify_two_factor` when they have two-factor authentication
+fn finish_login(database: &Database, user: User) -> Result<LoginResult, Error> {
+    if !user.approved {
+        return Err(Error::AccountPendingApproval);
+    }
+
+    match database.start_two_factor_login(&user.email)? {
+        Some(token) => Ok(LoginResult {
+            status: ResultStatus::TwoFactorRequired,
+            access_token: None,
+            two_factor_token: Some(token),
+        }),
+        None => Ok(LoginResult {
+            status: ResultStatus::Success,
+            access_token: Some(user.access_token),
+            two_factor_token: None,
+        }),
+    }
+}
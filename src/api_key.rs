@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+use crate::util::generate_token;
+
+/// Start of every API key, tells them apart from session access tokens
+pub const API_KEY_PREFIX: &str = "btk_";
+
+/// Characters of a key shown when listing keys so that users can tell them apart
+const DISPLAYED_LENGTH: usize = 12;
+
+/// What an API key may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// Listing and reading budgets and transactions
+    ReadOnly,
+    /// Everything read only keys may do, plus adding, importing, deleting
+    /// and restoring transactions
+    WriteTransactions
+}
+
+impl ApiKeyScope {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ApiKeyScope::ReadOnly => "read_only",
+            ApiKeyScope::WriteTransactions => "write_transactions"
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<ApiKeyScope> {
+        match s {
+            "read_only" => Some(ApiKeyScope::ReadOnly),
+            "write_transactions" => Some(ApiKeyScope::WriteTransactions),
+            _ => None
+        }
+    }
+
+    /// Whether a key with this scope may do what `needed` covers
+    pub fn allows(&self, needed: ApiKeyScope) -> bool {
+        match (*self, needed) {
+            (ApiKeyScope::WriteTransactions, _) => true,
+            (ApiKeyScope::ReadOnly, ApiKeyScope::ReadOnly) => true,
+            (ApiKeyScope::ReadOnly, ApiKeyScope::WriteTransactions) => false
+        }
+    }
+}
+
+impl ToSql for ApiKeyScope {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for ApiKeyScope {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str().and_then(|s| match ApiKeyScope::from_str(s) {
+            Some(scope) => Ok(scope),
+            None => Err(FromSqlError::InvalidType)
+        })
+    }
+}
+
+/// A key that scripts can use in place of an access token
+///
+/// The key itself is only given out when it's created, only a hash of it is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub api_key_id: Option<i64>,
+    pub name: String,
+    /// Start of the key, for telling keys apart
+    pub prefix: String,
+    pub scope: ApiKeyScope,
+    /// Budgets the key may access, `None` for every budget the user can access
+    pub budget_ids: Option<Vec<i64>>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>
+}
+
+impl ApiKey {
+    pub fn allows_budget(&self, budget_id: i64) -> bool {
+        match &self.budget_ids {
+            Some(x) => x.contains(&budget_id),
+            None => true,
+        }
+    }
+}
+
+/// A newly created key, the only time the full key is available
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewApiKey {
+    pub key: String,
+    pub api_key: ApiKey
+}
+
+pub fn is_api_key(access_token: &str) -> bool {
+    access_token.starts_with(API_KEY_PREFIX)
+}
+
+pub fn generate_api_key() -> String {
+    format!("{}{}", API_KEY_PREFIX, generate_token(40))
+}
+
+pub fn displayed_prefix(key: &str) -> String {
+    key.chars().take(DISPLAYED_LENGTH).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_key(budget_ids: Option<Vec<i64>>) -> ApiKey {
+        ApiKey {
+            api_key_id: Some(1),
+            name: String::from("test"),
+            prefix: String::from("btk_abcdefgh"),
+            scope: ApiKeyScope::ReadOnly,
+            budget_ids,
+            created_at: String::from("2020-01-01 00:00:00.000"),
+            last_used_at: None,
+            revoked_at: None,
+        }
+    }
+
+    #[test]
+    fn scopes_allow_what_they_cover() {
+        let cases = [
+            (ApiKeyScope::ReadOnly, ApiKeyScope::ReadOnly, true),
+            (ApiKeyScope::ReadOnly, ApiKeyScope::WriteTransactions, false),
+            (ApiKeyScope::WriteTransactions, ApiKeyScope::ReadOnly, true),
+            (ApiKeyScope::WriteTransactions, ApiKeyScope::WriteTransactions, true),
+        ];
+
+        for (scope, needed, allowed) in cases.iter() {
+            assert_eq!(scope.allows(*needed), *allowed, "{:?} allows {:?}", scope, needed);
+        }
+    }
+
+    #[test]
+    fn scopes_round_trip_through_strings() {
+        for scope in [ApiKeyScope::ReadOnly, ApiKeyScope::WriteTransactions].iter() {
+            assert_eq!(ApiKeyScope::from_str(scope.as_str()), Some(*scope));
+        }
+
+        assert_eq!(ApiKeyScope::from_str("admin"), None);
+    }
+
+    #[test]
+    fn keys_allow_only_their_budgets() {
+        assert!(api_key(None).allows_budget(3));
+
+        let limited = api_key(Some(vec![1, 2]));
+        assert!(limited.allows_budget(1));
+        assert!(limited.allows_budget(2));
+        assert!(!limited.allows_budget(3));
+
+        // An empty list, as left by an unreadable one, allows nothing
+        assert!(!api_key(Some(Vec::new())).allows_budget(1));
+    }
+
+    #[test]
+    fn generated_keys_are_recognised() {
+        let key = generate_api_key();
+
+        assert!(is_api_key(&key));
+        assert!(!is_api_key("an-access-token"));
+        assert_eq!(displayed_prefix(&key).len(), DISPLAYED_LENGTH);
+    }
+}
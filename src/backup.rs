@@ -0,0 +1,259 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
+
+use rusqlite::{Connection, DatabaseName, OpenFlags, NO_PARAMS};
+
+use crate::database::{schema_version, Error};
+
+const BACKUP_PREFIX: &str = "budget-";
+const BACKUP_EXTENSION: &str = ".db";
+
+/// Path of a new backup in `dir` taken at `now`, names sort oldest first
+pub fn backup_path(dir: &Path, now: &NaiveDateTime) -> PathBuf {
+    dir.join(format!("{}{}{}", BACKUP_PREFIX, now.format("%Y%m%d-%H%M%S"), BACKUP_EXTENSION))
+}
+
+/// Deletes all but the newest `keep` backups in `dir`, returning how many were deleted
+pub fn rotate_backups(dir: &Path, keep: usize) -> Result<usize, Error> {
+    let mut backups: Vec<PathBuf> = Vec::new();
+
+    for entry in fs::read_dir(dir).map_err(backup_error)? {
+        let path = entry.map_err(backup_error)?.path();
+
+        let is_backup = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map(|x| x.starts_with(BACKUP_PREFIX) && x.ends_with(BACKUP_EXTENSION))
+            .unwrap_or(false);
+
+        if is_backup {
+            backups.push(path);
+        }
+    }
+
+    backups.sort();
+
+    let expired = backups.len().saturating_sub(keep);
+
+    for path in &backups[..expired] {
+        fs::remove_file(path).map_err(backup_error)?;
+    }
+
+    Ok(expired)
+}
+
+/// Replaces the database at `db_path` with the backup at `backup_path`
+///
+/// The backup must pass SQLite's integrity check and have a schema version no
+/// newer than this server understands, older versions are migrated on the
+/// next start. The replaced database is kept alongside it with the extension
+/// "before-restore". The server must not be running while restoring.
+pub fn restore(backup_path: &Path, db_path: &Path) -> Result<(), Error> {
+    let backup = Connection::open_with_flags(backup_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|_| Error::LoadFileError)?;
+
+    let integrity: String = backup.query_row("PRAGMA integrity_check", NO_PARAMS, |row| row.get(0))?;
+
+    if integrity != "ok" {
+        return Err(Error::BackupError(format!("integrity check failed: {}", integrity)));
+    }
+
+    let version: i64 = backup.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+
+    if version > schema_version() {
+        return Err(Error::BackupError(format!(
+            "backup has schema version {} but this server only supports up to {}",
+            version,
+            schema_version()
+        )));
+    }
+
+    let has_users: bool = backup.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'users'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+
+    if !has_users {
+        return Err(Error::BackupError(String::from("not a budget database")));
+    }
+
+    // Copy into a temporary file first so that a failed copy leaves the
+    // current database untouched
+    let restoring = db_path.with_extension("restoring");
+
+    backup.backup(DatabaseName::Main, &restoring, None)?;
+
+    if db_path.exists() {
+        fs::rename(db_path, db_path.with_extension("before-restore")).map_err(backup_error)?;
+    }
+
+    fs::rename(&restoring, db_path).map_err(backup_error)
+}
+
+fn backup_error(error: std::io::Error) -> Error {
+    Error::BackupError(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    use chrono::NaiveDate;
+    use chrono_tz::Tz;
+
+    use crate::database::Database;
+
+    /// Directory removed again when the test finishes
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("budget-tracker-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn create_database(path: &Path, sql: &str) {
+        Connection::open(path).unwrap().execute_batch(sql).unwrap();
+    }
+
+    fn assert_rejected(backup: &Path, db_path: &Path, reason: &str) {
+        match restore(backup, db_path) {
+            Err(Error::BackupError(x)) => assert!(x.contains(reason), "{}", x),
+            x => panic!("restore didn't fail with \"{}\": {:?}", reason, x),
+        }
+
+        // The current database is left alone
+        assert_eq!(fs::read(db_path).unwrap(), b"current");
+        assert!(!db_path.with_extension("restoring").exists());
+    }
+
+    #[test]
+    fn restore_replaces_the_database() {
+        let dir = TempDir::new("restore");
+        let original = dir.0.join("original.db");
+        let db_path = dir.0.join("budget.db");
+
+        let backup = {
+            let database = Database::new(String::from("secret"), Tz::UTC, original.to_str().unwrap()).unwrap();
+            database.backup("test", &dir.0.join("backups"), 1).unwrap()
+        };
+
+        fs::write(&db_path, b"current").unwrap();
+
+        restore(&backup, &db_path).unwrap();
+
+        assert_eq!(fs::read(db_path.with_extension("before-restore")).unwrap(), b"current");
+
+        let restored = Connection::open(&db_path).unwrap();
+        let version: i64 = restored.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0)).unwrap();
+
+        assert_eq!(version, schema_version());
+    }
+
+    #[test]
+    fn restore_rejects_newer_schema_versions() {
+        let dir = TempDir::new("restore-newer");
+        let backup = dir.0.join("backup.db");
+        let db_path = dir.0.join("budget.db");
+
+        create_database(
+            &backup,
+            &format!("CREATE TABLE users(email TEXT); PRAGMA user_version = {};", schema_version() + 1),
+        );
+        fs::write(&db_path, b"current").unwrap();
+
+        assert_rejected(&backup, &db_path, "schema version");
+    }
+
+    #[test]
+    fn restore_rejects_other_databases() {
+        let dir = TempDir::new("restore-other");
+        let backup = dir.0.join("backup.db");
+        let db_path = dir.0.join("budget.db");
+
+        create_database(&backup, "CREATE TABLE notes(text TEXT);");
+        fs::write(&db_path, b"current").unwrap();
+
+        assert_rejected(&backup, &db_path, "not a budget database");
+
+        // Files that aren't SQLite databases at all
+        let text = dir.0.join("notes.txt");
+        fs::write(&text, "not a database, just some text that is long enough to have a header").unwrap();
+
+        assert!(restore(&text, &db_path).is_err());
+        assert_eq!(fs::read(&db_path).unwrap(), b"current");
+    }
+
+    #[test]
+    fn restore_rejects_corrupt_databases() {
+        let dir = TempDir::new("restore-corrupt");
+        let backup = dir.0.join("backup.db");
+        let db_path = dir.0.join("budget.db");
+
+        // Declaring the index on a different column leaves its entries not
+        // matching the table, which the integrity check finds
+        create_database(
+            &backup,
+            "CREATE TABLE users(email TEXT, name TEXT);
+            CREATE INDEX users_email ON users(email);
+            INSERT INTO users VALUES('a@example.com', 'A'), ('b@example.com', 'B');
+            PRAGMA writable_schema = ON;
+            UPDATE sqlite_master SET sql = 'CREATE INDEX users_email ON users(name)' WHERE name = 'users_email';
+            PRAGMA writable_schema = OFF;",
+        );
+        fs::write(&db_path, b"current").unwrap();
+
+        assert_rejected(&backup, &db_path, "integrity check failed");
+    }
+
+    #[test]
+    fn rotate_keeps_the_newest_backups() {
+        let dir = TempDir::new("rotate");
+
+        let mut backups: Vec<PathBuf> = (1..=5)
+            .map(|day| backup_path(&dir.0, &NaiveDate::from_ymd(2020, 1, day).and_hms(3, 0, 0)))
+            .collect();
+
+        // Created newest first so that the order on disk doesn't help
+        backups.reverse();
+
+        for path in &backups {
+            fs::write(path, b"backup").unwrap();
+        }
+
+        // Other files in the directory are never touched
+        fs::write(dir.0.join("notes.txt"), b"notes").unwrap();
+        fs::write(dir.0.join("budget-copy.txt"), b"copy").unwrap();
+
+        assert_eq!(rotate_backups(&dir.0, 2).unwrap(), 3);
+
+        let mut remaining: Vec<String> = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        remaining.sort();
+
+        assert_eq!(
+            remaining,
+            vec!["budget-20200104-030000.db", "budget-20200105-030000.db", "budget-copy.txt", "notes.txt"]
+        );
+
+        // Keeping more than there are deletes nothing
+        assert_eq!(rotate_backups(&dir.0, 10).unwrap(), 0);
+    }
+}
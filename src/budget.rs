@@ -1,13 +1,20 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::period_rule::PeriodRule;
+use crate::rollover_policy::RolloverPolicy;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Budget {
     pub budget_id: Option<i64>,
     pub owner: Option<String>,
     pub name: String,
     pub spend_limit: f64,
     pub period_length: i64,
-    pub start_date: String
+    pub start_date: String,
+    pub archived: bool,
+    pub timezone: Option<String>,
+    pub period_rule: PeriodRule,
+    pub rollover_policy: RolloverPolicy
 }
 
 impl Budget {
@@ -18,7 +25,11 @@ impl Budget {
             name,
             spend_limit,
             period_length,
-            start_date
+            start_date,
+            archived: false,
+            timezone: None,
+            period_rule: PeriodRule::Days { count: period_length },
+            rollover_policy: RolloverPolicy::None
         }
     }
 }
\ No newline at end of file
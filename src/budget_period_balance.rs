@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BudgetPeriodBalance {
+    pub period_id: i64,
+    pub start_date: String, // Note - these dates are inclusive
+    pub end_date: String,
+    pub spend_limit: f64,
+    pub carried: f64, // Amount carried over from the previous period
+    pub income: f64, // Income and net transfers within the period
+    pub effective_limit: f64,
+    pub spent: f64
+}
@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BudgetPeriodSummary {
+    pub period_id: i64,
+    pub start_date: String, // Note - these dates are inclusive
+    pub end_date: String,
+    pub limit: f64, // Effective limit, including income and any rollover
+    pub income: f64,
+    pub spent: f64,
+    pub remaining: f64,
+    pub percent_used: Option<f64>, // None when the limit isn't positive
+    pub elapsed_days: i64,
+    pub total_days: i64,
+    pub daily_burn_rate: f64,
+    pub projected_spent: f64 // Spent by the end of the period at the current burn rate
+}
@@ -3,14 +3,14 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CanAccessBudget {
     pub budget_id: i64,
-    pub user_id: i64
+    pub email: String
 }
 
 impl CanAccessBudget {
-    pub fn new(budget_id: i64, user_id: i64) -> CanAccessBudget {
+    pub fn new(budget_id: i64, email: String) -> CanAccessBudget {
         CanAccessBudget {
             budget_id,
-            user_id
+            email
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use termion::input::TermRead;
+
+use crate::backup;
+use crate::config::Config;
+use crate::database::{schema_version, Database, User, SYSTEM_ACTOR};
+use crate::util::parse_timezone;
+use crate::DB_PATH;
+
+pub const USAGE: &str = "Usage: budget-tracker-server [command]
+
+Commands:
+    serve                       Start the server (default)
+    init                        Create the config file, database and admin account
+        [--admin-email <email>] [--password-stdin]
+    create-user <email>         Add a user
+        [--first-name <name>] [--last-name <name>] [--admin] [--password-stdin]
+    reset-password <email>      Set a new password, signing the user out everywhere
+        [--password-stdin]
+    disable-two-factor <email>  Turn off two-factor authentication for a user
+    list-users                  List every user
+    migrate                     Bring the database schema up to date
+    backup [--dir <dir>]        Back up the database, defaults to the configured directory
+    restore <backup file>       Replace the database with a backup, the server must be stopped
+    check-config                Check the config file for problems
+    help                        Show this message
+
+--password-stdin reads the password from the first line of standard input
+instead of prompting for it.";
+
+// Options that are followed by a value, all others are switches
+const VALUE_OPTIONS: &[&str] = &["--admin-email", "--first-name", "--last-name", "--dir"];
+
+pub enum Command {
+    Serve,
+    Init,
+    CreateUser,
+    ResetPassword,
+    DisableTwoFactor,
+    ListUsers,
+    Migrate,
+    Backup,
+    Restore,
+    CheckConfig,
+    Help
+}
+
+/// Parsed command line, the command followed by its arguments and options
+pub struct Invocation {
+    pub command: Command,
+    arguments: Vec<String>,
+    options: HashMap<String, Option<String>>
+}
+
+impl Invocation {
+    /// Parses the command line arguments, excluding the program name
+    pub fn parse(args: &[String]) -> Result<Invocation, String> {
+        let command = match args.first().map(|x| x.as_str()) {
+            None | Some("serve") => Command::Serve,
+            Some("init") => Command::Init,
+            Some("create-user") => Command::CreateUser,
+            Some("reset-password") => Command::ResetPassword,
+            Some("disable-two-factor") => Command::DisableTwoFactor,
+            Some("list-users") => Command::ListUsers,
+            Some("migrate") => Command::Migrate,
+            Some("backup") => Command::Backup,
+            Some("restore") => Command::Restore,
+            Some("check-config") => Command::CheckConfig,
+            Some("help") | Some("--help") | Some("-h") => Command::Help,
+            Some(x) => return Err(format!("unknown command \"{}\"", x)),
+        };
+
+        let mut arguments: Vec<String> = Vec::new();
+        let mut options: HashMap<String, Option<String>> = HashMap::new();
+
+        let mut rest = args.iter().skip(1);
+
+        while let Some(arg) = rest.next() {
+            if !arg.starts_with("--") {
+                arguments.push(arg.clone());
+            } else if VALUE_OPTIONS.contains(&arg.as_str()) {
+                match rest.next() {
+                    Some(value) => options.insert(arg.clone(), Some(value.clone())),
+                    None => return Err(format!("{} needs a value", arg)),
+                };
+            } else {
+                options.insert(arg.clone(), None);
+            }
+        }
+
+        Ok(Invocation {
+            command,
+            arguments,
+            options,
+        })
+    }
+
+    fn argument(&self, index: usize, name: &str) -> Result<&str, String> {
+        match self.arguments.get(index) {
+            Some(x) => Ok(x.as_str()),
+            None => Err(format!("missing {}", name)),
+        }
+    }
+
+    fn value(&self, option: &str) -> Option<&str> {
+        self.options
+            .get(option)
+            .and_then(|x| x.as_ref())
+            .map(|x| x.as_str())
+    }
+
+    fn switch(&self, option: &str) -> bool {
+        self.options.contains_key(option)
+    }
+}
+
+/// Runs every command other than `serve`
+pub fn run(invocation: &Invocation) -> Result<(), String> {
+    match invocation.command {
+        Command::Serve => Err(String::from("the server is started from main")),
+        Command::Init => init(invocation),
+        Command::CreateUser => create_user(invocation),
+        Command::ResetPassword => reset_password(invocation),
+        Command::DisableTwoFactor => disable_two_factor(invocation),
+        Command::ListUsers => list_users(),
+        Command::Migrate => migrate(),
+        Command::Backup => run_backup(invocation),
+        Command::Restore => restore(invocation),
+        Command::CheckConfig => check_config(),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    }
+}
+
+/// Opens the database, creating and migrating it as needed
+pub fn open_database(config: &Config) -> Result<Database, String> {
+    let default_timezone = match parse_timezone(&config.default_timezone) {
+        Some(tz) => tz,
+        None => return Err(format!("unknown default timezone \"{}\" in config", config.default_timezone)),
+    };
+
+    Database::new(config.secret.clone(), default_timezone, DB_PATH)
+        .map_err(|error| format!("failed loading database: {:?}", error))
+}
+
+/// Prompts for the admin account when the database has no users yet
+pub fn setup_admin(database: &Database, email: Option<&str>, password_stdin: bool) -> Result<(), String> {
+    let has_users = !database
+        .get_all_users()
+        .map_err(|error| format!("{:?}", error))?
+        .is_empty();
+
+    if has_users {
+        return Ok(());
+    }
+
+    println!(" === Admin User Setup ===");
+
+    let email = match email {
+        Some(x) => x.to_string(),
+        None => prompt("Email:")?,
+    };
+
+    let password = read_password(password_stdin)?;
+
+    add_user(
+        database,
+        &email,
+        &String::from("Administrator"),
+        &String::from("Account"),
+        &password,
+        true,
+    )?;
+
+    println!("Admin user created.");
+
+    Ok(())
+}
+
+fn init(invocation: &Invocation) -> Result<(), String> {
+    let config = Config::load();
+    let database = open_database(&config)?;
+
+    setup_admin(
+        &database,
+        invocation.value("--admin-email"),
+        invocation.switch("--password-stdin"),
+    )?;
+
+    println!("Server is initialised.");
+
+    Ok(())
+}
+
+fn create_user(invocation: &Invocation) -> Result<(), String> {
+    let email = invocation.argument(0, "email")?;
+
+    let database = open_database(&Config::load_existing()?)?;
+    let password = read_password(invocation.switch("--password-stdin"))?;
+
+    add_user(
+        &database,
+        email,
+        &invocation.value("--first-name").unwrap_or("").to_string(),
+        &invocation.value("--last-name").unwrap_or("").to_string(),
+        &password,
+        invocation.switch("--admin"),
+    )?;
+
+    println!("Created user \"{}\".", email);
+
+    Ok(())
+}
+
+fn add_user(
+    database: &Database,
+    email: &str,
+    first_name: &String,
+    last_name: &String,
+    password: &String,
+    is_admin: bool,
+) -> Result<(), String> {
+    let email = email.trim().to_string();
+
+    if email.is_empty() {
+        return Err(String::from("email can't be empty"));
+    }
+
+    // Accounts made by operators are trusted to have the right email
+    let mut user = User::new(database, &email, first_name, last_name, password, is_admin);
+    user.email_verified = true;
+
+    database
+        .insert_user(&user)
+        .map_err(|error| format!("failed creating user: {:?}", error))
+}
+
+fn reset_password(invocation: &Invocation) -> Result<(), String> {
+    let email = invocation.argument(0, "email")?;
+
+    let database = open_database(&Config::load_existing()?)?;
+
+    let mut user = match database.get_user_by_email(email) {
+        Ok(Some(x)) => x,
+        Ok(None) => return Err(format!("no user with email \"{}\"", email)),
+        Err(error) => return Err(format!("{:?}", error)),
+    };
+
+    let password = read_password(invocation.switch("--password-stdin"))?;
+
+    // Changing the password also replaces the access token
+    user.change_password(&database, &database.hash(&password));
+
+    database
+        .update_user(&user)
+        .map_err(|error| format!("failed updating user: {:?}", error))?;
+
+    println!("Password of \"{}\" has been reset.", email);
+
+    Ok(())
+}
+
+fn disable_two_factor(invocation: &Invocation) -> Result<(), String> {
+    let email = invocation.argument(0, "email")?;
+
+    let database = open_database(&Config::load_existing()?)?;
+
+    database
+        .remove_two_factor(SYSTEM_ACTOR, email)
+        .map_err(|error| format!("failed disabling two-factor authentication: {:?}", error))?;
+
+    println!("Two-factor authentication of \"{}\" has been disabled.", email);
+
+    Ok(())
+}
+
+fn list_users() -> Result<(), String> {
+    let database = open_database(&Config::load_existing()?)?;
+
+    let users = database.get_all_users().map_err(|error| format!("{:?}", error))?;
+
+    for user in users {
+        println!(
+            "{}\t{} {}\t{}{}{}",
+            user.email,
+            user.first_name,
+            user.last_name,
+            user.timezone.unwrap_or_else(|| String::from("-")),
+            if user.is_admin { "\tadmin" } else { "" },
+            if user.approved { "" } else { "\tpending approval" }
+        );
+    }
+
+    Ok(())
+}
+
+fn migrate() -> Result<(), String> {
+    // Pending migrations are applied whenever the database is opened
+    let database = open_database(&Config::load_existing()?)?;
+
+    let version = database.get_schema_version().map_err(|error| format!("{:?}", error))?;
+
+    println!("Database is at schema version {} of {}.", version, schema_version());
+
+    Ok(())
+}
+
+fn run_backup(invocation: &Invocation) -> Result<(), String> {
+    let config = Config::load_existing()?;
+    let database = open_database(&config)?;
+
+    let dir = invocation.value("--dir").unwrap_or(&config.backup_dir);
+
+    let path = database
+        .backup(SYSTEM_ACTOR, Path::new(dir), config.backup_keep)
+        .map_err(|error| format!("failed backing up database: {:?}", error))?;
+
+    println!("Backed up database to \"{}\".", path.display());
+
+    Ok(())
+}
+
+fn restore(invocation: &Invocation) -> Result<(), String> {
+    let backup_path = invocation.argument(0, "backup file")?;
+
+    backup::restore(Path::new(backup_path), Path::new(DB_PATH))
+        .map_err(|error| format!("failed restoring database: {:?}", error))?;
+
+    println!("Restored database from \"{}\".", backup_path);
+
+    Ok(())
+}
+
+fn check_config() -> Result<(), String> {
+    let config = Config::load_existing()?;
+
+    let mut problems: Vec<String> = Vec::new();
+
+    match config.binding.to_socket_addrs() {
+        Ok(_) => (),
+        Err(error) => problems.push(format!("binding \"{}\" is invalid: {}", config.binding, error)),
+    }
+
+    match SslAcceptor::mozilla_intermediate(SslMethod::tls()) {
+        Ok(mut builder) => {
+            if let Err(error) = builder.set_private_key_file(&config.ssl_key_path, SslFiletype::PEM) {
+                problems.push(format!("SSL key \"{}\" can't be loaded: {}", config.ssl_key_path, error));
+            }
+
+            if let Err(error) = builder.set_certificate_chain_file(&config.ssl_cert_path) {
+                problems.push(format!("SSL certificate \"{}\" can't be loaded: {}", config.ssl_cert_path, error));
+            }
+        }
+        Err(error) => problems.push(format!("failed setting up SSL: {}", error)),
+    }
+
+    if config.secret.is_empty() {
+        problems.push(String::from("secret is empty"));
+    }
+
+    if parse_timezone(&config.default_timezone).is_none() {
+        problems.push(format!("unknown default timezone \"{}\"", config.default_timezone));
+    }
+
+    if config.trash_retention_days < 0 {
+        problems.push(String::from("trash_retention_days can't be negative"));
+    }
+
+    if config.login_lockout_attempts == 0 {
+        problems.push(String::from("login_lockout_attempts must be at least 1"));
+    }
+
+    if config.backup_keep == 0 {
+        problems.push(String::from("backup_keep must be at least 1"));
+    }
+
+    let backup_dir = Path::new(&config.backup_dir);
+
+    if backup_dir.exists() && !backup_dir.is_dir() {
+        problems.push(format!("backup_dir \"{}\" is not a directory", config.backup_dir));
+    }
+
+    if problems.is_empty() {
+        println!("Config is valid.");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("- {}", problem);
+    }
+
+    Err(format!("found {} problem(s) in the config", problems.len()))
+}
+
+fn prompt(message: &str) -> Result<String, String> {
+    println!("{}", message);
+
+    let mut buffer = String::new();
+
+    io::stdin()
+        .read_line(&mut buffer)
+        .map_err(|error| format!("failed reading input: {}", error))?;
+
+    Ok(buffer.trim().to_string())
+}
+
+/// Reads a new password, either the first line of stdin or prompted for twice
+fn read_password(from_stdin: bool) -> Result<String, String> {
+    let password = if from_stdin {
+        let mut buffer = String::new();
+
+        io::stdin()
+            .read_line(&mut buffer)
+            .map_err(|error| format!("failed reading password: {}", error))?;
+
+        buffer.trim_end_matches(|x| x == '\n' || x == '\r').to_string()
+    } else {
+        let read = || -> Result<String, String> {
+            match io::stdin().read_passwd(&mut io::stdout()) {
+                Ok(Some(x)) => Ok(x),
+                Ok(None) => Err(String::from("no password given")),
+                Err(error) => Err(format!("failed reading password: {}", error)),
+            }
+        };
+
+        println!("Password:");
+        let password = read()?;
+
+        println!("Confirm password:");
+        if read()? != password {
+            return Err(String::from("passwords don't match"));
+        }
+
+        password
+    };
+
+    if password.is_empty() {
+        return Err(String::from("password can't be empty"));
+    }
+
+    Ok(password)
+}
@@ -3,14 +3,113 @@ use std::io::{Read, Write};
 use serde::{Deserialize, Serialize};
 use toml;
 
+use crate::notifier::NotifierConfig;
+use crate::oidc::OidcConfig;
+use crate::registration::RegistrationMode;
+
 const CONFIG_PATH: &str = "./config.toml";
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     pub binding: String,
     pub ssl_key_path: String,
     pub ssl_cert_path: String,
-    pub secret: String
+    pub secret: String,
+
+    // Number of days deleted transactions are kept in the trash before being purged
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: i64,
+
+    // IANA timezone used for users and budgets that haven't chosen their own
+    #[serde(default = "default_timezone")]
+    pub default_timezone: String,
+
+    // Directory that database backups are written to
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: String,
+
+    // Hours between scheduled backups, 0 disables them
+    #[serde(default = "default_backup_interval_hours")]
+    pub backup_interval_hours: u64,
+
+    // Number of backups kept, older ones are deleted after each backup
+    #[serde(default = "default_backup_keep")]
+    pub backup_keep: usize,
+
+    // Minutes that password reset codes stay valid for
+    #[serde(default = "default_password_reset_minutes")]
+    pub password_reset_minutes: i64,
+
+    // Hours that email verification codes stay valid for
+    #[serde(default = "default_email_verification_hours")]
+    pub email_verification_hours: i64,
+
+    // Who may register: "open", "disabled", "invite_only" or "admin_approval"
+    #[serde(default)]
+    pub registration_mode: RegistrationMode,
+
+    // Days that invite codes stay valid for
+    #[serde(default = "default_invite_code_days")]
+    pub invite_code_days: i64,
+
+    // Consecutive failed logins that lock an account
+    #[serde(default = "default_login_lockout_attempts")]
+    pub login_lockout_attempts: u32,
+
+    // Minutes that locked accounts stay locked for
+    #[serde(default = "default_login_lockout_minutes")]
+    pub login_lockout_minutes: i64,
+
+    // How messages such as password reset codes are delivered to users
+    //
+    // Note - tables must come after plain values for the config to serialise
+    #[serde(default)]
+    pub notifier: NotifierConfig,
+
+    // Single sign-on through an OpenID Connect identity provider, left out to disable it
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>
+}
+
+fn default_trash_retention_days() -> i64 {
+    30
+}
+
+fn default_timezone() -> String {
+    // Matches the fixed offset used before timezones were configurable
+    String::from("Australia/Hobart")
+}
+
+fn default_backup_dir() -> String {
+    String::from("backups")
+}
+
+fn default_backup_interval_hours() -> u64 {
+    24
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+fn default_password_reset_minutes() -> i64 {
+    60
+}
+
+fn default_email_verification_hours() -> i64 {
+    48
+}
+
+fn default_invite_code_days() -> i64 {
+    7
+}
+
+fn default_login_lockout_attempts() -> u32 {
+    10
+}
+
+fn default_login_lockout_minutes() -> i64 {
+    15
 }
 
 impl Config {
@@ -55,8 +154,38 @@ impl Config {
             binding,
             ssl_key_path,
             ssl_cert_path,
-            secret
+            secret,
+            trash_retention_days: default_trash_retention_days(),
+            default_timezone: default_timezone(),
+            backup_dir: default_backup_dir(),
+            backup_interval_hours: default_backup_interval_hours(),
+            backup_keep: default_backup_keep(),
+            password_reset_minutes: default_password_reset_minutes(),
+            email_verification_hours: default_email_verification_hours(),
+            registration_mode: RegistrationMode::default(),
+            invite_code_days: default_invite_code_days(),
+            login_lockout_attempts: default_login_lockout_attempts(),
+            login_lockout_minutes: default_login_lockout_minutes(),
+            notifier: NotifierConfig::default(),
+            oidc: None
+        }
+    }
+
+    /// Reads the config file without prompting to create one when it's missing
+    pub fn load_existing() -> Result<Config, String> {
+        let mut f = match File::open(CONFIG_PATH) {
+            Ok(f) => f,
+            Err(_) => return Err(format!("config file '{}' not found, run `init` first", CONFIG_PATH)),
+        };
+
+        let mut file_contents = String::new();
+
+        if let Err(error) = f.read_to_string(&mut file_contents) {
+            return Err(format!("failed reading '{}': {}", CONFIG_PATH, error));
         }
+
+        toml::from_str(file_contents.as_str())
+            .map_err(|error| format!("config file '{}' is corrupt: {}", CONFIG_PATH, error))
     }
 
     pub fn load() -> Config {
@@ -0,0 +1,442 @@
+use serde::{Deserialize, Serialize};
+
+use chrono::NaiveDate;
+
+use crate::database::Error;
+use crate::statement_import::{parse_amount, ImportRow, StatementEntry};
+use crate::transaction::TransactionKind;
+use crate::util::to_sqlite_date;
+
+/// A column of a CSV file, either its position counting from 0 or its header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CsvColumn {
+    Index(usize),
+    Header(String)
+}
+
+/// Which direction a signed amount column counts as money spent
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignConvention {
+    /// Money spent is negative, as in most bank account statements
+    NegativeIsExpense,
+    /// Money spent is positive, as in most credit card statements
+    PositiveIsExpense
+}
+
+impl Default for SignConvention {
+    fn default() -> SignConvention {
+        SignConvention::NegativeIsExpense
+    }
+}
+
+/// Describes how the columns of a bank's CSV export map onto transactions
+///
+/// Amounts are either read from a single signed `amount` column or from
+/// separate `debit` and `credit` columns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CsvMapping {
+    pub date: CsvColumn,
+    pub description: CsvColumn, // Becomes the transaction name
+    pub memo: Option<CsvColumn>, // Becomes the transaction description
+    pub amount: Option<CsvColumn>,
+    pub debit: Option<CsvColumn>,
+    pub credit: Option<CsvColumn>,
+    pub reference: Option<CsvColumn>, // The bank's ID for each entry
+    #[serde(default)]
+    pub sign_convention: SignConvention,
+    #[serde(default = "default_date_format")]
+    pub date_format: String, // chrono format string, e.g. "%d/%m/%Y"
+    #[serde(default = "default_has_header")]
+    pub has_header: bool,
+    pub delimiter: Option<char>, // Defaults to ','
+    #[serde(default = "default_credit_kind")]
+    pub credit_kind: TransactionKind // Kind given to money coming in
+}
+
+fn default_date_format() -> String {
+    String::from("%d/%m/%Y")
+}
+
+fn default_has_header() -> bool {
+    true
+}
+
+fn default_credit_kind() -> TransactionKind {
+    TransactionKind::Refund
+}
+
+// Column positions of a mapping once headers have been looked up
+struct ResolvedColumns {
+    date: usize,
+    description: usize,
+    memo: Option<usize>,
+    amount: Option<usize>,
+    debit: Option<usize>,
+    credit: Option<usize>,
+    reference: Option<usize>
+}
+
+/// Reads the entries of a CSV statement, lines that can't be read are
+/// returned as rejected rows rather than failing the whole file
+pub fn parse_csv(text: &str, mapping: &CsvMapping) -> Result<Vec<ImportRow>, Error> {
+    if mapping.amount.is_none() && mapping.debit.is_none() && mapping.credit.is_none() {
+        return Err(Error::InvalidStatement(String::from(
+            "mapping needs an amount column or debit and credit columns",
+        )));
+    }
+
+    let delimiter = mapping.delimiter.unwrap_or(',');
+
+    if !delimiter.is_ascii() {
+        return Err(Error::InvalidStatement(String::from("delimiter must be an ASCII character")));
+    }
+
+    // Bank exports are often saved with a byte order mark
+    let text = text.trim_start_matches('\u{feff}');
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(mapping.has_header)
+        .delimiter(delimiter as u8)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+
+    let headers: Vec<String> = if mapping.has_header {
+        match reader.headers() {
+            Ok(x) => x.iter().map(|x| x.trim().to_lowercase()).collect(),
+            Err(error) => return Err(Error::InvalidStatement(error.to_string())),
+        }
+    } else {
+        Vec::new()
+    };
+
+    let resolve = |column: &CsvColumn| -> Result<usize, Error> {
+        match column {
+            CsvColumn::Index(x) => Ok(*x),
+            CsvColumn::Header(name) => headers
+                .iter()
+                .position(|x| *x == name.trim().to_lowercase())
+                .ok_or_else(|| Error::InvalidStatement(format!("no column named \"{}\"", name))),
+        }
+    };
+
+    let resolve_optional = |column: &Option<CsvColumn>| -> Result<Option<usize>, Error> {
+        match column {
+            Some(x) => resolve(x).map(Some),
+            None => Ok(None),
+        }
+    };
+
+    let columns = ResolvedColumns {
+        date: resolve(&mapping.date)?,
+        description: resolve(&mapping.description)?,
+        memo: resolve_optional(&mapping.memo)?,
+        amount: resolve_optional(&mapping.amount)?,
+        debit: resolve_optional(&mapping.debit)?,
+        credit: resolve_optional(&mapping.credit)?,
+        reference: resolve_optional(&mapping.reference)?,
+    };
+
+    let mut rows: Vec<ImportRow> = Vec::new();
+
+    for (i, record) in reader.records().enumerate() {
+        let record = match record {
+            Ok(x) => x,
+            Err(error) => {
+                let line = error.position().map(|x| line_at(text, x)).unwrap_or(i as u64 + 1);
+                rows.push(ImportRow::rejected(line, error.to_string()));
+                continue;
+            }
+        };
+
+        // Skip blank lines, such as those trailing some exports
+        if record.iter().all(|x| x.trim().is_empty()) {
+            continue;
+        }
+
+        let line = record.position().map(|x| line_at(text, x)).unwrap_or(i as u64 + 1);
+
+        let field = |index: usize| record.get(index).map(|x| x.trim()).unwrap_or("");
+
+        rows.push(match parse_record(&field, &columns, mapping) {
+            Ok(entry) => ImportRow::parsed(line, entry),
+            Err(reason) => ImportRow::rejected(line, reason),
+        });
+    }
+
+    Ok(rows)
+}
+
+// The reader's own line numbers leave out blank lines, so count them from
+// the record's byte offset instead, which is before any blank lines
+fn line_at(text: &str, position: &csv::Position) -> u64 {
+    let bytes = text.as_bytes();
+    let mut offset = (position.byte() as usize).min(bytes.len());
+
+    while offset < bytes.len() && (bytes[offset] == b'\n' || bytes[offset] == b'\r') {
+        offset += 1;
+    }
+
+    bytes[..offset].iter().filter(|x| **x == b'\n').count() as u64 + 1
+}
+
+fn parse_record<'a, F>(field: &F, columns: &ResolvedColumns, mapping: &CsvMapping) -> Result<StatementEntry, String>
+where
+    F: Fn(usize) -> &'a str,
+{
+    let date = match NaiveDate::parse_from_str(field(columns.date), &mapping.date_format) {
+        Ok(x) => to_sqlite_date(&x),
+        Err(_) => return Err(format!("invalid date \"{}\"", field(columns.date))),
+    };
+
+    let name = field(columns.description).to_string();
+
+    if name.is_empty() {
+        return Err(String::from("missing description"));
+    }
+
+    let read_amount = |index: Option<usize>| -> Result<Option<f64>, String> {
+        match index.map(|x| field(x)) {
+            None | Some("") => Ok(None),
+            Some(x) => match parse_amount(x) {
+                Some(amount) => Ok(Some(amount)),
+                None => Err(format!("invalid amount \"{}\"", x)),
+            },
+        }
+    };
+
+    // Positive for money spent, negative for money coming in
+    let spent = match read_amount(columns.amount)? {
+        Some(amount) => match mapping.sign_convention {
+            SignConvention::NegativeIsExpense => -amount,
+            SignConvention::PositiveIsExpense => amount,
+        },
+        None => {
+            let debit = read_amount(columns.debit)?.unwrap_or(0.0).abs();
+            let credit = read_amount(columns.credit)?.unwrap_or(0.0).abs();
+
+            debit - credit
+        }
+    };
+
+    if spent == 0.0 {
+        return Err(String::from("amount is zero"));
+    }
+
+    Ok(StatementEntry {
+        date,
+        name,
+        description: columns.memo.map(|x| field(x).to_string()).unwrap_or_default(),
+        amount: spent.abs(),
+        kind: if spent > 0.0 { TransactionKind::Expense } else { mapping.credit_kind },
+        external_id: columns
+            .reference
+            .map(|x| field(x).to_string())
+            .filter(|x| !x.is_empty()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::statement_import::ImportStatus;
+
+    fn mapping() -> CsvMapping {
+        CsvMapping {
+            date: CsvColumn::Header(String::from("Date")),
+            description: CsvColumn::Header(String::from("Description")),
+            memo: None,
+            amount: Some(CsvColumn::Header(String::from("Amount"))),
+            debit: None,
+            credit: None,
+            reference: None,
+            sign_convention: SignConvention::NegativeIsExpense,
+            date_format: default_date_format(),
+            has_header: true,
+            delimiter: None,
+            credit_kind: default_credit_kind(),
+        }
+    }
+
+    fn entry(row: &ImportRow) -> &StatementEntry {
+        match &row.entry {
+            Some(x) => x,
+            None => panic!("line {} was rejected: {:?}", row.line, row.reason),
+        }
+    }
+
+    #[test]
+    fn sign_conventions() {
+        let cases = [
+            (SignConvention::NegativeIsExpense, "-12.50", TransactionKind::Expense),
+            (SignConvention::NegativeIsExpense, "12.50", TransactionKind::Refund),
+            (SignConvention::NegativeIsExpense, "(12.50)", TransactionKind::Expense),
+            (SignConvention::PositiveIsExpense, "12.50", TransactionKind::Expense),
+            (SignConvention::PositiveIsExpense, "-12.50", TransactionKind::Refund),
+            (SignConvention::PositiveIsExpense, "$12.50", TransactionKind::Expense),
+        ];
+
+        for (sign_convention, amount, kind) in cases.iter() {
+            let mapping = CsvMapping {
+                sign_convention: *sign_convention,
+                ..mapping()
+            };
+            let text = format!("Date,Description,Amount\n31/01/2020,Shop,\"{}\"\n", amount);
+
+            let rows = parse_csv(&text, &mapping).unwrap();
+            let entry = entry(&rows[0]);
+
+            assert_eq!(entry.amount, 12.5, "{:?} {}", sign_convention, amount);
+            assert_eq!(entry.kind, *kind, "{:?} {}", sign_convention, amount);
+        }
+    }
+
+    #[test]
+    fn debit_and_credit_columns() {
+        let mapping = CsvMapping {
+            amount: None,
+            debit: Some(CsvColumn::Header(String::from("Debit"))),
+            credit: Some(CsvColumn::Header(String::from("Credit"))),
+            credit_kind: TransactionKind::Income,
+            ..mapping()
+        };
+        let text = "Date,Description,Debit,Credit\n\
+            31/01/2020,Shop,12.50,\n\
+            31/01/2020,Salary,,1000\n\
+            31/01/2020,Card fee,-3.00,\n";
+
+        let rows = parse_csv(text, &mapping).unwrap();
+
+        let expected = [
+            (12.5, TransactionKind::Expense),
+            (1000.0, TransactionKind::Income),
+            // Some banks write debits as negative numbers
+            (3.0, TransactionKind::Expense),
+        ];
+
+        for (row, (amount, kind)) in rows.iter().zip(expected.iter()) {
+            assert_eq!(entry(row).amount, *amount);
+            assert_eq!(entry(row).kind, *kind);
+        }
+    }
+
+    #[test]
+    fn date_formats() {
+        let cases = [
+            ("%d/%m/%Y", "31/01/2020"),
+            ("%m/%d/%Y", "01/31/2020"),
+            ("%Y-%m-%d", "2020-01-31"),
+            ("%d.%m.%y", "31.01.20"),
+            ("%d %b %Y", "31 Jan 2020"),
+        ];
+
+        for (date_format, date) in cases.iter() {
+            let mapping = CsvMapping {
+                date_format: date_format.to_string(),
+                ..mapping()
+            };
+            let text = format!("Date,Description,Amount\n{},Shop,-1\n", date);
+
+            let rows = parse_csv(&text, &mapping).unwrap();
+
+            assert_eq!(entry(&rows[0]).date, "2020-01-31", "{} {}", date_format, date);
+        }
+    }
+
+    #[test]
+    fn columns_by_index_without_a_header() {
+        let mapping = CsvMapping {
+            date: CsvColumn::Index(0),
+            description: CsvColumn::Index(2),
+            memo: Some(CsvColumn::Index(3)),
+            amount: Some(CsvColumn::Index(1)),
+            reference: Some(CsvColumn::Index(4)),
+            has_header: false,
+            delimiter: Some(';'),
+            ..mapping()
+        };
+        let text = "31/01/2020;-4.20;Cafe;Coffee;ref-1\n";
+
+        let rows = parse_csv(text, &mapping).unwrap();
+        let entry = entry(&rows[0]);
+
+        assert_eq!(entry.name, "Cafe");
+        assert_eq!(entry.description, "Coffee");
+        assert_eq!(entry.amount, 4.2);
+        assert_eq!(entry.external_id, Some(String::from("ref-1")));
+    }
+
+    #[test]
+    fn headers_are_matched_loosely() {
+        // A byte order mark, different case and padding around the names
+        let text = "\u{feff} DATE , description,AMOUNT\n31/01/2020,Shop,-1\n";
+
+        let rows = parse_csv(text, &mapping()).unwrap();
+
+        assert_eq!(entry(&rows[0]).name, "Shop");
+    }
+
+    #[test]
+    fn mapping_errors() {
+        let missing_column = CsvMapping {
+            memo: Some(CsvColumn::Header(String::from("Memo"))),
+            ..mapping()
+        };
+        let no_amount = CsvMapping {
+            amount: None,
+            ..mapping()
+        };
+        let bad_delimiter = CsvMapping {
+            delimiter: Some('§'),
+            ..mapping()
+        };
+
+        for mapping in [missing_column, no_amount, bad_delimiter].iter() {
+            match parse_csv("Date,Description,Amount\n", mapping) {
+                Err(Error::InvalidStatement(_)) => (),
+                x => panic!("{:?} was accepted: {:?}", mapping, x),
+            }
+        }
+    }
+
+    #[test]
+    fn rejected_rows_keep_their_line_numbers() {
+        let text = "Date,Description,Amount\n\
+            31/01/2020,Shop,-1\n\
+            2020-01-31,Bad date,-1\n\
+            31/01/2020,,-1\n\
+            \n\
+            31/01/2020,Bad amount,abc\n\
+            31/01/2020,Nothing,0\n\
+            31/01/2020,\"Multi\nline\",-2\n\
+            01/02/2020,Last,-3\n";
+
+        let rows = parse_csv(text, &mapping()).unwrap();
+
+        let expected = [
+            (2, ImportStatus::New, None),
+            (3, ImportStatus::Rejected, Some("invalid date \"2020-01-31\"")),
+            (4, ImportStatus::Rejected, Some("missing description")),
+            (6, ImportStatus::Rejected, Some("invalid amount \"abc\"")),
+            (7, ImportStatus::Rejected, Some("amount is zero")),
+            (8, ImportStatus::New, None),
+            (10, ImportStatus::New, None),
+        ];
+
+        assert_eq!(rows.len(), expected.len());
+
+        for (row, (line, status, reason)) in rows.iter().zip(expected.iter()) {
+            assert_eq!(row.line, *line);
+            assert_eq!(row.status, *status, "line {}", line);
+            assert_eq!(row.reason.as_ref().map(|x| x.as_str()), *reason, "line {}", line);
+        }
+
+        // Windows line endings count the same
+        let rows = parse_csv("Date,Description,Amount\r\n\r\n31/01/2020,Shop,x\r\n", &mapping()).unwrap();
+
+        assert_eq!(rows[0].line, 3);
+        assert_eq!(rows[0].status, ImportStatus::Rejected);
+    }
+}
@@ -1,23 +1,45 @@
+use crate::account_archive::*;
+use crate::api_key::*;
+use crate::audit_entry::AuditEntry;
+use crate::backup::{backup_path, rotate_backups};
 use crate::budget::Budget;
 use crate::budget_period::BudgetPeriod;
+use crate::can_access_budget::CanAccessBudget;
+use crate::export::BudgetExport;
+use crate::login_throttle::{backoff_delay, LockedAccount};
+use crate::oidc::IdTokenClaims;
+use crate::budget_period_balance::BudgetPeriodBalance;
+use crate::budget_period_summary::BudgetPeriodSummary;
+use crate::period_rule::PeriodRule;
+use crate::registration::{InviteCode, PendingUser, RegistrationMode};
+use crate::rollover_policy::RolloverPolicy;
+use crate::statement_import::{ImportRow, ImportStatus, ImportSummary};
 use crate::transaction::Transaction;
+use crate::transaction_search_hit::TransactionSearchHit;
+use crate::transaction_query::{SortDirection, TransactionPage, TransactionQuery, TransactionSort};
+use crate::totp::*;
+use crate::user_token::TokenPurpose;
 use crate::util::*;
 
+use std::collections::HashSet;
 use std::fs;
-use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 
-use rusqlite::Error::SqliteFailure;
-use rusqlite::{params, Connection, NO_PARAMS};
+use rusqlite::Error::{QueryReturnedNoRows, SqliteFailure};
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, DatabaseName, Row, NO_PARAMS};
 
-use chrono::{DateTime, Duration, FixedOffset, Utc};
+use chrono::Duration;
+use chrono_tz::Tz;
+
+use serde::Serialize;
+use serde_json::json;
 
 use time::Duration as OldDuration;
 
-use termion::input::TermRead;
 
 #[derive(Debug)]
 pub enum Error {
@@ -29,6 +51,32 @@ pub enum Error {
     InvalidCredentials,
     UserDeniedError,
     AccessRecursionError,
+    BudgetArchived,
+    InvalidTimezone,
+    InvalidPeriodRule,
+    InvalidPeriod,
+    InvalidCursor,
+    InvalidRolloverPolicy,
+    InvalidDate,
+    DateBeforeBudgetStart,
+    InvalidStatement(String),
+    UnsupportedArchiveVersion,
+    BackupError(String),
+    InvalidToken,
+    InvalidEmail,
+    EmailNotVerified,
+    RegistrationDisabled,
+    InvalidInviteCode,
+    AccountPendingApproval,
+    TwoFactorAlreadyEnabled,
+    TwoFactorNotEnabled,
+    InvalidTwoFactorCode,
+    CorruptTwoFactorSecret,
+    TooManyAttempts,
+    AccountLocked,
+    NoLinkedAccount,
+    IdentityAlreadyLinked,
+    ApiKeyScopeDenied,
     SqliteError(libsqlite3_sys::Error, Option<String>),
     UnknownError,
 }
@@ -47,13 +95,246 @@ impl std::convert::From<chrono::ParseError> for Error {
     }
 }
 
+// Columns selected whenever a full `User` is read, see `user_from_row`
+const USER_COLUMNS: &str =
+    "email, first_name, last_name, password, access_token, is_admin, timezone, email_verified, approved";
+
+// Columns selected whenever a full `Budget` is read, see `budget_from_row`
+const BUDGET_COLUMNS: &str =
+    "budget_id, owner, name, spend_limit, period_length, start_date, archived, timezone, period_rule,
+    rollover_policy";
+
+// Columns selected whenever a full `ApiKey` is read, see `api_key_from_row`
+const API_KEY_COLUMNS: &str =
+    "api_key_id, name, prefix, scope, budget_ids, created_at, last_used_at, revoked_at";
+
+// Columns selected whenever a full `Transaction` is read, see `transaction_from_row`
+const TRANSACTION_COLUMNS: &str =
+    "transaction_id, budget_id, email, name, description, date, amount, recur_days, recur_until, deleted_at,
+    kind, external_id";
+
+// Selects the live transactions of budget ?1 dated within a period, the
+// bounds ?2 and ?3 must come from `period_bounds`. Scheduled transactions
+// dated after ?4, the current time in the budget's timezone, are excluded.
+const IN_PERIOD_CONDITION: &str =
+    "budget_id = ?1 AND deleted_at IS NULL AND date >= ?2 AND date < ?3 AND date <= ?4";
+
+// Page of transactions returned by `query_transactions` when no limit is given
+const DEFAULT_TRANSACTION_PAGE_SIZE: i64 = 100;
+
+// Largest page of transactions returned by `query_transactions`
+const MAX_TRANSACTION_PAGE_SIZE: i64 = 1000;
+
+// Largest number of results returned by `search_transactions`
+const MAX_SEARCH_RESULTS: i64 = 100;
+
+// Control characters that can't be typed into a transaction, used to mark
+// snippet matches until the text around them has been HTML escaped
+const SNIPPET_MATCH_START: char = '\u{2}';
+const SNIPPET_MATCH_END: char = '\u{3}';
+
+// Largest number of periods summarised by one call to `get_budget_period_summaries`
+const MAX_SUMMARY_PERIODS: i64 = 120;
+
+// How far past the current period summaries may be requested, for projections
+const MAX_FUTURE_PERIODS: i64 = 12;
+
+// Schema changes applied on top of the tables created by `Database::init`.
+//
+// The index of each migration (plus one) is the schema version it produces,
+// the current version is stored in the database file using `PRAGMA user_version`.
+// Never edit or reorder existing entries, only append new ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: Budget archiving
+    "ALTER TABLE budgets ADD COLUMN archived BOOL NOT NULL DEFAULT FALSE;",
+    // 2: Soft deletion of transactions
+    "ALTER TABLE transactions ADD COLUMN deleted_at TEXT;",
+    // 3: Audit log
+    "CREATE TABLE audit_log (
+        audit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+        actor TEXT NOT NULL,
+        action TEXT NOT NULL,
+        target_type TEXT NOT NULL,
+        target_id TEXT NOT NULL,
+        budget_id INTEGER,
+        before TEXT,
+        after TEXT,
+        date TEXT NOT NULL
+    );
+
+    CREATE INDEX audit_log_budget_id ON audit_log(budget_id);
+
+    CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+    BEGIN
+        SELECT RAISE(ABORT, 'audit log is append-only');
+    END;
+
+    CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+    BEGIN
+        SELECT RAISE(ABORT, 'audit log is append-only');
+    END;",
+    // 4: Per-user and per-budget timezones
+    "ALTER TABLE users ADD COLUMN timezone TEXT;
+    ALTER TABLE budgets ADD COLUMN timezone TEXT;",
+    // 5: Calendar aligned budget periods, NULL means every `period_length` days
+    "ALTER TABLE budgets ADD COLUMN period_rule TEXT;",
+    // 6: Rollover of leftover balances between periods, NULL means no rollover
+    "ALTER TABLE budgets ADD COLUMN rollover_policy TEXT;",
+    // 7: Canonical date formats, see `to_sqlite_date` and `to_sqlite_date_time`
+    "UPDATE transactions SET date = strftime('%Y-%m-%d %H:%M:%f', date)
+        WHERE strftime('%Y-%m-%d %H:%M:%f', date) IS NOT NULL;
+    UPDATE budgets SET start_date = date(start_date)
+        WHERE date(start_date) IS NOT NULL;
+    CREATE INDEX transactions_budget_id_date ON transactions(budget_id, date);",
+    // 8: Transaction kinds
+    "ALTER TABLE transactions ADD COLUMN kind TEXT NOT NULL DEFAULT 'expense';",
+    // 9: Full-text search over transaction names and descriptions, kept in
+    // sync with the transactions table by triggers so that every insert and
+    // edit is indexed
+    "CREATE VIRTUAL TABLE transactions_fts USING fts5(
+        name, description, content='transactions', content_rowid='transaction_id'
+    );
+
+    INSERT INTO transactions_fts(transactions_fts) VALUES('rebuild');
+
+    CREATE TRIGGER transactions_fts_insert AFTER INSERT ON transactions
+    BEGIN
+        INSERT INTO transactions_fts(rowid, name, description)
+        VALUES (new.transaction_id, new.name, new.description);
+    END;
+
+    CREATE TRIGGER transactions_fts_delete AFTER DELETE ON transactions
+    BEGIN
+        INSERT INTO transactions_fts(transactions_fts, rowid, name, description)
+        VALUES ('delete', old.transaction_id, old.name, old.description);
+    END;
+
+    CREATE TRIGGER transactions_fts_update AFTER UPDATE OF name, description ON transactions
+    BEGIN
+        INSERT INTO transactions_fts(transactions_fts, rowid, name, description)
+        VALUES ('delete', old.transaction_id, old.name, old.description);
+        INSERT INTO transactions_fts(rowid, name, description)
+        VALUES (new.transaction_id, new.name, new.description);
+    END;",
+    // 10: Bank references of imported transactions, used to skip entries that
+    // have already been imported
+    "ALTER TABLE transactions ADD COLUMN external_id TEXT;
+    CREATE INDEX transactions_budget_id_external_id ON transactions(budget_id, external_id);",
+    // 11: Single-use tokens sent to users, e.g. for password resets. Only a
+    // hash of each token is stored.
+    "CREATE TABLE user_tokens (
+        token_hash TEXT NOT NULL PRIMARY KEY,
+        email TEXT NOT NULL,
+        purpose TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT NOT NULL,
+        used_at TEXT,
+        FOREIGN KEY(email) REFERENCES users(email)
+    );
+
+    CREATE INDEX user_tokens_email_purpose ON user_tokens(email, purpose);",
+    // 12: Email verification, existing accounts are treated as verified
+    "ALTER TABLE users ADD COLUMN email_verified BOOL NOT NULL DEFAULT TRUE;",
+    // 13: Registration policy, existing accounts are treated as approved. Only
+    // a hash of each invite code is stored.
+    "ALTER TABLE users ADD COLUMN approved BOOL NOT NULL DEFAULT TRUE;
+
+    CREATE TABLE invite_codes (
+        code_hash TEXT NOT NULL PRIMARY KEY,
+        created_by TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT NOT NULL,
+        used_by TEXT,
+        used_at TEXT,
+        FOREIGN KEY(created_by) REFERENCES users(email)
+    );",
+    // 14: Two-factor authentication. Secrets are encrypted with a key derived
+    // from the server secret and only hashes of recovery codes are stored.
+    "CREATE TABLE two_factor (
+        email TEXT NOT NULL PRIMARY KEY,
+        secret TEXT NOT NULL,
+        enabled BOOL NOT NULL,
+        last_counter INTEGER,
+        FOREIGN KEY(email) REFERENCES users(email)
+    );
+
+    CREATE TABLE recovery_codes (
+        code_hash TEXT NOT NULL PRIMARY KEY,
+        email TEXT NOT NULL,
+        used_at TEXT,
+        FOREIGN KEY(email) REFERENCES users(email)
+    );
+
+    CREATE INDEX recovery_codes_email ON recovery_codes(email);",
+    // 15: Consecutive failed logins of each account, cleared by a successful login
+    "CREATE TABLE login_failures (
+        email TEXT NOT NULL PRIMARY KEY,
+        failures INTEGER NOT NULL,
+        last_failure_at TEXT NOT NULL,
+        locked_until TEXT,
+        FOREIGN KEY(email) REFERENCES users(email)
+    );",
+    // 16: Accounts at the single sign-on provider linked to local users
+    "CREATE TABLE external_identities (
+        issuer TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        email TEXT NOT NULL,
+        linked_at TEXT NOT NULL,
+        PRIMARY KEY(issuer, subject),
+        FOREIGN KEY(email) REFERENCES users(email)
+    );
+
+    CREATE INDEX external_identities_email ON external_identities(email);",
+    // 17: API keys for scripts. Only a hash of each key is stored, budget_ids
+    // is a JSON array of the budgets the key is limited to.
+    "CREATE TABLE api_keys (
+        api_key_id INTEGER PRIMARY KEY AUTOINCREMENT,
+        email TEXT NOT NULL,
+        name TEXT NOT NULL,
+        key_hash TEXT NOT NULL UNIQUE,
+        prefix TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        budget_ids TEXT,
+        created_at TEXT NOT NULL,
+        last_used_at TEXT,
+        revoked_at TEXT,
+        FOREIGN KEY(email) REFERENCES users(email)
+    );
+
+    CREATE INDEX api_keys_email ON api_keys(email);",
+    // 18: Failed logins are counted for emails without an account too, so that
+    // lockouts don't reveal which emails are registered
+    "CREATE TABLE login_failures_new (
+        email TEXT NOT NULL PRIMARY KEY,
+        failures INTEGER NOT NULL,
+        last_failure_at TEXT NOT NULL,
+        locked_until TEXT
+    );
+
+    INSERT INTO login_failures_new SELECT email, failures, last_failure_at, locked_until FROM login_failures;
+    DROP TABLE login_failures;
+    ALTER TABLE login_failures_new RENAME TO login_failures;",
+    // 19: Wrong codes entered against each token, see `finish_two_factor_login`
+    "ALTER TABLE user_tokens ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;",
+];
+
+/// Schema version produced by applying every migration
+pub fn schema_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+// Actor recorded in the audit log for changes made by the server itself
+pub const SYSTEM_ACTOR: &str = "system";
+
 pub struct Database {
     db_conn: Connection,
     secret: String,
+    // Timezone used for users and budgets that don't have one set
+    default_timezone: Tz,
 }
 
 impl Database {
-    pub fn new(secret: String, path: &str) -> Result<Database, Error> {
+    pub fn new(secret: String, default_timezone: Tz, path: &str) -> Result<Database, Error> {
         let rpath = Path::new(path);
 
         // Check if db file exist
@@ -70,65 +351,21 @@ impl Database {
             .execute("PRAGMA foreign_keys = ON", NO_PARAMS)
             .expect("Failed enabling foreign key support.");
 
-        let database = Database { secret, db_conn };
+        let database = Database {
+            secret,
+            db_conn,
+            default_timezone,
+        };
 
         // Does the database need to be initialised?
         if init_req {
-            match database.init() {
-                Err(error) => rollback(path, error),
-                Ok(_) => {
-                    // Create admin user
-                    println!(" === Admin User Setup ===");
-
-                    println!("email:");
-
-                    let mut buffer = String::new();
-
-                    io::stdin()
-                        .read_line(&mut buffer)
-                        .expect("Failed reading line.");
-                    let email = buffer;
-
-                    let password: String;
-                    loop {
-                        println!("Password:");
-                        let buffer = io::stdin().read_passwd(&mut io::stdout());
-
-                        match buffer {
-                            Ok(Some(p)) => {
-                                password = p;
-                                break;
-                            }
-                            _ => (),
-                        }
-                    }
-
-                    io::stdin()
-                        .read_passwd(&mut io::stdout())
-                        .expect("Failed reading password");
-
-                    let admin_user = User::new(
-                        &database,
-                        &email,
-                        &String::from("Administrator"),
-                        &String::from("Account"),
-                        &password,
-                        true,
-                    );
-
-                    match database.insert_user(&admin_user) {
-                        Ok(_) => {
-                            println!("Admin user created.");
-                        }
-                        Err(error) => {
-                            println!("Error: Failed creating admin user.");
-                            rollback(path, error)
-                        }
-                    };
-                }
-            };
+            if let Err(error) = database.init() {
+                rollback(path, error);
+            }
         }
 
+        database.migrate()?;
+
         Ok(database)
     }
 
@@ -182,6 +419,59 @@ impl Database {
         }
     }
 
+    /// Brings the database schema up to date by applying any pending `MIGRATIONS`
+    fn migrate(&self) -> Result<(), Error> {
+        let version: i64 = self
+            .db_conn
+            .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let target_version = i + 1;
+
+            println!("Migrating database to schema version {}...", target_version);
+
+            self.atomically(|| {
+                self.db_conn.execute_batch(migration)?;
+                self.db_conn
+                    .execute_batch(&format!("PRAGMA user_version = {}", target_version))?;
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Current schema version of the database, see `MIGRATIONS`
+    pub fn get_schema_version(&self) -> Result<i64, Error> {
+        Ok(self
+            .db_conn
+            .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?)
+    }
+
+    /// Runs `f` inside of a database transaction, the transaction is committed
+    /// if `f` succeeds and rolled back otherwise
+    ///
+    /// Savepoints are used so that calls may be nested, in which case nothing
+    /// is committed until the outermost call succeeds
+    fn atomically<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        self.db_conn.execute_batch("SAVEPOINT atomically")?;
+
+        match f() {
+            Ok(x) => {
+                self.db_conn.execute_batch("RELEASE atomically")?;
+                Ok(x)
+            }
+            Err(error) => {
+                self.db_conn
+                    .execute_batch("ROLLBACK TO atomically; RELEASE atomically")?;
+                Err(error)
+            }
+        }
+    }
+
     pub fn hash(&self, s: &String) -> String {
         let mut hasher = Sha256::new();
 
@@ -195,19 +485,360 @@ impl Database {
         hasher.result_str()
     }
 
+    /// Copies the database into a new file in `dir` using SQLite's online
+    /// backup API, which is safe while the database is in use, then deletes
+    /// all but the newest `keep` backups
+    pub fn backup(&self, actor: &str, dir: &Path, keep: usize) -> Result<PathBuf, Error> {
+        fs::create_dir_all(dir).map_err(|x| Error::BackupError(x.to_string()))?;
+
+        let path = backup_path(dir, &get_now(&self.default_timezone).naive_local());
+
+        self.db_conn.backup(DatabaseName::Main, &path, None)?;
+
+        rotate_backups(dir, keep)?;
+
+        self.record_audit(
+            actor,
+            "database.backup",
+            "database",
+            &path.to_string_lossy(),
+            None,
+            None,
+            Some(&json!({ "path": path })),
+        )?;
+
+        Ok(path)
+    }
+
+    /// Backs up the database on behalf of an admin, see `backup`
+    pub fn create_backup(&self, access_token: &str, dir: &Path, keep: usize) -> Result<PathBuf, Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        if !user.is_admin {
+            return Err(Error::UserDeniedError);
+        }
+
+        self.backup(&user.email, dir, keep)
+    }
+
+    /// Appends an entry to the audit log
+    ///
+    /// `before` and `after` are snapshots of the target, `None` when the target
+    /// didn't exist before or doesn't exist after the action
+    fn record_audit<T: Serialize>(
+        &self,
+        actor: &str,
+        action: &str,
+        target_type: &str,
+        target_id: &str,
+        budget_id: Option<i64>,
+        before: Option<&T>,
+        after: Option<&T>,
+    ) -> Result<(), Error> {
+        let before = before.map(|x| json!(x).to_string());
+        let after = after.map(|x| json!(x).to_string());
+
+        self.db_conn
+            .execute(
+                "INSERT INTO audit_log(
+                    actor, action, target_type, target_id, budget_id, before, after, date
+                )
+                VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    actor,
+                    action,
+                    target_type,
+                    target_id,
+                    budget_id,
+                    before,
+                    after,
+                    get_current_date_time(&self.default_timezone)
+                ],
+            )
+            .map_err(sqlite_error)?;
+
+        Ok(())
+    }
+
+    /// Gets audit log entries, most recent first
+    ///
+    /// When `budget_id` is given only that budget's entries are returned and
+    /// the user must be its owner, otherwise the user must be an admin and
+    /// every entry is returned
+    pub fn get_audit_log(
+        &self,
+        access_token: &str,
+        budget_id: Option<i64>,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        match budget_id {
+            Some(budget_id) => {
+                if !user.is_admin {
+                    self.get_owned_budget(&user, budget_id)?;
+                }
+            }
+            None => {
+                if !user.is_admin {
+                    return Err(Error::UserDeniedError);
+                }
+            }
+        };
+
+        let mut stmt = self.db_conn.prepare(
+            "SELECT audit_id, actor, action, target_type, target_id, budget_id, before, after, date
+            FROM audit_log WHERE ?1 IS NULL OR budget_id = ?1 ORDER BY audit_id DESC",
+        )?;
+
+        let mut result: Vec<AuditEntry> = Vec::new();
+
+        let entry_iter = stmt.query_map(params![budget_id], audit_entry_from_row);
+
+        for entry in entry_iter? {
+            result.push(entry?);
+        }
+
+        Ok(result)
+    }
+
     pub fn insert_user(&self, user: &User) -> Result<(), Error> {
+        if !is_valid_email(&user.email) {
+            return Err(Error::InvalidEmail);
+        }
+
+        self.atomically(|| {
+            self.insert_user_row(user)?;
+            self.record_audit(
+                &user.email,
+                "user.register",
+                "user",
+                &user.email,
+                None,
+                None,
+                Some(&user.profile()),
+            )
+        })
+    }
+
+    /// Adds a user registering themselves, subject to the registration mode
+    ///
+    /// The invite code is only checked, and used up, while registration is
+    /// invite only. Users registering while admin approval is required can't
+    /// sign in until an admin approves them.
+    pub fn register_user(
+        &self,
+        user: &mut User,
+        mode: RegistrationMode,
+        invite_code: Option<&str>,
+    ) -> Result<(), Error> {
+        match mode {
+            RegistrationMode::Open => self.insert_user(user),
+            RegistrationMode::Disabled => Err(Error::RegistrationDisabled),
+            RegistrationMode::InviteOnly => {
+                let invite_code = match invite_code {
+                    Some(x) => x,
+                    None => return Err(Error::InvalidInviteCode),
+                };
+
+                self.atomically(|| {
+                    self.insert_user(user)?;
+                    self.use_invite_code(invite_code, &user.email)
+                })
+            }
+            RegistrationMode::AdminApproval => {
+                user.approved = false;
+                self.insert_user(user)
+            }
+        }
+    }
+
+    /// Creates an invite code valid for `lifetime`, only admins may invite users
+    pub fn create_invite_code(&self, access_token: &str, lifetime: Duration) -> Result<InviteCode, Error> {
+        let admin = self.get_admin_user(access_token)?;
+
+        let now = get_now(&self.default_timezone).naive_local();
+
+        let invite = InviteCode {
+            code: generate_token(16),
+            created_by: admin.email.clone(),
+            expires_at: to_sqlite_date_time(&(now + lifetime)),
+        };
+
+        let code_hash = self.hash(&invite.code);
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "INSERT INTO invite_codes(code_hash, created_by, created_at, expires_at)
+                    VALUES(?1, ?2, ?3, ?4)",
+                    params![
+                        code_hash,
+                        invite.created_by,
+                        to_sqlite_date_time(&now),
+                        invite.expires_at
+                    ],
+                )
+                .map_err(sqlite_error)?;
+
+            // The code itself is left out so that the log can't be used to register
+            self.record_audit(
+                &admin.email,
+                "invite_code.create",
+                "invite_code",
+                &code_hash,
+                None,
+                None,
+                Some(&json!({ "expires_at": invite.expires_at })),
+            )
+        })?;
+
+        Ok(invite)
+    }
+
+    /// Marks an unused, unexpired invite code as used by the user
+    fn use_invite_code(&self, invite_code: &str, email: &str) -> Result<(), Error> {
+        let updated = self
+            .db_conn
+            .execute(
+                "UPDATE invite_codes SET used_by = ?2, used_at = ?3
+                WHERE code_hash = ?1 AND used_at IS NULL AND expires_at > ?3",
+                params![
+                    self.hash(&invite_code.to_string()),
+                    email,
+                    get_current_date_time(&self.default_timezone)
+                ],
+            )
+            .map_err(sqlite_error)?;
+
+        if updated == 0 {
+            return Err(Error::InvalidInviteCode);
+        }
+
+        Ok(())
+    }
+
+    /// Gets the accounts waiting for approval, only admins may see them
+    pub fn get_pending_users(&self, access_token: &str) -> Result<Vec<PendingUser>, Error> {
+        self.get_admin_user(access_token)?;
+
+        let mut stmt = self.db_conn.prepare(
+            "SELECT email, first_name, last_name, email_verified FROM users
+            WHERE NOT approved ORDER BY email",
+        )?;
+
+        let mut result: Vec<PendingUser> = Vec::new();
+
+        let user_iter = stmt.query_map(NO_PARAMS, |row| {
+            Ok(PendingUser {
+                email: row.get(0)?,
+                first_name: row.get(1)?,
+                last_name: row.get(2)?,
+                email_verified: row.get(3)?,
+            })
+        });
+
+        for user in user_iter? {
+            result.push(user?);
+        }
+
+        Ok(result)
+    }
+
+    /// Lets an account waiting for approval sign in
+    pub fn approve_user(&self, access_token: &str, email: &str) -> Result<(), Error> {
+        let admin = self.get_admin_user(access_token)?;
+        let user = self.get_pending_user(email)?;
+
+        self.atomically(|| {
+            self.db_conn
+                .execute("UPDATE users SET approved = TRUE WHERE email = ?1", params![email])
+                .map_err(sqlite_error)?;
+
+            let mut after = user.profile();
+            after["approved"] = json!(true);
+
+            self.record_audit(
+                &admin.email,
+                "user.approve",
+                "user",
+                email,
+                None,
+                Some(&user.profile()),
+                Some(&after),
+            )
+        })
+    }
+
+    /// Deletes an account waiting for approval, the email can then be registered again
+    pub fn reject_user(&self, access_token: &str, email: &str) -> Result<(), Error> {
+        let admin = self.get_admin_user(access_token)?;
+        let user = self.get_pending_user(email)?;
+
+        self.atomically(|| {
+            self.db_conn
+                .execute("DELETE FROM user_tokens WHERE email = ?1", params![email])
+                .map_err(sqlite_error)?;
+
+            self.db_conn
+                .execute("DELETE FROM login_failures WHERE email = ?1", params![email])
+                .map_err(sqlite_error)?;
+
+            self.db_conn
+                .execute("DELETE FROM external_identities WHERE email = ?1", params![email])
+                .map_err(sqlite_error)?;
+
+            self.db_conn
+                .execute("DELETE FROM users WHERE email = ?1", params![email])
+                .map_err(sqlite_error)?;
+
+            self.record_audit(
+                &admin.email,
+                "user.reject",
+                "user",
+                email,
+                None,
+                Some(&user.profile()),
+                None,
+            )
+        })
+    }
+
+    fn get_pending_user(&self, email: &str) -> Result<User, Error> {
+        match self.get_user_by_email(email)? {
+            Some(x) => {
+                if x.approved {
+                    Err(Error::EntryNotFound)
+                } else {
+                    Ok(x)
+                }
+            }
+            None => Err(Error::EntryNotFound),
+        }
+    }
+
+    fn insert_user_row(&self, user: &User) -> Result<(), Error> {
         let res = self.db_conn.execute(
             "INSERT INTO users(
-                email, first_name, last_name, password, access_token, is_admin
+                email, first_name, last_name, password, access_token, is_admin, timezone,
+                email_verified, approved
             )
-            VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
             params![
                 user.email,
                 user.first_name,
                 user.last_name,
                 user.password,
                 user.access_token,
-                user.is_admin
+                user.is_admin,
+                user.timezone,
+                user.email_verified,
+                user.approved
             ],
         );
 
@@ -224,21 +855,12 @@ impl Database {
     }
 
     pub fn get_user_by_email(&self, email: &str) -> Result<Option<User>, Error> {
-        let mut stmt = self.db_conn.prepare(
-            "SELECT email, first_name, last_name, password, access_token, is_admin
-            FROM users WHERE email = ?1",
-        )?;
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM users WHERE email = ?1",
+            USER_COLUMNS
+        ))?;
 
-        match stmt.query_row(params![email], |row| {
-            Ok(User {
-                email: row.get(0)?,
-                first_name: row.get(1)?,
-                last_name: row.get(2)?,
-                password: row.get(3)?,
-                access_token: row.get(4)?,
-                is_admin: row.get(5)?,
-            })
-        }) {
+        match stmt.query_row(params![email], user_from_row) {
             Ok(user) => Ok(Some(user)),
             Err(error) => match error {
                 QueryReturnedNoRows => Ok(None),
@@ -247,22 +869,33 @@ impl Database {
         }
     }
 
+    /// Gets every user, for use by server operators only
+    pub fn get_all_users(&self) -> Result<Vec<User>, Error> {
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM users ORDER BY email",
+            USER_COLUMNS
+        ))?;
+
+        let mut result: Vec<User> = Vec::new();
+
+        let user_iter = stmt.query_map(NO_PARAMS, user_from_row);
+
+        for user in user_iter? {
+            result.push(user?);
+        }
+
+        Ok(result)
+    }
+
+    /// Gets the user an access token belongs to, accounts waiting for
+    /// approval have no valid access token
     pub fn get_user_by_access_token(&self, access_token: &str) -> Result<Option<User>, Error> {
-        let mut stmt = self.db_conn.prepare(
-            "SELECT email, first_name, last_name, password, access_token, is_admin
-            FROM users WHERE access_token = ?1",
-        )?;
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM users WHERE access_token = ?1 AND approved",
+            USER_COLUMNS
+        ))?;
 
-        match stmt.query_row(params![access_token], |row| {
-            Ok(User {
-                email: row.get(0)?,
-                first_name: row.get(1)?,
-                last_name: row.get(2)?,
-                password: row.get(3)?,
-                access_token: row.get(4)?,
-                is_admin: row.get(5)?,
-            })
-        }) {
+        match stmt.query_row(params![access_token], user_from_row) {
             Ok(user) => Ok(Some(user)),
             Err(error) => match error {
                 QueryReturnedNoRows => Ok(None),
@@ -272,673 +905,4483 @@ impl Database {
     }
 
     pub fn update_user(&self, user: &User) -> Result<(), Error> {
-        let res = self.db_conn.execute(
-            "UPDATE users SET first_name = ?1, SET last_name ?2,
-            SET password = ?3, access_token = ?4, is_admin = ?5
-            WHERE email = ?4",
-            params![
-                user.first_name,
-                user.last_name,
-                user.password,
-                user.access_token,
-                user.is_admin,
-                user.email
-            ],
-        );
+        let before = match self.get_user_by_email(&user.email)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
 
-        match res {
-            Ok(_) => Ok(()),
-            Err(error) => match error {
-                SqliteFailure(error, desc) => Err(Error::SqliteError(error, desc)),
-                _ => Err(Error::UnknownError),
-            },
-        }
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE users SET first_name = ?1, last_name = ?2,
+                    password = ?3, access_token = ?4, is_admin = ?5
+                    WHERE email = ?6",
+                    params![
+                        user.first_name,
+                        user.last_name,
+                        user.password,
+                        user.access_token,
+                        user.is_admin,
+                        user.email
+                    ],
+                )
+                .map_err(sqlite_error)?;
+
+            // Password hashes are deliberately left out of the snapshots
+            let action = if before.password != user.password {
+                // Keys made by whoever knew the old password stop working with it
+                self.revoke_user_api_keys(&user.email)?;
+
+                "user.change_password"
+            } else {
+                "user.update"
+            };
+
+            self.record_audit(
+                &user.email,
+                action,
+                "user",
+                &user.email,
+                None,
+                Some(&before.profile()),
+                Some(&user.profile()),
+            )
+        })
     }
 
-    pub fn get_available_budgets(&self, access_token: &str) -> Result<Vec<Budget>, Error> {
-        let mut stmt = self.db_conn.prepare(
-            "SELECT budget_id, owner, name, spend_limit, period_length, start_date FROM budgets WHERE budget_id in (
-            SELECT budget_id FROM (SELECT budget_id, owner AS email FROM budgets
-            UNION SELECT budget_id, email FROM can_access_budget) WHERE email in
-            (SELECT email FROM users WHERE access_token = ?1))"
-        )?;
+    /// Issues a single-use token to the user, replacing any unused token with
+    /// the same purpose
+    fn create_user_token(&self, email: &str, purpose: TokenPurpose, lifetime: Duration) -> Result<String, Error> {
+        let token = generate_token(32);
+        let now = get_now(&self.default_timezone).naive_local();
+
+        self.atomically(|| {
+            self.revoke_user_tokens(email, purpose)?;
+
+            self.db_conn
+                .execute(
+                    "INSERT INTO user_tokens(token_hash, email, purpose, created_at, expires_at)
+                    VALUES(?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        self.hash(&token),
+                        email,
+                        purpose,
+                        to_sqlite_date_time(&now),
+                        to_sqlite_date_time(&(now + lifetime))
+                    ],
+                )
+                .map_err(sqlite_error)?;
+
+            Ok(())
+        })?;
+
+        Ok(token)
+    }
 
-        let mut result: Vec<Budget> = Vec::new();
+    /// Stops every unused token of the user with the given purpose from working
+    fn revoke_user_tokens(&self, email: &str, purpose: TokenPurpose) -> Result<(), Error> {
+        self.db_conn
+            .execute(
+                "UPDATE user_tokens SET used_at = ?3
+                WHERE email = ?1 AND purpose = ?2 AND used_at IS NULL",
+                params![email, purpose, get_current_date_time(&self.default_timezone)],
+            )
+            .map_err(sqlite_error)?;
 
-        let budget_iter = stmt.query_map(params![access_token], |row| {
-            Ok(Budget {
-                budget_id: row.get(0)?,
-                owner: row.get(1)?,
-                name: row.get(2)?,
-                spend_limit: row.get(3)?,
-                period_length: row.get(4)?,
-                start_date: row.get(5)?,
-            })
-        });
+        Ok(())
+    }
 
-        for budget in budget_iter? {
-            result.push(budget?);
-        }
+    fn revoke_user_api_keys(&self, email: &str) -> Result<(), Error> {
+        self.db_conn
+            .execute(
+                "UPDATE api_keys SET revoked_at = ?2 WHERE email = ?1 AND revoked_at IS NULL",
+                params![email, get_current_date_time(&self.default_timezone)],
+            )
+            .map_err(sqlite_error)?;
 
-        Ok(result)
+        Ok(())
     }
 
-    pub fn add_budget(&self, access_token: &str, budget: &Budget) -> Result<Budget, Error> {
-        let user = match self.get_user_by_access_token(access_token)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
+    /// Exchanges a token for the user it was issued to, each token only works once
+    fn consume_user_token(&self, token: &str, purpose: TokenPurpose) -> Result<User, Error> {
+        let now = get_current_date_time(&self.default_timezone);
+        let token_hash = self.hash(&token.to_string());
+
+        let email: String = match self.db_conn.query_row(
+            "SELECT email FROM user_tokens
+            WHERE token_hash = ?1 AND purpose = ?2 AND used_at IS NULL AND expires_at > ?3",
+            params![token_hash, purpose, now],
+            |row| row.get(0),
+        ) {
+            Ok(x) => x,
+            Err(QueryReturnedNoRows) => return Err(Error::InvalidToken),
+            Err(error) => return Err(sqlite_error(error)),
         };
 
-        let res = self.db_conn.execute(
-            "INSERT INTO budgets(
-                owner, name, spend_limit, period_length, start_date
+        self.db_conn
+            .execute(
+                "UPDATE user_tokens SET used_at = ?2 WHERE token_hash = ?1",
+                params![token_hash, now],
             )
-            VALUES(?1, ?2, ?3, ?4, ?5)",
-            params![
-                user.email,
-                budget.name,
-                budget.spend_limit,
-                budget.period_length,
-                budget.start_date
-            ],
-        );
+            .map_err(sqlite_error)?;
 
-        match res {
-            Ok(_) => {
-                let budget_id = self.db_conn.last_insert_rowid();
-                Ok(Budget {
-                    budget_id: Some(budget_id),
-                    owner: Some(user.email),
-                    name: budget.name.clone(),
-                    spend_limit: budget.spend_limit,
-                    period_length: budget.period_length,
-                    start_date: budget.start_date.clone(),
-                })
-            }
-            Err(error) => match error {
-                SqliteFailure(error, desc) => Err(Error::SqliteError(error, desc)),
-                _ => Err(Error::UnknownError),
-            },
+        match self.get_user_by_email(&email)? {
+            Some(x) => Ok(x),
+            None => Err(Error::InvalidToken),
         }
     }
 
-    pub fn get_budget(&self, budget_id: i64) -> Result<Option<Budget>, Error> {
-        // Get budget
-        let mut stmt = self.db_conn.prepare(
-            "SELECT budget_id, owner, name, spend_limit, period_length, start_date FROM budgets
-            WHERE budget_id = ?1",
-        )?;
-
-        match stmt.query_row(params![budget_id], |row| {
-            Ok(Budget {
-                budget_id: row.get(0)?,
-                owner: row.get(1)?,
-                name: row.get(2)?,
-                spend_limit: row.get(3)?,
-                period_length: row.get(4)?,
-                start_date: row.get(5)?,
-            })
-        }) {
-            Ok(budget) => Ok(Some(budget)),
-            Err(error) => match error {
-                QueryReturnedNoRows => Ok(None),
-                _ => Err(Error::UnknownError),
-            },
+    /// Issues a password reset token valid for `lifetime`, `None` when there
+    /// is no user with the email
+    pub fn request_password_reset(&self, email: &str, lifetime: Duration) -> Result<Option<String>, Error> {
+        if self.get_user_by_email(email)?.is_none() {
+            return Ok(None);
         }
-    }
 
-    pub fn get_available_budget(
-        &self,
-        access_token: &str,
-        budget_id: i64,
-    ) -> Result<Option<Budget>, Error> {
-        // Get available budget
-        let mut stmt = self.db_conn.prepare(
-            "SELECT budget_id, owner, name, spend_limit, period_length, start_date FROM budgets WHERE budget_id = ?1 AND budget_id in (
-            SELECT budget_id FROM (SELECT budget_id, owner AS email FROM budgets
-            UNION SELECT budget_id, email FROM can_access_budget) WHERE email in
-            (SELECT email FROM users WHERE access_token = ?2))"
+        let token = self.create_user_token(email, TokenPurpose::PasswordReset, lifetime)?;
+
+        self.record_audit(
+            email,
+            "user.request_password_reset",
+            "user",
+            email,
+            None,
+            None::<&serde_json::Value>,
+            None,
         )?;
 
-        match stmt.query_row(params![budget_id, access_token], |row| {
-            Ok(Budget {
-                budget_id: row.get(0)?,
-                owner: row.get(1)?,
-                name: row.get(2)?,
-                spend_limit: row.get(3)?,
-                period_length: row.get(4)?,
-                start_date: row.get(5)?,
-            })
-        }) {
-            Ok(budget) => Ok(Some(budget)),
-            Err(error) => match error {
-                QueryReturnedNoRows => Ok(None),
-                _ => Err(Error::UnknownError),
-            },
-        }
+        Ok(Some(token))
     }
 
-    pub fn delete_budget(&self, access_token: &str, budget_id: i64) -> Result<(), Error> {
-        let user = match self.get_user_by_access_token(access_token)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
-        };
+    /// Sets a new password using a password reset token, returning the new
+    /// access token
+    ///
+    /// The old access token, the user's API keys and any other outstanding reset
+    /// tokens stop working.
+    /// No access token is returned to users with two-factor authentication,
+    /// they have to sign in with their new password and a code.
+    pub fn confirm_password_reset(&self, token: &str, new_password: &String) -> Result<Option<String>, Error> {
+        if new_password.is_empty() {
+            return Err(Error::InvalidCredentials);
+        }
 
-        // Get budget
-        let budget = self.get_budget(budget_id)?;
+        self.atomically(|| {
+            let mut user = self.consume_user_token(token, TokenPurpose::PasswordReset)?;
 
-        match budget {
-            Some(budget) => {
-                let owner = match budget.owner {
-                    Some(id) => id,
-                    None => return Err(Error::UnknownError),
-                };
+            if !user.approved {
+                return Err(Error::AccountPendingApproval);
+            }
 
-                // Check if user is the budget owner
-                if owner != user.email {
-                    return Err(Error::UserDeniedError);
-                }
+            self.revoke_user_tokens(&user.email, TokenPurpose::PasswordReset)?;
 
-                // Perform deletion
-                let res = self.db_conn.execute(
-                    "DELETE FROM budgets WHERE budget_id = ?1",
-                    params![budget_id],
-                );
-                match res {
-                    Ok(_) => Ok(()),
-                    Err(error) => match error {
-                        SqliteFailure(error, desc) => Err(Error::SqliteError(error, desc)),
-                        _ => Err(Error::UnknownError),
-                    },
-                }
+            // Changing the password also replaces the access token
+            user.change_password(self, &self.hash(new_password));
+            self.update_user(&user)?;
+
+            if self.has_two_factor(&user.email)? {
+                Ok(None)
+            } else {
+                Ok(Some(user.access_token))
             }
-            None => Err(Error::EntryNotFound),
-        }
+        })
     }
 
-    pub fn get_available_can_access_budget_users(
-        &self,
-        access_token: &str,
-        budget_id: i64,
-    ) -> Result<Vec<String>, Error> {
-        let user = match self.get_user_by_access_token(access_token)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
-        };
-
-        let mut stmt = self.db_conn.prepare(
-            "SELECT email FROM users WHERE email IN (SELECT email FROM (SELECT owner AS email FROM budgets WHERE budget_id = ?1
-            UNION SELECT email FROM can_access_budget WHERE budget_id = ?1))"
-        )?;
-
-        let mut is_available = false;
-        let mut result: Vec<String> = Vec::new();
+    /// Fails when the account is locked or hasn't waited long enough since its
+    /// last failed login
+    pub fn check_login_allowed(&self, email: &str) -> Result<(), Error> {
+        let res = self.db_conn.query_row(
+            "SELECT failures, last_failure_at, locked_until FROM login_failures WHERE email = ?1",
+            params![email],
+            |row| Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?)),
+        );
 
-        let email_iter = stmt.query_map(params![budget_id], |row| Ok(row.get(0)?));
+        let (failures, last_failure_at, locked_until) = match res {
+            Ok(x) => x,
+            Err(QueryReturnedNoRows) => return Ok(()),
+            Err(error) => return Err(sqlite_error(error)),
+        };
 
-        for email in email_iter? {
-            let email = email?;
+        let now = get_now(&self.default_timezone).naive_local();
 
-            if email == user.email {
-                is_available = true;
+        if let Some(locked_until) = locked_until {
+            if from_sqlite_date_time(&locked_until)? > now {
+                return Err(Error::AccountLocked);
             }
-
-            result.push(email);
         }
 
-        if result.len() == 0 {
-            Err(Error::EntryNotFound)
-        } else if is_available {
-            Ok(result)
-        } else {
-            Err(Error::UserDeniedError)
+        let delay = Duration::from_std(backoff_delay(failures)).unwrap_or_else(|_| Duration::zero());
+
+        if from_sqlite_date_time(&last_failure_at)? + delay > now {
+            return Err(Error::TooManyAttempts);
         }
+
+        Ok(())
     }
 
-    pub fn add_can_access_budget(
-        &self,
-        access_token: &str,
-        budget_id: i64,
-        email: &str,
-    ) -> Result<(), Error> {
-        // Get current user
-        let user = match self.get_user_by_access_token(access_token)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
-        };
+    /// Counts a failed login, locking the account for `lockout` once it reaches
+    /// `lockout_attempts` consecutive failures
+    ///
+    /// Emails without an account are counted and locked the same way so that
+    /// responses don't reveal which emails are registered.
+    pub fn record_login_failure(&self, email: &str, lockout_attempts: u32, lockout: Duration) -> Result<(), Error> {
+        let now = get_now(&self.default_timezone).naive_local();
+
+        // Guessed emails would otherwise pile up forever
+        self.db_conn
+            .execute(
+                "DELETE FROM login_failures WHERE email NOT IN (SELECT email FROM users)
+                AND last_failure_at < ?1 AND (locked_until IS NULL OR locked_until < ?1)",
+                params![to_sqlite_date_time(&(now - Duration::days(1)))],
+            )
+            .map_err(sqlite_error)?;
 
-        // Get budget
-        let budget = match self.get_budget(budget_id) {
-            Ok(budget) => match budget {
-                Some(budget) => budget,
-                None => return Err(Error::EntryNotFound),
-            },
-            Err(error) => return Err(error),
+        let failures: u32 = match self.db_conn.query_row(
+            "SELECT failures FROM login_failures WHERE email = ?1",
+            params![email],
+            |row| row.get(0),
+        ) {
+            Ok(x) => x,
+            Err(QueryReturnedNoRows) => 0,
+            Err(error) => return Err(sqlite_error(error)),
         };
 
-        // Check if the current user is the budget owner
-        match budget.owner {
-            Some(owner) => {
-                if !owner.eq(&user.email) {
-                    return Err(Error::UserDeniedError);
-                }
-            }
-            None => return Err(Error::UnknownError),
-        };
+        let failures = failures + 1;
 
-        // Check if the request is trying to give owner access to their own budget
-        if email.eq(&user.email) {
-            return Err(Error::AccessRecursionError);
+        if failures < lockout_attempts {
+            self.db_conn
+                .execute(
+                    "INSERT OR REPLACE INTO login_failures(email, failures, last_failure_at, locked_until)
+                    VALUES(?1, ?2, ?3, NULL)",
+                    params![email, failures, to_sqlite_date_time(&now)],
+                )
+                .map_err(sqlite_error)?;
+
+            return Ok(());
         }
 
-        let res = self.db_conn.execute(
-            "INSERT INTO can_access_budget(
-                budget_id, email
+        let locked_until = to_sqlite_date_time(&(now + lockout));
+
+        // Failures start again from zero once the lock expires
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "INSERT OR REPLACE INTO login_failures(email, failures, last_failure_at, locked_until)
+                    VALUES(?1, 0, ?2, ?3)",
+                    params![email, to_sqlite_date_time(&now), locked_until],
+                )
+                .map_err(sqlite_error)?;
+
+            self.record_audit(
+                SYSTEM_ACTOR,
+                "user.lock",
+                "user",
+                email,
+                None,
+                None,
+                Some(&json!({ "locked_until": locked_until })),
             )
-            VALUES(?1, ?2)",
-            params![budget_id, email],
-        );
-
-        match res {
-            Ok(_) => Ok(()),
-            Err(error) => match error {
-                SqliteFailure(error, desc) => Err(Error::SqliteError(error, desc)),
-                _ => Err(Error::UnknownError),
-            },
-        }
+        })
     }
 
-    pub fn delete_can_access_budget(
-        &self,
-        access_token: &str,
-        budget_id: i64,
-        email: &str,
-    ) -> Result<(), Error> {
-        let user = match self.get_user_by_access_token(access_token)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
-        };
-
-        // Get budget
-        let budget = self.get_budget(budget_id)?;
-
-        match budget {
-            Some(budget) => {
-                let owner = match budget.owner {
-                    Some(id) => id,
-                    None => return Err(Error::UnknownError),
-                };
-
-                // Check if user is the budget owner
-                if owner != user.email {
-                    return Err(Error::UserDeniedError);
-                }
+    pub fn clear_login_failures(&self, email: &str) -> Result<(), Error> {
+        self.db_conn
+            .execute("DELETE FROM login_failures WHERE email = ?1", params![email])
+            .map_err(sqlite_error)?;
 
-                // Perform deletion
-                let res = self.db_conn.execute(
-                    "DELETE FROM can_access_budget WHERE budget_id = ?1 AND email = ?2",
-                    params![budget_id, email],
-                );
-                match res {
-                    Ok(_) => Ok(()),
-                    Err(error) => match error {
-                        SqliteFailure(error, desc) => Err(Error::SqliteError(error, desc)),
-                        _ => Err(Error::UnknownError),
-                    },
-                }
-            }
-            None => Err(Error::EntryNotFound),
-        }
+        Ok(())
     }
 
-    pub fn get_budget_transactions(
-        &self,
-        access_token: &str,
-        budget_id: i64,
-    ) -> Result<Vec<Transaction>, Error> {
-        let user = match self.get_user_by_access_token(access_token)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
-        };
-        let budget = match self.get_available_budget(access_token, budget_id)? {
-            Some(x) => x,
-            None => return Err(Error::EntryNotFound),
-        };
+    /// Gets the accounts that are currently locked, only admins may see them
+    pub fn get_locked_accounts(&self, access_token: &str) -> Result<Vec<LockedAccount>, Error> {
+        self.get_admin_user(access_token)?;
 
         let mut stmt = self.db_conn.prepare(
-            "SELECT transaction_id, budget_id, email, name, description, date, amount, recur_days,
-            recur_until FROM transactions WHERE budget_id = ?1 ORDER BY date DESC",
+            "SELECT email, locked_until, last_failure_at FROM login_failures
+            WHERE locked_until > ?1 AND email IN (SELECT email FROM users) ORDER BY locked_until",
         )?;
 
-        let mut result: Vec<Transaction> = Vec::new();
+        let mut result: Vec<LockedAccount> = Vec::new();
 
-        let transaction_iter = stmt.query_map(params![budget_id], |row| {
-            Ok(Transaction {
-                transaction_id: row.get(0)?,
-                budget_id: row.get(1)?,
-                email: row.get(2)?,
-                name: row.get(3)?,
-                description: row.get(4)?,
-                date: row.get(5)?,
-                amount: row.get(6)?,
-                recur_days: row.get(7)?,
-                recur_until: row.get(8)?,
+        let account_iter = stmt.query_map(params![get_current_date_time(&self.default_timezone)], |row| {
+            Ok(LockedAccount {
+                email: row.get(0)?,
+                locked_until: row.get(1)?,
+                last_failure_at: row.get(2)?,
             })
         });
 
-        for transaction in transaction_iter? {
-            result.push(transaction?);
+        for account in account_iter? {
+            result.push(account?);
         }
 
         Ok(result)
     }
 
-    pub fn get_budget_transactions_in_period(
+    /// Lifts the lock of an account and forgets its failed logins
+    pub fn unlock_account(&self, access_token: &str, email: &str) -> Result<(), Error> {
+        let admin = self.get_admin_user(access_token)?;
+
+        self.atomically(|| {
+            let removed = self
+                .db_conn
+                .execute("DELETE FROM login_failures WHERE email = ?1", params![email])
+                .map_err(sqlite_error)?;
+
+            if removed == 0 {
+                return Err(Error::EntryNotFound);
+            }
+
+            self.record_audit(
+                &admin.email,
+                "user.unlock",
+                "user",
+                email,
+                None,
+                None::<&serde_json::Value>,
+                None,
+            )
+        })
+    }
+
+    /// Finds the local user for an identity from the single sign-on provider
+    ///
+    /// When `link_existing_users` is set, an identity that isn't linked yet is
+    /// linked to the user with the same email, as long as both the provider and
+    /// the user have verified it. Otherwise the user has to link it themselves
+    /// while signed in. When there is no such user and `create_users` is set an
+    /// account is made, unless the registration mode wouldn't let them register.
+    pub fn login_with_external_identity(
         &self,
-        access_token: &str,
-        budget_id: i64,
-        period_id: i64,
-    ) -> Result<Vec<Transaction>, Error> {
-        let user = match self.get_user_by_access_token(access_token)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
-        };
-        let budget = match self.get_available_budget(access_token, budget_id)? {
-            Some(x) => x,
-            None => return Err(Error::EntryNotFound),
+        claims: &IdTokenClaims,
+        link_existing_users: bool,
+        create_users: bool,
+        mode: RegistrationMode,
+    ) -> Result<User, Error> {
+        let linked_email: Option<String> = match self.db_conn.query_row(
+            "SELECT email FROM external_identities WHERE issuer = ?1 AND subject = ?2",
+            params![claims.issuer, claims.subject],
+            |row| row.get(0),
+        ) {
+            Ok(x) => Some(x),
+            Err(QueryReturnedNoRows) => None,
+            Err(error) => return Err(sqlite_error(error)),
         };
 
-        // Get period
-        let period = match self.get_budget_period(access_token, budget_id, period_id)? {
-            Some(x) => x,
-            None => return Err(Error::EntryNotFound)
-        };
+        if let Some(email) = linked_email {
+            return match self.get_user_by_email(&email)? {
+                Some(x) => Ok(x),
+                None => Err(Error::NoLinkedAccount),
+            };
+        }
 
-        let mut stmt = self.db_conn.prepare(
-            "SELECT transaction_id, budget_id, email, name, description, date, amount, recur_days,
-            recur_until FROM transactions WHERE budget_id = ?1 AND date(date) >= date(?2) AND date(date) <= date(?3) ORDER BY date DESC",
-        )?;
+        // Unverified emails could be set to anyone's address at the provider
+        let email = match &claims.email {
+            Some(x) if claims.email_verified => x,
+            _ => return Err(Error::NoLinkedAccount),
+        };
 
-        let mut result: Vec<Transaction> = Vec::new();
+        if let Some(user) = self.get_user_by_email(email)? {
+            // Anyone can register an unverified account under someone else's
+            // email, which mustn't capture that person's sign ins
+            if !link_existing_users || !user.email_verified {
+                return Err(Error::NoLinkedAccount);
+            }
 
-        let transaction_iter = stmt.query_map(params![budget_id, period.start_date, period.end_date], |row| {
-            Ok(Transaction {
-                transaction_id: row.get(0)?,
-                budget_id: row.get(1)?,
-                email: row.get(2)?,
-                name: row.get(3)?,
-                description: row.get(4)?,
-                date: row.get(5)?,
-                amount: row.get(6)?,
-                recur_days: row.get(7)?,
-                recur_until: row.get(8)?,
-            })
-        });
+            self.insert_external_identity(claims, &user.email)?;
 
-        for transaction in transaction_iter? {
-            result.push(transaction?);
+            return Ok(user);
         }
 
-        Ok(result)
-    }
-
-    pub fn add_transaction(
-        &self,
-        access_token: &str,
-        transaction: &Transaction,
-    ) -> Result<Transaction, Error> {
-        let user = match self.get_user_by_access_token(access_token)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
-        };
+        if !create_users {
+            return Err(Error::NoLinkedAccount);
+        }
 
-        let date = match &transaction.date {
-            Some(x) => x.clone(),
-            None => get_current_date_time(),
+        let approved = match mode {
+            RegistrationMode::Open => true,
+            RegistrationMode::AdminApproval => false,
+            RegistrationMode::Disabled | RegistrationMode::InviteOnly => return Err(Error::NoLinkedAccount),
         };
 
-        // TODO: verify that the current user has access to this budget
-
-        let res = self.db_conn.execute(
-            "INSERT INTO transactions(
-                budget_id, email, name, description, date, amount, recur_days, recur_until
-            )
-            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-            params![
-                transaction.budget_id,
-                user.email,
-                transaction.name,
-                transaction.description,
-                date,
-                transaction.amount,
-                transaction.recur_days,
-                transaction.recur_until
-            ],
+        // The password is never given out, the user can set one with a password reset
+        let mut user = User::new(
+            self,
+            email,
+            &claims.given_name.clone().unwrap_or_default(),
+            &claims.family_name.clone().unwrap_or_default(),
+            &generate_token(32),
+            false,
         );
+        user.email_verified = true;
+        user.approved = approved;
 
-        match res {
-            Ok(_) => {
-                let transaction_id = self.db_conn.last_insert_rowid();
-                Ok(Transaction {
-                    transaction_id: Some(transaction_id),
-                    budget_id: transaction.budget_id,
-                    email: Some(user.email.clone()),
-                    name: transaction.name.clone(),
-                    description: transaction.description.clone(),
-                    date: transaction.date.clone(),
-                    amount: transaction.amount,
-                    recur_days: transaction.recur_days,
-                    recur_until: transaction.recur_until.clone(),
-                })
-            }
-            Err(error) => match error {
-                SqliteFailure(error, desc) => Err(Error::SqliteError(error, desc)),
-                _ => Err(Error::UnknownError),
-            },
-        }
+        self.atomically(|| {
+            self.insert_user(&user)?;
+            self.insert_external_identity(claims, &user.email)
+        })?;
+
+        Ok(user)
     }
 
-    pub fn get_budget_periods(
-        &self,
-        access_token: &str,
-        budget_id: i64,
-    ) -> Result<Vec<BudgetPeriod>, Error> {
+    /// Links an identity from the single sign-on provider to the signed in user
+    pub fn link_external_identity(&self, access_token: &str, claims: &IdTokenClaims) -> Result<(), Error> {
         let user = match self.get_user_by_access_token(access_token)? {
             Some(x) => x,
             None => return Err(Error::InvalidCredentials),
         };
 
-        let budget = match self.get_available_budget(access_token, budget_id)? {
+        self.insert_external_identity(claims, &user.email)
+    }
+
+    /// Unlinks the user's identity at a single sign-on provider, they then
+    /// have to sign in with their password
+    pub fn unlink_external_identity(&self, access_token: &str, issuer: &str) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
             Some(x) => x,
             None => return Err(Error::InvalidCredentials),
         };
 
-        let mut start_date: DateTime<FixedOffset> = from_sqlite_date(&budget.start_date)?;
+        self.atomically(|| {
+            let removed = self
+                .db_conn
+                .execute(
+                    "DELETE FROM external_identities WHERE email = ?1 AND issuer = ?2",
+                    params![user.email, issuer],
+                )
+                .map_err(sqlite_error)?;
+
+            if removed == 0 {
+                return Err(Error::EntryNotFound);
+            }
 
-        let today: DateTime<FixedOffset> = DateTime::parse_from_rfc2822(&Utc::now().to_rfc2822())?;
-        let mut period_id: i64 = 0;
+            self.record_audit(
+                &user.email,
+                "user.unlink_identity",
+                "user",
+                &user.email,
+                None,
+                None,
+                Some(&json!({ "issuer": issuer })),
+            )
+        })
+    }
 
-        let mut res: Vec<BudgetPeriod> = Vec::new();
-        loop {
-            let end_date = start_date + Duration::days(budget.period_length - 1);
-            let period = BudgetPeriod {
-                period_id,
-                start_date: to_sqlite_date(&start_date),
-                end_date: to_sqlite_date(&end_date),
-            };
+    fn insert_external_identity(&self, claims: &IdTokenClaims, email: &str) -> Result<(), Error> {
+        self.atomically(|| {
+            let res = self.db_conn.execute(
+                "INSERT INTO external_identities(issuer, subject, email, linked_at)
+                VALUES(?1, ?2, ?3, ?4)",
+                params![
+                    claims.issuer,
+                    claims.subject,
+                    email,
+                    get_current_date_time(&self.default_timezone)
+                ],
+            );
+
+            match res {
+                Ok(_) => (),
+                Err(SqliteFailure(ref error, _)) if error.code == rusqlite::ErrorCode::ConstraintViolation => {
+                    return Err(Error::IdentityAlreadyLinked)
+                }
+                Err(error) => return Err(sqlite_error(error)),
+            }
 
-            res.insert(0, period);
+            self.record_audit(
+                email,
+                "user.link_identity",
+                "user",
+                email,
+                None,
+                None,
+                Some(&json!({ "issuer": claims.issuer, "subject": claims.subject })),
+            )
+        })
+    }
 
-            start_date = end_date + Duration::days(1);
+    fn get_two_factor(&self, email: &str) -> Result<Option<TwoFactor>, Error> {
+        let res = self.db_conn.query_row(
+            "SELECT secret, enabled, last_counter FROM two_factor WHERE email = ?1",
+            params![email],
+            |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?)),
+        );
 
-            if start_date > today {
-                break;
-            }
+        let (secret, enabled, last_counter) = match res {
+            Ok(x) => x,
+            Err(QueryReturnedNoRows) => return Ok(None),
+            Err(error) => return Err(sqlite_error(error)),
+        };
 
-            period_id += 1;
+        match decrypt_secret(&self.secret, &secret) {
+            Some(secret) => Ok(Some(TwoFactor {
+                secret,
+                enabled,
+                last_counter,
+            })),
+            None => Err(Error::CorruptTwoFactorSecret),
         }
+    }
 
-        Ok(res)
+    /// Whether the user needs a code as well as their password to sign in
+    pub fn has_two_factor(&self, email: &str) -> Result<bool, Error> {
+        Ok(self.get_two_factor(email)?.map_or(false, |x| x.enabled))
     }
 
-    pub fn get_budget_period(
-        &self,
-        access_token: &str,
-        budget_id: i64,
-        period_id: i64
-    ) -> Result<Option<BudgetPeriod>, Error> {
+    /// Creates a new two-factor secret for the user
+    ///
+    /// Codes aren't required until the secret is confirmed with
+    /// `confirm_two_factor`. Enrolling again replaces an unconfirmed secret.
+    pub fn enroll_two_factor(&self, access_token: &str) -> Result<TwoFactorEnrollment, Error> {
         let user = match self.get_user_by_access_token(access_token)? {
             Some(x) => x,
             None => return Err(Error::InvalidCredentials),
         };
 
-        let budget = match self.get_available_budget(access_token, budget_id)? {
-            Some(x) => x,
-            None => return Err(Error::InvalidCredentials),
-        };
+        if self.has_two_factor(&user.email)? {
+            return Err(Error::TwoFactorAlreadyEnabled);
+        }
 
-        let mut start_date: DateTime<FixedOffset> = from_sqlite_date(&budget.start_date)?;
+        let secret = generate_secret();
 
-        start_date = start_date + Duration::days(budget.period_length * period_id);
-        let end_date = start_date + Duration::days(budget.period_length -1);
+        self.db_conn
+            .execute(
+                "INSERT OR REPLACE INTO two_factor(email, secret, enabled, last_counter)
+                VALUES(?1, ?2, FALSE, NULL)",
+                params![user.email, encrypt_secret(&self.secret, &secret)],
+            )
+            .map_err(sqlite_error)?;
 
-        if start_date < from_sqlite_date(&budget.start_date)? {
-            Ok(None)
-        } else {
-            Ok(Some(BudgetPeriod {
-                period_id,
-                start_date: to_sqlite_date(&start_date),
-                end_date: to_sqlite_date(&end_date)
-            }))
-        }
+        Ok(TwoFactorEnrollment {
+            secret: base32_encode(&secret),
+            otpauth_uri: otpauth_uri(TOTP_ISSUER, &user.email, &secret),
+        })
     }
 
-    pub fn get_budget_period_amount_spent(
-        &self,
-        access_token: &str,
-        budget_id: i64,
-        period_id: i64
-    ) -> Result<f64, Error> {
+    /// Turns on two-factor authentication once the user proves their app
+    /// works with a code from it, returning their recovery codes
+    pub fn confirm_two_factor(&self, access_token: &str, code: &str) -> Result<Vec<String>, Error> {
         let user = match self.get_user_by_access_token(access_token)? {
             Some(x) => x,
             None => return Err(Error::InvalidCredentials),
         };
 
-        let budget = match self.get_available_budget(access_token, budget_id)? {
+        let two_factor = match self.get_two_factor(&user.email)? {
+            Some(x) => x,
+            None => return Err(Error::TwoFactorNotEnabled),
+        };
+
+        if two_factor.enabled {
+            return Err(Error::TwoFactorAlreadyEnabled);
+        }
+
+        let now = get_now(&self.default_timezone).timestamp();
+
+        let counter = match verify(&two_factor.secret, code, now, None) {
+            Some(x) => x,
+            None => return Err(Error::InvalidTwoFactorCode),
+        };
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE two_factor SET enabled = TRUE, last_counter = ?2 WHERE email = ?1",
+                    params![user.email, counter],
+                )
+                .map_err(sqlite_error)?;
+
+            let recovery_codes = self.replace_recovery_codes(&user.email)?;
+
+            self.record_audit(
+                &user.email,
+                "user.enable_two_factor",
+                "user",
+                &user.email,
+                None,
+                None::<&serde_json::Value>,
+                None,
+            )?;
+
+            Ok(recovery_codes)
+        })
+    }
+
+    /// Turns off two-factor authentication, needs a current code or a recovery code
+    pub fn disable_two_factor(&self, access_token: &str, code: &str) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
             Some(x) => x,
             None => return Err(Error::InvalidCredentials),
         };
 
-        let mut stmt = self.db_conn.prepare(
-            "SELECT SUM(amount), substr(date, 0, 11) FROM transactions WHERE
-            budget_id = ?1 AND
-            date >= ?2 AND date <= ?3"
-        )?;
+        self.atomically(|| {
+            self.check_two_factor_code(&user.email, code)?;
+            self.remove_two_factor(&user.email, &user.email)
+        })
+    }
+
+    /// Turns off two-factor authentication for a user who has lost their
+    /// device and recovery codes, for use by server operators only
+    pub fn remove_two_factor(&self, actor: &str, email: &str) -> Result<(), Error> {
+        self.atomically(|| {
+            self.db_conn
+                .execute("DELETE FROM recovery_codes WHERE email = ?1", params![email])
+                .map_err(sqlite_error)?;
+
+            let removed = self
+                .db_conn
+                .execute("DELETE FROM two_factor WHERE email = ?1", params![email])
+                .map_err(sqlite_error)?;
+
+            if removed == 0 {
+                return Err(Error::TwoFactorNotEnabled);
+            }
+
+            self.record_audit(
+                actor,
+                "user.disable_two_factor",
+                "user",
+                email,
+                None,
+                None::<&serde_json::Value>,
+                None,
+            )
+        })
+    }
 
-        let period = match self.get_budget_period(access_token, budget_id, period_id)? {
+    /// Replaces the recovery codes of the user, needs a current code or a
+    /// recovery code
+    pub fn regenerate_recovery_codes(&self, access_token: &str, code: &str) -> Result<Vec<String>, Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
             Some(x) => x,
-            None => return Err(Error::EntryNotFound),
+            None => return Err(Error::InvalidCredentials),
         };
 
-        match stmt.query_row(params![budget_id, period.start_date, period.end_date], |row| {
-            let amount: f64 = row.get(0)?;
-            Ok(amount)
-        }) {
-            Ok(amount) => Ok(amount),
-            Err(_) => Ok(0.0)
+        self.atomically(|| {
+            self.check_two_factor_code(&user.email, code)?;
+            self.replace_recovery_codes(&user.email)
+        })
+    }
+
+    fn replace_recovery_codes(&self, email: &str) -> Result<Vec<String>, Error> {
+        self.db_conn
+            .execute("DELETE FROM recovery_codes WHERE email = ?1", params![email])
+            .map_err(sqlite_error)?;
+
+        let mut recovery_codes: Vec<String> = Vec::new();
+
+        for _ in 0..RECOVERY_CODE_COUNT {
+            let recovery_code = generate_recovery_code();
+
+            self.db_conn
+                .execute(
+                    "INSERT INTO recovery_codes(code_hash, email) VALUES(?1, ?2)",
+                    params![self.hash(&recovery_code), email],
+                )
+                .map_err(sqlite_error)?;
+
+            recovery_codes.push(recovery_code);
         }
+
+        Ok(recovery_codes)
     }
 
-    pub fn get_current_budget_period(
+    /// Accepts either a code from the user's authenticator app or one of their
+    /// unused recovery codes, which is then used up
+    fn check_two_factor_code(&self, email: &str, code: &str) -> Result<(), Error> {
+        let two_factor = match self.get_two_factor(email)? {
+            Some(x) => {
+                if x.enabled {
+                    x
+                } else {
+                    return Err(Error::TwoFactorNotEnabled);
+                }
+            }
+            None => return Err(Error::TwoFactorNotEnabled),
+        };
+
+        let now = get_now(&self.default_timezone);
+
+        if let Some(counter) = verify(&two_factor.secret, code, now.timestamp(), two_factor.last_counter) {
+            self.db_conn
+                .execute(
+                    "UPDATE two_factor SET last_counter = ?2 WHERE email = ?1",
+                    params![email, counter],
+                )
+                .map_err(sqlite_error)?;
+
+            return Ok(());
+        }
+
+        let used = self
+            .db_conn
+            .execute(
+                "UPDATE recovery_codes SET used_at = ?3
+                WHERE code_hash = ?1 AND email = ?2 AND used_at IS NULL",
+                params![
+                    self.hash(&code.trim().to_lowercase()),
+                    email,
+                    to_sqlite_date_time(&now.naive_local())
+                ],
+            )
+            .map_err(sqlite_error)?;
+
+        if used == 0 {
+            return Err(Error::InvalidTwoFactorCode);
+        }
+
+        Ok(())
+    }
+
+    /// Issues the token exchanged for an access token by `finish_two_factor_login`
+    /// once the user has given their password, `None` when the user doesn't
+    /// have two-factor authentication
+    pub fn start_two_factor_login(&self, email: &str) -> Result<Option<String>, Error> {
+        if !self.has_two_factor(email)? {
+            return Ok(None);
+        }
+
+        let lifetime = Duration::minutes(TWO_FACTOR_LOGIN_MINUTES);
+
+        Ok(Some(self.create_user_token(email, TokenPurpose::TwoFactorLogin, lifetime)?))
+    }
+
+    /// Exchanges a token from `start_two_factor_login` and a code for the
+    /// user's access token
+    ///
+    /// Typos can be retried, but wrong codes count as failed logins of the
+    /// account, see `record_login_failure`, and the token stops working after
+    /// `TWO_FACTOR_ATTEMPTS` of them. Failed logins of the account are only
+    /// cleared here, once both factors have been checked.
+    pub fn finish_two_factor_login(
+        &self,
+        token: &str,
+        code: &str,
+        lockout_attempts: u32,
+        lockout: Duration,
+    ) -> Result<String, Error> {
+        let token_hash = self.hash(&token.to_string());
+
+        let email: String = match self.db_conn.query_row(
+            "SELECT email FROM user_tokens
+            WHERE token_hash = ?1 AND purpose = ?2 AND used_at IS NULL AND expires_at > ?3",
+            params![
+                token_hash,
+                TokenPurpose::TwoFactorLogin,
+                get_current_date_time(&self.default_timezone)
+            ],
+            |row| row.get(0),
+        ) {
+            Ok(x) => x,
+            Err(QueryReturnedNoRows) => return Err(Error::InvalidToken),
+            Err(error) => return Err(sqlite_error(error)),
+        };
+
+        // Locked accounts can't keep guessing codes either
+        self.check_login_allowed(&email)?;
+
+        let res = self.atomically(|| {
+            let user = self.consume_user_token(token, TokenPurpose::TwoFactorLogin)?;
+
+            self.check_two_factor_code(&user.email, code)?;
+            self.clear_login_failures(&user.email)?;
+
+            Ok(user.access_token)
+        });
+
+        if let Err(Error::InvalidTwoFactorCode) = res {
+            self.atomically(|| {
+                self.record_login_failure(&email, lockout_attempts, lockout)?;
+
+                self.db_conn
+                    .execute(
+                        "UPDATE user_tokens SET attempts = attempts + 1,
+                        used_at = CASE WHEN attempts + 1 >= ?2 THEN ?3 ELSE used_at END
+                        WHERE token_hash = ?1",
+                        params![
+                            token_hash,
+                            TWO_FACTOR_ATTEMPTS,
+                            get_current_date_time(&self.default_timezone)
+                        ],
+                    )
+                    .map_err(sqlite_error)?;
+
+                Ok(())
+            })?;
+        }
+
+        res
+    }
+
+    /// Gets the user, failing unless they have verified their email
+    ///
+    /// Unverified users can sign in and look around but can't create or share
+    /// anything until they verify. API keys are accepted as for `get_caller`.
+    fn get_verified_user(&self, access_token: &str, scope: Option<ApiKeyScope>) -> Result<User, Error> {
+        match self.get_caller(access_token, scope)? {
+            Some(x) => {
+                if x.user.email_verified {
+                    Ok(x.user)
+                } else {
+                    Err(Error::EmailNotVerified)
+                }
+            }
+            None => Err(Error::InvalidCredentials),
+        }
+    }
+
+    /// Gets who a session access token or API key belongs to
+    ///
+    /// API keys are only accepted when `scope` is given and the key's scope
+    /// covers it, so anything that doesn't ask for a scope stays limited to
+    /// signed in users. Each use of a key is recorded.
+    fn get_caller(&self, access_token: &str, scope: Option<ApiKeyScope>) -> Result<Option<Caller>, Error> {
+        if !is_api_key(access_token) {
+            return Ok(self
+                .get_user_by_access_token(access_token)?
+                .map(|user| Caller { user, api_key: None }));
+        }
+
+        let key_hash = self.hash(&access_token.to_string());
+
+        let res = self.db_conn.query_row(
+            &format!(
+                "SELECT email, {} FROM api_keys WHERE key_hash = ?1 AND revoked_at IS NULL",
+                API_KEY_COLUMNS
+            ),
+            params![key_hash],
+            |row| Ok((row.get::<_, String>(0)?, api_key_from_row(row, 1)?)),
+        );
+
+        let (email, api_key) = match res {
+            Ok(x) => x,
+            Err(QueryReturnedNoRows) => return Ok(None),
+            Err(error) => return Err(sqlite_error(error)),
+        };
+
+        match scope {
+            Some(scope) if api_key.scope.allows(scope) => (),
+            _ => return Err(Error::ApiKeyScopeDenied),
+        }
+
+        let user = match self.get_user_by_email(&email)? {
+            Some(x) if x.approved => x,
+            _ => return Ok(None),
+        };
+
+        self.db_conn
+            .execute(
+                "UPDATE api_keys SET last_used_at = ?2 WHERE key_hash = ?1",
+                params![key_hash, get_current_date_time(&self.default_timezone)],
+            )
+            .map_err(sqlite_error)?;
+
+        Ok(Some(Caller {
+            user,
+            api_key: Some(api_key),
+        }))
+    }
+
+    /// Creates an API key for the signed in user
+    ///
+    /// Keys limited to budgets can only name budgets the user can access.
+    /// Keys can't be used to create more keys.
+    pub fn create_api_key(
         &self,
         access_token: &str,
-        budget_id: i64
-    ) -> Result<BudgetPeriod, Error> {
+        name: &str,
+        scope: ApiKeyScope,
+        budget_ids: Option<Vec<i64>>,
+    ) -> Result<NewApiKey, Error> {
         let user = match self.get_user_by_access_token(access_token)? {
             Some(x) => x,
             None => return Err(Error::InvalidCredentials),
         };
 
-        let budget = match self.get_available_budget(access_token, budget_id)? {
+        if let Some(budget_ids) = &budget_ids {
+            for budget_id in budget_ids {
+                if self.get_available_budget(access_token, *budget_id)?.is_none() {
+                    return Err(Error::EntryNotFound);
+                }
+            }
+        }
+
+        let key = generate_api_key();
+
+        let mut api_key = ApiKey {
+            api_key_id: None,
+            name: name.to_string(),
+            prefix: displayed_prefix(&key),
+            scope,
+            budget_ids,
+            created_at: get_current_date_time(&self.default_timezone),
+            last_used_at: None,
+            revoked_at: None,
+        };
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "INSERT INTO api_keys(email, name, key_hash, prefix, scope, budget_ids, created_at)
+                    VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        user.email,
+                        api_key.name,
+                        self.hash(&key),
+                        api_key.prefix,
+                        api_key.scope,
+                        api_key.budget_ids.as_ref().map(|x| json!(x).to_string()),
+                        api_key.created_at
+                    ],
+                )
+                .map_err(sqlite_error)?;
+
+            let api_key_id = self.db_conn.last_insert_rowid();
+            api_key.api_key_id = Some(api_key_id);
+
+            self.record_audit(
+                &user.email,
+                "api_key.create",
+                "api_key",
+                &api_key_id.to_string(),
+                None,
+                None,
+                Some(&api_key),
+            )
+        })?;
+
+        Ok(NewApiKey { key, api_key })
+    }
+
+    /// Gets the API keys of the signed in user, including revoked ones
+    pub fn get_api_keys(&self, access_token: &str) -> Result<Vec<ApiKey>, Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
             Some(x) => x,
             None => return Err(Error::InvalidCredentials),
         };
 
-        let start_date: DateTime<FixedOffset> = from_sqlite_date(&budget.start_date)?;
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM api_keys WHERE email = ?1 ORDER BY api_key_id",
+            API_KEY_COLUMNS
+        ))?;
 
-        let today: DateTime<FixedOffset> = get_now();
+        let mut result: Vec<ApiKey> = Vec::new();
 
-        let duration = today.signed_duration_since(start_date);
+        let api_key_iter = stmt.query_map(params![user.email], |row| api_key_from_row(row, 0));
 
-        let period_id = duration.num_days() / budget.period_length;
+        for api_key in api_key_iter? {
+            result.push(api_key?);
+        }
 
-        let start_date = start_date + Duration::days(period_id * budget.period_length);
-        let end_date = start_date + Duration::days(budget.period_length - 1);
+        Ok(result)
+    }
 
-        Ok(BudgetPeriod {
-            period_id,
-            start_date: to_sqlite_date(&start_date),
-            end_date: to_sqlite_date(&end_date)
+    /// Stops an API key of the signed in user from working
+    pub fn revoke_api_key(&self, access_token: &str, api_key_id: i64) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let before = match self
+            .get_api_keys(access_token)?
+            .into_iter()
+            .find(|x| x.api_key_id == Some(api_key_id) && x.revoked_at.is_none())
+        {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        let revoked_at = get_current_date_time(&self.default_timezone);
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE api_keys SET revoked_at = ?2 WHERE api_key_id = ?1",
+                    params![api_key_id, revoked_at],
+                )
+                .map_err(sqlite_error)?;
+
+            let after = ApiKey {
+                revoked_at: Some(revoked_at.clone()),
+                ..before.clone()
+            };
+
+            self.record_audit(
+                &user.email,
+                "api_key.revoke",
+                "api_key",
+                &api_key_id.to_string(),
+                None,
+                Some(&before),
+                Some(&after),
+            )
         })
     }
-}
 
-fn rollback(path: &str, error: Error) {
-    // Do rollback
-    println!("Error occurred while setting up database, rolling back changes...");
-    fs::remove_file(Path::new(path)).unwrap();
+    /// Gets the user, failing unless they are an admin
+    fn get_admin_user(&self, access_token: &str) -> Result<User, Error> {
+        match self.get_user_by_access_token(access_token)? {
+            Some(x) => {
+                if x.is_admin {
+                    Ok(x)
+                } else {
+                    Err(Error::UserDeniedError)
+                }
+            }
+            None => Err(Error::InvalidCredentials),
+        }
+    }
 
-    panic!("Database setup failed:\n{:#?}", error);
-}
+    /// Issues an email verification token valid for `lifetime`, `None` when the
+    /// user has already verified their email
+    pub fn request_email_verification(&self, email: &str, lifetime: Duration) -> Result<Option<String>, Error> {
+        let user = match self.get_user_by_email(email)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
 
-// --- Database Types ---
+        if user.email_verified {
+            return Ok(None);
+        }
 
-// --- User Type ---
-pub struct User {
-    pub email: String,
-    pub first_name: String,
-    pub last_name: String,
-    pub password: String,
-    pub access_token: String,
-    pub is_admin: bool,
-}
+        Ok(Some(self.create_user_token(email, TokenPurpose::EmailVerification, lifetime)?))
+    }
 
-impl User {
-    /// Constructs a new `User`
-    ///
-    /// Note: The password field should be plaintext (it is hashed here)
-    pub fn new(
-        database: &Database,
-        email: &String,
-        first_name: &String,
-        last_name: &String,
-        password: &String,
-        is_admin: bool,
-    ) -> User {
-        let hpassword = database.hash(password);
+    /// Marks the email of the user a verification token was issued to as verified
+    pub fn verify_email(&self, token: &str) -> Result<(), Error> {
+        self.atomically(|| {
+            let user = self.consume_user_token(token, TokenPurpose::EmailVerification)?;
+
+            self.revoke_user_tokens(&user.email, TokenPurpose::EmailVerification)?;
+
+            self.db_conn
+                .execute(
+                    "UPDATE users SET email_verified = TRUE WHERE email = ?1",
+                    params![user.email],
+                )
+                .map_err(sqlite_error)?;
+
+            let mut after = user.profile();
+            after["email_verified"] = json!(true);
+
+            self.record_audit(
+                &user.email,
+                "user.verify_email",
+                "user",
+                &user.email,
+                None,
+                Some(&user.profile()),
+                Some(&after),
+            )
+        })
+    }
 
-        let access_token = User::generate_access_token(database, email, &hpassword);
+    /// Sets the timezone of the user, `None` falls back to the server default
+    pub fn set_user_timezone(&self, access_token: &str, timezone: Option<&str>) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
 
-        User {
-            email: email.clone(),
-            first_name: first_name.clone(),
-            last_name: last_name.clone(),
-            password: hpassword,
-            access_token,
-            is_admin,
+        if let Some(timezone) = timezone {
+            if parse_timezone(timezone).is_none() {
+                return Err(Error::InvalidTimezone);
+            }
         }
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE users SET timezone = ?1 WHERE email = ?2",
+                    params![timezone, user.email],
+                )
+                .map_err(sqlite_error)?;
+
+            self.record_audit(
+                &user.email,
+                "user.set_timezone",
+                "user",
+                &user.email,
+                None,
+                Some(&json!({ "timezone": user.timezone })),
+                Some(&json!({ "timezone": timezone })),
+            )
+        })
     }
 
-    fn generate_access_token(database: &Database, email: &String, hpassword: &String) -> String {
-        let mut s = email.clone();
-        s.push_str("::::");
-        s.push_str(hpassword);
+    /// Gets the timezone used for the user
+    pub fn get_user_timezone(&self, access_token: &str) -> Result<Tz, Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
 
-        database.hash(&s)
+        Ok(self.user_timezone(&user))
     }
 
-    pub fn change_password(&mut self, database: &Database, hpassword: &String) {
-        self.password = String::from(hpassword);
-        self.access_token = User::generate_access_token(database, &self.email, &self.password);
+    fn user_timezone(&self, user: &User) -> Tz {
+        user.timezone
+            .as_ref()
+            .and_then(|x| parse_timezone(x))
+            .unwrap_or(self.default_timezone)
+    }
+
+    /// Gets the timezone used for the budget's transactions and periods
+    ///
+    /// Falls back to the owner's timezone and then to the server default
+    fn budget_timezone(&self, budget: &Budget) -> Result<Tz, Error> {
+        if let Some(tz) = budget.timezone.as_ref().and_then(|x| parse_timezone(x)) {
+            return Ok(tz);
+        }
+
+        let owner = match &budget.owner {
+            Some(owner) => self.get_user_by_email(owner)?,
+            None => None,
+        };
+
+        Ok(match owner {
+            Some(owner) => self.user_timezone(&owner),
+            None => self.default_timezone,
+        })
+    }
+
+    /// Gathers everything tied to the user's account into a single archive
+    pub fn export_account(&self, access_token: &str) -> Result<AccountArchive, Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let mut owned: Vec<Budget> = Vec::new();
+
+        {
+            let mut stmt = self.db_conn.prepare(&format!(
+                "SELECT {} FROM budgets WHERE owner = ?1 ORDER BY budget_id",
+                BUDGET_COLUMNS
+            ))?;
+
+            for budget in stmt.query_map(params![user.email], budget_from_row)? {
+                owned.push(budget?);
+            }
+        }
+
+        let mut budgets: Vec<ArchivedBudget> = Vec::new();
+
+        for budget in owned {
+            let mut grants: Vec<String> = Vec::new();
+            let mut transactions: Vec<Transaction> = Vec::new();
+
+            let mut stmt = self
+                .db_conn
+                .prepare("SELECT email FROM can_access_budget WHERE budget_id = ?1 ORDER BY email")?;
+
+            for grant in stmt.query_map(params![budget.budget_id], |row| row.get(0))? {
+                grants.push(grant?);
+            }
+
+            let mut stmt = self.db_conn.prepare(&format!(
+                "SELECT {} FROM transactions WHERE budget_id = ?1 ORDER BY transaction_id",
+                TRANSACTION_COLUMNS
+            ))?;
+
+            for transaction in stmt.query_map(params![budget.budget_id], transaction_from_row)? {
+                transactions.push(transaction?);
+            }
+
+            budgets.push(ArchivedBudget {
+                budget,
+                grants,
+                transactions,
+            });
+        }
+
+        let mut shared_budgets: Vec<SharedBudget> = Vec::new();
+
+        let mut stmt = self.db_conn.prepare(
+            "SELECT b.budget_id, b.name, b.owner FROM budgets b
+            JOIN can_access_budget c ON c.budget_id = b.budget_id
+            WHERE c.email = ?1 ORDER BY b.budget_id",
+        )?;
+
+        let shared_iter = stmt.query_map(params![user.email], |row| {
+            Ok(SharedBudget {
+                budget_id: row.get(0)?,
+                name: row.get(1)?,
+                owner: row.get(2)?,
+            })
+        });
+
+        for shared in shared_iter? {
+            shared_budgets.push(shared?);
+        }
+
+        let mut shared_transactions: Vec<Transaction> = Vec::new();
+
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM transactions WHERE email = ?1
+            AND budget_id NOT IN (SELECT budget_id FROM budgets WHERE owner = ?1)
+            ORDER BY transaction_id",
+            TRANSACTION_COLUMNS
+        ))?;
+
+        for transaction in stmt.query_map(params![user.email], transaction_from_row)? {
+            shared_transactions.push(transaction?);
+        }
+
+        let mut activity: Vec<AuditEntry> = Vec::new();
+
+        let mut stmt = self.db_conn.prepare(
+            "SELECT audit_id, actor, action, target_type, target_id, budget_id, before, after, date
+            FROM audit_log WHERE actor = ?1 ORDER BY audit_id",
+        )?;
+
+        for entry in stmt.query_map(params![user.email], audit_entry_from_row)? {
+            activity.push(entry?);
+        }
+
+        self.record_audit(
+            &user.email,
+            "user.export",
+            "user",
+            &user.email,
+            None,
+            None,
+            Some(&user.profile()),
+        )?;
+
+        Ok(AccountArchive {
+            version: ACCOUNT_ARCHIVE_VERSION,
+            exported_at: get_current_date_time(&self.user_timezone(&user)),
+            user: ArchivedUser {
+                email: user.email.clone(),
+                first_name: user.first_name.clone(),
+                last_name: user.last_name.clone(),
+                is_admin: user.is_admin,
+                timezone: user.timezone.clone(),
+            },
+            budgets,
+            shared_budgets,
+            shared_transactions,
+            activity,
+        })
+    }
+
+    /// Creates the account stored in an archive from `export_account`, giving
+    /// its budgets and transactions new IDs
+    ///
+    /// Only admins may import accounts. The imported user gets a random
+    /// temporary password and is never an admin, whatever the archive says.
+    pub fn import_account(&self, access_token: &str, archive: &AccountArchive) -> Result<AccountImport, Error> {
+        let admin = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        if !admin.is_admin {
+            return Err(Error::UserDeniedError);
+        }
+
+        if archive.version != ACCOUNT_ARCHIVE_VERSION {
+            return Err(Error::UnsupportedArchiveVersion);
+        }
+
+        if self.get_user_by_email(&archive.user.email)?.is_some() {
+            return Err(Error::UserAlreadyExists);
+        }
+
+        if let Some(timezone) = &archive.user.timezone {
+            if parse_timezone(timezone).is_none() {
+                return Err(Error::InvalidTimezone);
+            }
+        }
+
+        let temporary_password = generate_token(16);
+
+        let mut user = User::new(
+            self,
+            &archive.user.email,
+            &archive.user.first_name,
+            &archive.user.last_name,
+            &temporary_password,
+            false,
+        );
+        user.timezone = archive.user.timezone.clone();
+        user.email_verified = true;
+
+        let mut budget_ids: Vec<IdMapping> = Vec::new();
+        let mut transaction_ids: Vec<IdMapping> = Vec::new();
+        let mut skipped_grants: Vec<String> = Vec::new();
+        let mut reassigned_transactions = 0;
+
+        self.atomically(|| {
+            self.insert_user_row(&user)?;
+
+            for archived in &archive.budgets {
+                let budget = &archived.budget;
+
+                if from_sqlite_date(&budget.start_date).is_err() {
+                    return Err(Error::InvalidDate);
+                }
+
+                if !budget.period_rule.is_valid() {
+                    return Err(Error::InvalidPeriodRule);
+                }
+
+                if !budget.rollover_policy.is_valid() {
+                    return Err(Error::InvalidRolloverPolicy);
+                }
+
+                self.db_conn
+                    .execute(
+                        "INSERT INTO budgets(
+                            owner, name, spend_limit, period_length, start_date, archived, timezone,
+                            period_rule, rollover_policy
+                        )
+                        VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                        params![
+                            user.email,
+                            budget.name,
+                            budget.spend_limit,
+                            budget.period_rule.approximate_days(),
+                            budget.start_date,
+                            budget.archived,
+                            budget.timezone,
+                            json!(budget.period_rule).to_string(),
+                            json!(budget.rollover_policy).to_string()
+                        ],
+                    )
+                    .map_err(sqlite_error)?;
+
+                let budget_id = self.db_conn.last_insert_rowid();
+
+                if let Some(old_id) = budget.budget_id {
+                    budget_ids.push(IdMapping { old_id, new_id: budget_id });
+                }
+
+                for email in &archived.grants {
+                    if *email == user.email || self.get_user_by_email(email)?.is_none() {
+                        skipped_grants.push(email.clone());
+                        continue;
+                    }
+
+                    self.db_conn
+                        .execute(
+                            "INSERT INTO can_access_budget(budget_id, email) VALUES(?1, ?2)",
+                            params![budget_id, email],
+                        )
+                        .map_err(sqlite_error)?;
+                }
+
+                for transaction in &archived.transactions {
+                    let date = match transaction.date.as_ref().map(|x| from_sqlite_date_time(x)) {
+                        Some(Ok(date)) => to_sqlite_date_time(&date),
+                        _ => return Err(Error::InvalidDate),
+                    };
+
+                    // Authors must exist on this server
+                    let author = match &transaction.email {
+                        Some(email) if self.get_user_by_email(email)?.is_some() => email.clone(),
+                        _ => {
+                            reassigned_transactions += 1;
+                            user.email.clone()
+                        }
+                    };
+
+                    self.db_conn
+                        .execute(
+                            "INSERT INTO transactions(
+                                budget_id, email, name, description, date, amount, recur_days,
+                                recur_until, deleted_at, kind, external_id
+                            )
+                            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                            params![
+                                budget_id,
+                                author,
+                                transaction.name,
+                                transaction.description,
+                                date,
+                                transaction.amount,
+                                transaction.recur_days.unwrap_or(0),
+                                transaction.recur_until,
+                                transaction.deleted_at,
+                                transaction.kind,
+                                transaction.external_id
+                            ],
+                        )
+                        .map_err(sqlite_error)?;
+
+                    if let Some(old_id) = transaction.transaction_id {
+                        transaction_ids.push(IdMapping {
+                            old_id,
+                            new_id: self.db_conn.last_insert_rowid(),
+                        });
+                    }
+                }
+            }
+
+            self.record_audit(
+                &admin.email,
+                "user.import",
+                "user",
+                &user.email,
+                None,
+                None,
+                Some(&user.profile()),
+            )
+        })?;
+
+        Ok(AccountImport {
+            email: user.email,
+            temporary_password,
+            budget_ids,
+            transaction_ids,
+            skipped_grants,
+            reassigned_transactions,
+            skipped_shared_transactions: archive.shared_transactions.len() as i64,
+        })
+    }
+
+    /// Gets every budget the user owns or has been given access to
+    ///
+    /// Archived budgets are only included when `include_archived` is set
+    pub fn get_available_budgets(
+        &self,
+        access_token: &str,
+        include_archived: bool,
+    ) -> Result<Vec<Budget>, Error> {
+        let caller = match self.get_caller(access_token, Some(ApiKeyScope::ReadOnly))? {
+            Some(x) => x,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM budgets WHERE (?2 OR NOT archived) AND budget_id in (
+            SELECT budget_id FROM (SELECT budget_id, owner AS email FROM budgets
+            UNION SELECT budget_id, email FROM can_access_budget) WHERE email = ?1)",
+            BUDGET_COLUMNS
+        ))?;
+
+        let mut result: Vec<Budget> = Vec::new();
+
+        let budget_iter = stmt.query_map(params![caller.user.email, include_archived], budget_from_row);
+
+        for budget in budget_iter? {
+            let budget = budget?;
+
+            // Keys limited to some budgets don't see the others
+            if caller.can_access_budget(budget.budget_id.unwrap_or(-1)) {
+                result.push(budget);
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn add_budget(&self, access_token: &str, budget: &Budget) -> Result<Budget, Error> {
+        let user = self.get_verified_user(access_token, None)?;
+
+        if let Some(timezone) = &budget.timezone {
+            if parse_timezone(timezone).is_none() {
+                return Err(Error::InvalidTimezone);
+            }
+        }
+
+        if !budget.period_rule.is_valid() {
+            return Err(Error::InvalidPeriodRule);
+        }
+
+        if !budget.rollover_policy.is_valid() {
+            return Err(Error::InvalidRolloverPolicy);
+        }
+
+        let start_date = match from_sqlite_date(&budget.start_date) {
+            Ok(x) => to_sqlite_date(&x),
+            Err(_) => return Err(Error::InvalidDate),
+        };
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "INSERT INTO budgets(
+                        owner, name, spend_limit, period_length, start_date, timezone, period_rule,
+                        rollover_policy
+                    )
+                    VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        user.email,
+                        budget.name,
+                        budget.spend_limit,
+                        budget.period_rule.approximate_days(),
+                        start_date,
+                        budget.timezone,
+                        json!(budget.period_rule).to_string(),
+                        json!(budget.rollover_policy).to_string()
+                    ],
+                )
+                .map_err(sqlite_error)?;
+
+            let budget_id = self.db_conn.last_insert_rowid();
+            let budget = Budget {
+                budget_id: Some(budget_id),
+                owner: Some(user.email.clone()),
+                name: budget.name.clone(),
+                spend_limit: budget.spend_limit,
+                period_length: budget.period_rule.approximate_days(),
+                start_date: start_date.clone(),
+                archived: false,
+                timezone: budget.timezone.clone(),
+                period_rule: budget.period_rule.clone(),
+                rollover_policy: budget.rollover_policy.clone(),
+            };
+
+            self.record_audit(
+                &user.email,
+                "budget.create",
+                "budget",
+                &budget_id.to_string(),
+                Some(budget_id),
+                None,
+                Some(&budget),
+            )?;
+
+            Ok(budget)
+        })
+    }
+
+    pub fn get_budget(&self, budget_id: i64) -> Result<Option<Budget>, Error> {
+        // Get budget
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM budgets WHERE budget_id = ?1",
+            BUDGET_COLUMNS
+        ))?;
+
+        match stmt.query_row(params![budget_id], budget_from_row) {
+            Ok(budget) => Ok(Some(budget)),
+            Err(error) => match error {
+                QueryReturnedNoRows => Ok(None),
+                _ => Err(Error::UnknownError),
+            },
+        }
+    }
+
+    pub fn get_available_budget(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+    ) -> Result<Option<Budget>, Error> {
+        let caller = match self.get_caller(access_token, Some(ApiKeyScope::ReadOnly))? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if !caller.can_access_budget(budget_id) {
+            return Ok(None);
+        }
+
+        // Get available budget
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM budgets WHERE budget_id = ?1 AND budget_id in (
+            SELECT budget_id FROM (SELECT budget_id, owner AS email FROM budgets
+            UNION SELECT budget_id, email FROM can_access_budget) WHERE email = ?2)",
+            BUDGET_COLUMNS
+        ))?;
+
+        match stmt.query_row(params![budget_id, caller.user.email], budget_from_row) {
+            Ok(budget) => Ok(Some(budget)),
+            Err(error) => match error {
+                QueryReturnedNoRows => Ok(None),
+                _ => Err(Error::UnknownError),
+            },
+        }
+    }
+
+    /// Gets a budget that is owned by `user`
+    fn get_owned_budget(&self, user: &User, budget_id: i64) -> Result<Budget, Error> {
+        let budget = match self.get_budget(budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        match &budget.owner {
+            Some(owner) => {
+                if *owner != user.email {
+                    return Err(Error::UserDeniedError);
+                }
+            }
+            None => return Err(Error::UnknownError),
+        };
+
+        Ok(budget)
+    }
+
+    /// Archives or unarchives a budget
+    ///
+    /// Archived budgets are read-only and hidden from the default budget listing
+    pub fn set_budget_archived(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        archived: bool,
+    ) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let before = self.get_owned_budget(&user, budget_id)?;
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE budgets SET archived = ?1 WHERE budget_id = ?2",
+                    params![archived, budget_id],
+                )
+                .map_err(sqlite_error)?;
+
+            let after = Budget {
+                archived,
+                ..before.clone()
+            };
+
+            self.record_audit(
+                &user.email,
+                if archived { "budget.archive" } else { "budget.unarchive" },
+                "budget",
+                &budget_id.to_string(),
+                Some(budget_id),
+                Some(&before),
+                Some(&after),
+            )
+        })
+    }
+
+    /// Sets the timezone of a budget, `None` falls back to the owner's timezone
+    pub fn set_budget_timezone(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        timezone: Option<&str>,
+    ) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let before = self.get_owned_budget(&user, budget_id)?;
+
+        if before.archived {
+            return Err(Error::BudgetArchived);
+        }
+
+        if let Some(timezone) = timezone {
+            if parse_timezone(timezone).is_none() {
+                return Err(Error::InvalidTimezone);
+            }
+        }
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE budgets SET timezone = ?1 WHERE budget_id = ?2",
+                    params![timezone, budget_id],
+                )
+                .map_err(sqlite_error)?;
+
+            let after = Budget {
+                timezone: timezone.map(String::from),
+                ..before.clone()
+            };
+
+            self.record_audit(
+                &user.email,
+                "budget.set_timezone",
+                "budget",
+                &budget_id.to_string(),
+                Some(budget_id),
+                Some(&before),
+                Some(&after),
+            )
+        })
+    }
+
+    /// Sets how leftover balances of a budget's periods carry into the next period
+    pub fn set_budget_rollover_policy(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        rollover_policy: &RolloverPolicy,
+    ) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let before = self.get_owned_budget(&user, budget_id)?;
+
+        if before.archived {
+            return Err(Error::BudgetArchived);
+        }
+
+        if !rollover_policy.is_valid() {
+            return Err(Error::InvalidRolloverPolicy);
+        }
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE budgets SET rollover_policy = ?1 WHERE budget_id = ?2",
+                    params![json!(rollover_policy).to_string(), budget_id],
+                )
+                .map_err(sqlite_error)?;
+
+            let after = Budget {
+                rollover_policy: rollover_policy.clone(),
+                ..before.clone()
+            };
+
+            self.record_audit(
+                &user.email,
+                "budget.set_rollover_policy",
+                "budget",
+                &budget_id.to_string(),
+                Some(budget_id),
+                Some(&before),
+                Some(&after),
+            )
+        })
+    }
+
+    /// Permanently deletes a budget along with all of its transactions and
+    /// access grants
+    pub fn delete_budget(&self, access_token: &str, budget_id: i64) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let before = self.get_owned_budget(&user, budget_id)?;
+
+        // Perform deletion, dependent rows must go first to satisfy foreign keys
+        self.atomically(|| {
+            self.db_conn.execute(
+                "DELETE FROM transactions WHERE budget_id = ?1",
+                params![budget_id],
+            )?;
+            self.db_conn.execute(
+                "DELETE FROM can_access_budget WHERE budget_id = ?1",
+                params![budget_id],
+            )?;
+            self.db_conn.execute(
+                "DELETE FROM budgets WHERE budget_id = ?1",
+                params![budget_id],
+            )?;
+
+            self.record_audit(
+                &user.email,
+                "budget.delete",
+                "budget",
+                &budget_id.to_string(),
+                Some(budget_id),
+                Some(&before),
+                None,
+            )
+        })
+    }
+
+    pub fn get_available_can_access_budget_users(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+    ) -> Result<Vec<String>, Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let mut stmt = self.db_conn.prepare(
+            "SELECT email FROM users WHERE email IN (SELECT email FROM (SELECT owner AS email FROM budgets WHERE budget_id = ?1
+            UNION SELECT email FROM can_access_budget WHERE budget_id = ?1))"
+        )?;
+
+        let mut is_available = false;
+        let mut result: Vec<String> = Vec::new();
+
+        let email_iter = stmt.query_map(params![budget_id], |row| Ok(row.get(0)?));
+
+        for email in email_iter? {
+            let email = email?;
+
+            if email == user.email {
+                is_available = true;
+            }
+
+            result.push(email);
+        }
+
+        if result.len() == 0 {
+            Err(Error::EntryNotFound)
+        } else if is_available {
+            Ok(result)
+        } else {
+            Err(Error::UserDeniedError)
+        }
+    }
+
+    pub fn add_can_access_budget(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        email: &str,
+    ) -> Result<(), Error> {
+        // Get current user
+        let user = self.get_verified_user(access_token, None)?;
+
+        // Get budget
+        let budget = match self.get_budget(budget_id) {
+            Ok(budget) => match budget {
+                Some(budget) => budget,
+                None => return Err(Error::EntryNotFound),
+            },
+            Err(error) => return Err(error),
+        };
+
+        // Check if the current user is the budget owner
+        match budget.owner {
+            Some(owner) => {
+                if !owner.eq(&user.email) {
+                    return Err(Error::UserDeniedError);
+                }
+            }
+            None => return Err(Error::UnknownError),
+        };
+
+        if budget.archived {
+            return Err(Error::BudgetArchived);
+        }
+
+        // Check if the request is trying to give owner access to their own budget
+        if email.eq(&user.email) {
+            return Err(Error::AccessRecursionError);
+        }
+
+        // Unverified addresses may belong to someone other than who registered them
+        match self.get_user_by_email(email)? {
+            Some(x) => {
+                if !x.email_verified {
+                    return Err(Error::EmailNotVerified);
+                }
+            }
+            None => return Err(Error::EntryNotFound),
+        };
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "INSERT INTO can_access_budget(
+                        budget_id, email
+                    )
+                    VALUES(?1, ?2)",
+                    params![budget_id, email],
+                )
+                .map_err(sqlite_error)?;
+
+            self.record_audit(
+                &user.email,
+                "budget.share",
+                "can_access_budget",
+                email,
+                Some(budget_id),
+                None,
+                Some(&CanAccessBudget::new(budget_id, email.to_string())),
+            )
+        })
+    }
+
+    pub fn delete_can_access_budget(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        email: &str,
+    ) -> Result<(), Error> {
+        let user = match self.get_user_by_access_token(access_token)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        // Get budget
+        let budget = self.get_budget(budget_id)?;
+
+        match budget {
+            Some(budget) => {
+                let owner = match budget.owner {
+                    Some(id) => id,
+                    None => return Err(Error::UnknownError),
+                };
+
+                // Check if user is the budget owner
+                if owner != user.email {
+                    return Err(Error::UserDeniedError);
+                }
+
+                if budget.archived {
+                    return Err(Error::BudgetArchived);
+                }
+
+                // Perform deletion
+                self.atomically(|| {
+                    let count = self
+                        .db_conn
+                        .execute(
+                            "DELETE FROM can_access_budget WHERE budget_id = ?1 AND email = ?2",
+                            params![budget_id, email],
+                        )
+                        .map_err(sqlite_error)?;
+
+                    if count == 0 {
+                        return Ok(());
+                    }
+
+                    self.record_audit(
+                        &user.email,
+                        "budget.unshare",
+                        "can_access_budget",
+                        email,
+                        Some(budget_id),
+                        Some(&CanAccessBudget::new(budget_id, email.to_string())),
+                        None,
+                    )
+                })
+            }
+            None => Err(Error::EntryNotFound),
+        }
+    }
+
+    pub fn get_budget_transactions(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        query: &TransactionQuery,
+    ) -> Result<TransactionPage, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        self.query_transactions(&budget, None, query)
+    }
+
+    /// Gets a page of a budget's live transactions, optionally limited to a period
+    fn query_transactions(
+        &self,
+        budget: &Budget,
+        period: Option<&BudgetPeriod>,
+        query: &TransactionQuery,
+    ) -> Result<TransactionPage, Error> {
+        let now = get_current_date_time(&self.budget_timezone(budget)?);
+
+        let mut conditions: Vec<String> = vec![
+            String::from("budget_id = ?"),
+            String::from("deleted_at IS NULL"),
+            String::from("date <= ?"),
+        ];
+        let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(budget.budget_id), Box::new(now)];
+
+        if let Some(period) = period {
+            let (start, end) = period_bounds(period)?;
+
+            conditions.push(String::from("date >= ? AND date < ?"));
+            values.push(Box::new(start));
+            values.push(Box::new(end));
+        }
+
+        if let Some(date_from) = &query.date_from {
+            let date_from = from_sqlite_date(date_from).map_err(|_| Error::InvalidDate)?;
+
+            conditions.push(String::from("date >= ?"));
+            values.push(Box::new(to_sqlite_date_time(&date_from.and_hms(0, 0, 0))));
+        }
+
+        if let Some(date_to) = &query.date_to {
+            let date_to = from_sqlite_date(date_to).map_err(|_| Error::InvalidDate)?;
+
+            conditions.push(String::from("date < ?"));
+            values.push(Box::new(to_sqlite_date_time(&date_to.succ().and_hms(0, 0, 0))));
+        }
+
+        if let Some(amount_min) = query.amount_min {
+            conditions.push(String::from("amount >= ?"));
+            values.push(Box::new(amount_min));
+        }
+
+        if let Some(amount_max) = query.amount_max {
+            conditions.push(String::from("amount <= ?"));
+            values.push(Box::new(amount_max));
+        }
+
+        if let Some(email) = &query.email {
+            conditions.push(String::from("email = ?"));
+            values.push(Box::new(email.clone()));
+        }
+
+        if let Some(text) = &query.text {
+            let pattern = format!(
+                "%{}%",
+                text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+            );
+
+            conditions.push(String::from(
+                "(name LIKE ? ESCAPE '\\' OR description LIKE ? ESCAPE '\\')",
+            ));
+            values.push(Box::new(pattern.clone()));
+            values.push(Box::new(pattern));
+        }
+
+        // Count every match before the cursor narrows things down to a page
+        let total_count: i64 = self.db_conn.query_row(
+            &format!("SELECT COUNT(*) FROM transactions WHERE {}", conditions.join(" AND ")),
+            values.iter().map(|x| x.as_ref()),
+            |row| row.get(0),
+        )?;
+
+        let sort = query.sort.unwrap_or(TransactionSort::Date);
+        let sort_column = match sort {
+            TransactionSort::Date => "date",
+            TransactionSort::Amount => "amount",
+            TransactionSort::Name => "name",
+        };
+
+        let (order, comparison) = match query.direction.unwrap_or(SortDirection::Desc) {
+            SortDirection::Asc => ("ASC", ">"),
+            SortDirection::Desc => ("DESC", "<"),
+        };
+
+        // Cursors are the ID of the last transaction on the previous page, the
+        // next page continues after that transaction's position in the sort order
+        if let Some(cursor) = &query.cursor {
+            // Transactions from other budgets would leak their sort position
+            let cursor = match cursor.parse::<i64>() {
+                Ok(x) => match self.get_transaction(x)? {
+                    Some(x) if Some(x.budget_id) == budget.budget_id => x,
+                    _ => return Err(Error::InvalidCursor),
+                },
+                Err(_) => return Err(Error::InvalidCursor),
+            };
+
+            conditions.push(format!(
+                "({col} {op} ? OR ({col} = ? AND transaction_id {op} ?))",
+                col = sort_column,
+                op = comparison
+            ));
+
+            for _ in 0..2 {
+                let cursor_value: Box<dyn ToSql> = match sort {
+                    TransactionSort::Date => Box::new(cursor.date.clone()),
+                    TransactionSort::Amount => Box::new(cursor.amount),
+                    TransactionSort::Name => Box::new(cursor.name.clone()),
+                };
+                values.push(cursor_value);
+            }
+            values.push(Box::new(cursor.transaction_id));
+        }
+
+        // Fetch one extra row to find out whether there is another page
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_TRANSACTION_PAGE_SIZE)
+            .max(1)
+            .min(MAX_TRANSACTION_PAGE_SIZE);
+        values.push(Box::new(limit + 1));
+
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM transactions WHERE {}
+            ORDER BY {} {}, transaction_id {} LIMIT ?",
+            TRANSACTION_COLUMNS,
+            conditions.join(" AND "),
+            sort_column,
+            order,
+            order
+        ))?;
+
+        let mut transactions: Vec<Transaction> = Vec::new();
+
+        let transaction_iter = stmt.query_map(values.iter().map(|x| x.as_ref()), transaction_from_row);
+
+        for transaction in transaction_iter? {
+            transactions.push(transaction?);
+        }
+
+        let next_cursor = if transactions.len() as i64 > limit {
+            transactions.truncate(limit as usize);
+            transactions
+                .last()
+                .and_then(|x| x.transaction_id)
+                .map(|x| x.to_string())
+        } else {
+            None
+        };
+
+        Ok(TransactionPage {
+            transactions,
+            total_count,
+            next_cursor,
+        })
+    }
+
+    /// Gets the transactions of a budget that are dated in the future, soonest first
+    ///
+    /// Scheduled transactions are left out of other listings and totals until
+    /// their date arrives
+    pub fn get_budget_scheduled_transactions(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+    ) -> Result<Vec<Transaction>, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        let now = get_current_date_time(&self.budget_timezone(&budget)?);
+
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM transactions WHERE budget_id = ?1 AND deleted_at IS NULL
+            AND date > ?2 ORDER BY date ASC",
+            TRANSACTION_COLUMNS
+        ))?;
+
+        let mut result: Vec<Transaction> = Vec::new();
+
+        let transaction_iter = stmt.query_map(params![budget_id, now], transaction_from_row);
+
+        for transaction in transaction_iter? {
+            result.push(transaction?);
+        }
+
+        Ok(result)
+    }
+
+    /// Gets a budget, and one of its periods when `period_id` is set, ready to export
+    pub fn get_budget_export(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        period_id: Option<i64>,
+    ) -> Result<BudgetExport, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        let period = match period_id {
+            Some(period_id) => match self.budget_period(&budget, period_id)? {
+                Some(x) => Some(x),
+                None => return Err(Error::EntryNotFound),
+            },
+            None => None,
+        };
+
+        Ok(BudgetExport {
+            exported_at: get_current_date_time(&self.budget_timezone(&budget)?),
+            budget,
+            period,
+        })
+    }
+
+    /// Gets the next page of live transactions to export after `after_id`,
+    /// ordered by ID
+    ///
+    /// When `period` is set only the transactions counted in that period are
+    /// included, otherwise every transaction is, even those scheduled for later.
+    pub fn get_export_transactions(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        period: Option<&BudgetPeriod>,
+        after_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<Transaction>, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(budget_id)];
+
+        let condition = match period {
+            Some(period) => {
+                let (start, end) = period_bounds(period)?;
+
+                values.push(Box::new(start));
+                values.push(Box::new(end));
+                values.push(Box::new(get_current_date_time(&self.budget_timezone(&budget)?)));
+
+                IN_PERIOD_CONDITION
+            }
+            None => "budget_id = ?1 AND deleted_at IS NULL",
+        };
+
+        values.push(Box::new(after_id.unwrap_or(0)));
+        values.push(Box::new(limit));
+
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM transactions WHERE {} AND transaction_id > ?{}
+            ORDER BY transaction_id LIMIT ?{}",
+            TRANSACTION_COLUMNS,
+            condition,
+            values.len() - 1,
+            values.len()
+        ))?;
+
+        let mut result: Vec<Transaction> = Vec::new();
+
+        let transaction_iter = stmt.query_map(values.iter().map(|x| x.as_ref()), transaction_from_row);
+
+        for transaction in transaction_iter? {
+            result.push(transaction?);
+        }
+
+        Ok(result)
+    }
+
+    /// Gets the soft-deleted transactions of a budget, most recently deleted first
+    pub fn get_budget_deleted_transactions(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+    ) -> Result<Vec<Transaction>, Error> {
+        if self.get_available_budget(access_token, budget_id)?.is_none() {
+            return Err(Error::EntryNotFound);
+        }
+
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM transactions WHERE budget_id = ?1 AND deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC",
+            TRANSACTION_COLUMNS
+        ))?;
+
+        let mut result: Vec<Transaction> = Vec::new();
+
+        let transaction_iter = stmt.query_map(params![budget_id], transaction_from_row);
+
+        for transaction in transaction_iter? {
+            result.push(transaction?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn get_budget_transactions_in_period(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        period_id: i64,
+        query: &TransactionQuery,
+    ) -> Result<TransactionPage, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        // Get period
+        let period = match self.budget_period(&budget, period_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound)
+        };
+
+        self.query_transactions(&budget, Some(&period), query)
+    }
+
+    /// Searches the names and descriptions of transactions in every budget
+    /// available to the user, best matches first
+    ///
+    /// Each word of `text` must appear in the transaction, words match as prefixes
+    pub fn search_transactions(
+        &self,
+        access_token: &str,
+        text: &str,
+        limit: Option<i64>,
+    ) -> Result<Vec<TransactionSearchHit>, Error> {
+        let budget_ids: Vec<i64> = self
+            .get_available_budgets(access_token, true)?
+            .iter()
+            .filter_map(|x| x.budget_id)
+            .collect();
+
+        // Quote each word so that user input can't use FTS query syntax
+        let match_query = text
+            .split_whitespace()
+            .map(|x| format!("\"{}\"*", x.replace('"', "\"\"")))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        if budget_ids.is_empty() || match_query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let columns = TRANSACTION_COLUMNS
+            .split(',')
+            .map(|x| format!("t.{}", x.trim()))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let placeholders = vec!["?"; budget_ids.len()].join(", ");
+
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {},
+                snippet(transactions_fts, 0, ?, ?, '…', 10),
+                snippet(transactions_fts, 1, ?, ?, '…', 10)
+            FROM transactions_fts JOIN transactions t ON t.transaction_id = transactions_fts.rowid
+            WHERE transactions_fts MATCH ? AND t.deleted_at IS NULL AND t.budget_id IN ({})
+            ORDER BY rank LIMIT ?",
+            columns, placeholders
+        ))?;
+
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        for _ in 0..2 {
+            values.push(Box::new(SNIPPET_MATCH_START.to_string()));
+            values.push(Box::new(SNIPPET_MATCH_END.to_string()));
+        }
+        values.push(Box::new(match_query));
+        for budget_id in budget_ids {
+            values.push(Box::new(budget_id));
+        }
+        values.push(Box::new(limit.unwrap_or(MAX_SEARCH_RESULTS).max(1).min(MAX_SEARCH_RESULTS)));
+
+        let mut result: Vec<TransactionSearchHit> = Vec::new();
+
+        let hit_iter = stmt.query_map(values.iter().map(|x| x.as_ref()), |row| {
+            Ok(TransactionSearchHit {
+                transaction: transaction_from_row(row)?,
+                name_snippet: snippet_to_html(&row.get::<_, String>(12)?),
+                description_snippet: snippet_to_html(&row.get::<_, String>(13)?),
+            })
+        });
+
+        for hit in hit_iter? {
+            result.push(hit?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn add_transaction(
+        &self,
+        access_token: &str,
+        transaction: &Transaction,
+    ) -> Result<Transaction, Error> {
+        let user = self.get_verified_user(access_token, Some(ApiKeyScope::WriteTransactions))?;
+
+        // Verify that the current user has access to this budget
+        let budget = match self.get_available_budget(access_token, transaction.budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        if budget.archived {
+            return Err(Error::BudgetArchived);
+        }
+
+        // Store dates in canonical form so they compare correctly against period bounds,
+        // dates in the future are scheduled and only count once they arrive
+        let date = match &transaction.date {
+            Some(x) => match from_sqlite_date_time(x) {
+                Ok(date) => {
+                    if date.date() < from_sqlite_date(&budget.start_date)? {
+                        return Err(Error::DateBeforeBudgetStart);
+                    }
+
+                    to_sqlite_date_time(&date)
+                }
+                Err(_) => return Err(Error::InvalidDate),
+            },
+            None => get_current_date_time(&self.budget_timezone(&budget)?),
+        };
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "INSERT INTO transactions(
+                        budget_id, email, name, description, date, amount, recur_days, recur_until,
+                        kind, external_id
+                    )
+                    VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                    params![
+                        transaction.budget_id,
+                        user.email,
+                        transaction.name,
+                        transaction.description,
+                        date,
+                        transaction.amount,
+                        transaction.recur_days,
+                        transaction.recur_until,
+                        transaction.kind,
+                        transaction.external_id
+                    ],
+                )
+                .map_err(sqlite_error)?;
+
+            let transaction_id = self.db_conn.last_insert_rowid();
+            let transaction = Transaction {
+                transaction_id: Some(transaction_id),
+                budget_id: transaction.budget_id,
+                email: Some(user.email.clone()),
+                name: transaction.name.clone(),
+                description: transaction.description.clone(),
+                date: Some(date.clone()),
+                amount: transaction.amount,
+                recur_days: transaction.recur_days,
+                recur_until: transaction.recur_until.clone(),
+                deleted_at: None,
+                kind: transaction.kind,
+                external_id: transaction.external_id.clone(),
+            };
+
+            self.record_audit(
+                &user.email,
+                "transaction.create",
+                "transaction",
+                &transaction_id.to_string(),
+                Some(transaction.budget_id),
+                None,
+                Some(&transaction),
+            )?;
+
+            Ok(transaction)
+        })
+    }
+
+    /// Imports the rows of a parsed bank statement into a budget, skipping
+    /// entries that are already in the budget
+    ///
+    /// Entries with a bank reference are duplicates of any transaction with the
+    /// same reference, including ones in the trash. Otherwise an entry is a
+    /// duplicate of a live transaction without a reference of the same kind and
+    /// amount on the same day, e.g. one entered by hand. Each existing
+    /// transaction matches at most one entry so that repeated purchases on the
+    /// same day are kept.
+    ///
+    /// When `dry_run` is set nothing is written and the summary previews the import.
+    pub fn import_transactions(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        mut rows: Vec<ImportRow>,
+        dry_run: bool,
+    ) -> Result<ImportSummary, Error> {
+        self.get_verified_user(access_token, Some(ApiKeyScope::WriteTransactions))?;
+
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        if budget.archived {
+            return Err(Error::BudgetArchived);
+        }
+
+        let start_date = from_sqlite_date(&budget.start_date)?;
+
+        // Existing transactions already matched by an earlier entry
+        let mut matched: HashSet<i64> = HashSet::new();
+        let mut seen_references: HashSet<String> = HashSet::new();
+
+        // Every row is classified before anything is inserted so that new
+        // transactions can't be mistaken for duplicates of later entries
+        for row in rows.iter_mut().filter(|x| x.status == ImportStatus::New) {
+            let entry = match &row.entry {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let date = match from_sqlite_date(&entry.date) {
+                Ok(x) => x,
+                Err(_) => {
+                    row.status = ImportStatus::Rejected;
+                    row.reason = Some(format!("invalid date \"{}\"", entry.date));
+                    continue;
+                }
+            };
+
+            if date < start_date {
+                row.status = ImportStatus::Rejected;
+                row.reason = Some(String::from("date is before the budget start date"));
+                continue;
+            }
+
+            if let Some(reference) = &entry.external_id {
+                if !seen_references.insert(reference.clone()) {
+                    row.status = ImportStatus::Duplicate;
+                    row.reason = Some(String::from("reference appears earlier in the statement"));
+                    continue;
+                }
+
+                let existing = self.db_conn.query_row(
+                    "SELECT transaction_id FROM transactions WHERE budget_id = ?1 AND external_id = ?2",
+                    params![budget_id, reference],
+                    |row| row.get::<_, i64>(0),
+                );
+
+                match existing {
+                    Ok(transaction_id) => {
+                        row.status = ImportStatus::Duplicate;
+                        row.duplicate_of = Some(transaction_id);
+                        continue;
+                    }
+                    Err(QueryReturnedNoRows) => (),
+                    Err(error) => return Err(sqlite_error(error)),
+                }
+            }
+
+            let mut stmt = self.db_conn.prepare(
+                "SELECT transaction_id FROM transactions
+                WHERE budget_id = ?1 AND deleted_at IS NULL AND external_id IS NULL AND kind = ?2
+                    AND date >= ?3 AND date < ?4 AND abs(amount - ?5) < 0.005
+                ORDER BY transaction_id",
+            )?;
+
+            let candidates = stmt.query_map(
+                params![
+                    budget_id,
+                    entry.kind,
+                    to_sqlite_date(&date),
+                    to_sqlite_date(&date.succ()),
+                    entry.amount
+                ],
+                |row| row.get::<_, i64>(0),
+            )?;
+
+            for candidate in candidates {
+                let candidate = candidate?;
+
+                if matched.insert(candidate) {
+                    row.status = ImportStatus::Duplicate;
+                    row.duplicate_of = Some(candidate);
+                    break;
+                }
+            }
+        }
+
+        if !dry_run {
+            self.atomically(|| {
+                for row in rows.iter_mut().filter(|x| x.status == ImportStatus::New) {
+                    let entry = match &row.entry {
+                        Some(x) => x,
+                        None => continue,
+                    };
+
+                    let mut transaction = Transaction::new(
+                        budget_id,
+                        entry.name.clone(),
+                        entry.description.clone(),
+                        entry.amount,
+                        Some(0), // Not recurring
+                        None,
+                    );
+                    transaction.kind = entry.kind;
+                    transaction.date = Some(entry.date.clone());
+                    transaction.external_id = entry.external_id.clone();
+
+                    row.transaction_id = self.add_transaction(access_token, &transaction)?.transaction_id;
+                }
+
+                Ok(())
+            })?;
+        }
+
+        let count = |status: ImportStatus| rows.iter().filter(|x| x.status == status).count() as i64;
+
+        Ok(ImportSummary {
+            dry_run,
+            new_entries: count(ImportStatus::New),
+            duplicates: count(ImportStatus::Duplicate),
+            rejected: count(ImportStatus::Rejected),
+            rows,
+        })
+    }
+
+    pub fn get_transaction(&self, transaction_id: i64) -> Result<Option<Transaction>, Error> {
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT {} FROM transactions WHERE transaction_id = ?1",
+            TRANSACTION_COLUMNS
+        ))?;
+
+        match stmt.query_row(params![transaction_id], transaction_from_row) {
+            Ok(transaction) => Ok(Some(transaction)),
+            Err(error) => match error {
+                QueryReturnedNoRows => Ok(None),
+                _ => Err(Error::UnknownError),
+            },
+        }
+    }
+
+    /// Gets a transaction that the user is allowed to modify, being either
+    /// its author or the owner of its budget
+    fn get_modifiable_transaction(
+        &self,
+        access_token: &str,
+        transaction_id: i64,
+    ) -> Result<(User, Transaction), Error> {
+        let user = match self.get_caller(access_token, Some(ApiKeyScope::WriteTransactions))? {
+            Some(x) => x.user,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let transaction = match self.get_transaction(transaction_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        let budget = match self.get_available_budget(access_token, transaction.budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        if budget.archived {
+            return Err(Error::BudgetArchived);
+        }
+
+        let is_author = transaction.email.as_ref() == Some(&user.email);
+        let is_owner = budget.owner.as_ref() == Some(&user.email);
+
+        if !is_author && !is_owner {
+            return Err(Error::UserDeniedError);
+        }
+
+        Ok((user, transaction))
+    }
+
+    /// Moves a transaction into its budget's trash
+    ///
+    /// Deleted transactions are excluded from listings and totals, they can be
+    /// restored until purged by `purge_deleted_transactions`
+    pub fn delete_transaction(&self, access_token: &str, transaction_id: i64) -> Result<(), Error> {
+        let (user, before) = self.get_modifiable_transaction(access_token, transaction_id)?;
+
+        if before.deleted_at.is_some() {
+            return Err(Error::EntryNotFound);
+        }
+
+        let deleted_at = get_current_date_time(&self.default_timezone);
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE transactions SET deleted_at = ?1 WHERE transaction_id = ?2",
+                    params![deleted_at, transaction_id],
+                )
+                .map_err(sqlite_error)?;
+
+            let after = Transaction {
+                deleted_at: Some(deleted_at.clone()),
+                ..before.clone()
+            };
+
+            self.record_audit(
+                &user.email,
+                "transaction.delete",
+                "transaction",
+                &transaction_id.to_string(),
+                Some(before.budget_id),
+                Some(&before),
+                Some(&after),
+            )
+        })
+    }
+
+    /// Restores a transaction out of its budget's trash
+    pub fn restore_transaction(&self, access_token: &str, transaction_id: i64) -> Result<(), Error> {
+        let (user, before) = self.get_modifiable_transaction(access_token, transaction_id)?;
+
+        if before.deleted_at.is_none() {
+            return Err(Error::EntryNotFound);
+        }
+
+        self.atomically(|| {
+            self.db_conn
+                .execute(
+                    "UPDATE transactions SET deleted_at = NULL WHERE transaction_id = ?1",
+                    params![transaction_id],
+                )
+                .map_err(sqlite_error)?;
+
+            let after = Transaction {
+                deleted_at: None,
+                ..before.clone()
+            };
+
+            self.record_audit(
+                &user.email,
+                "transaction.restore",
+                "transaction",
+                &transaction_id.to_string(),
+                Some(before.budget_id),
+                Some(&before),
+                Some(&after),
+            )
+        })
+    }
+
+    /// Permanently removes transactions that have been in the trash for longer
+    /// than `retention_days`, returning the number of removed transactions
+    pub fn purge_deleted_transactions(&self, retention_days: i64) -> Result<usize, Error> {
+        let cutoff = to_sqlite_date_time(
+            &(get_now(&self.default_timezone).naive_local() - Duration::days(retention_days)),
+        );
+
+        self.atomically(|| {
+            let mut stmt = self.db_conn.prepare(&format!(
+                "SELECT {} FROM transactions WHERE deleted_at IS NOT NULL AND deleted_at < ?1",
+                TRANSACTION_COLUMNS
+            ))?;
+
+            let mut expired: Vec<Transaction> = Vec::new();
+
+            for transaction in stmt.query_map(params![cutoff], transaction_from_row)? {
+                expired.push(transaction?);
+            }
+
+            for transaction in &expired {
+                let transaction_id = transaction.transaction_id.unwrap_or_default();
+
+                self.db_conn
+                    .execute(
+                        "DELETE FROM transactions WHERE transaction_id = ?1",
+                        params![transaction_id],
+                    )
+                    .map_err(sqlite_error)?;
+
+                self.record_audit(
+                    SYSTEM_ACTOR,
+                    "transaction.purge",
+                    "transaction",
+                    &transaction_id.to_string(),
+                    Some(transaction.budget_id),
+                    Some(transaction),
+                    None,
+                )?;
+            }
+
+            Ok(expired.len())
+        })
+    }
+
+    /// Gets the period `period_id` of a budget, `None` if it is before the first period
+    fn budget_period(&self, budget: &Budget, period_id: i64) -> Result<Option<BudgetPeriod>, Error> {
+        if period_id < 0 {
+            return Ok(None);
+        }
+
+        let budget_start = from_sqlite_date(&budget.start_date)?;
+        let (start_date, end_date) = match budget.period_rule.period_bounds(budget_start, period_id) {
+            Some(x) => x,
+            None => return Err(Error::InvalidPeriod),
+        };
+
+        Ok(Some(BudgetPeriod {
+            period_id,
+            start_date: to_sqlite_date(&start_date),
+            end_date: to_sqlite_date(&end_date),
+        }))
+    }
+
+    /// Gets the ID of the budget's current period in its timezone
+    fn current_budget_period_id(&self, budget: &Budget) -> Result<i64, Error> {
+        let budget_start = from_sqlite_date(&budget.start_date)?;
+        let today = get_today(&self.budget_timezone(budget)?);
+
+        // Budgets starting in the future are considered to be in their first period
+        match budget.period_rule.period_containing(budget_start, today) {
+            Some(x) => Ok(x.max(0)),
+            None => Err(Error::InvalidPeriod),
+        }
+    }
+
+    /// Gets every period of a budget up to and including the current one,
+    /// most recent first
+    pub fn get_budget_periods(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+    ) -> Result<Vec<BudgetPeriod>, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let current_period_id = self.current_budget_period_id(&budget)?;
+
+        let mut res: Vec<BudgetPeriod> = Vec::new();
+        for period_id in (0..=current_period_id).rev() {
+            if let Some(period) = self.budget_period(&budget, period_id)? {
+                res.push(period);
+            }
+        }
+
+        Ok(res)
+    }
+
+    pub fn get_budget_period(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        period_id: i64
+    ) -> Result<Option<BudgetPeriod>, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        self.budget_period(&budget, period_id)
+    }
+
+    pub fn get_budget_period_amount_spent(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        period_id: i64
+    ) -> Result<f64, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let period = match self.budget_period(&budget, period_id)? {
+            Some(x) => x,
+            None => return Err(Error::EntryNotFound),
+        };
+
+        let (spent, _) = self.budget_period_totals(&budget, &period)?;
+
+        Ok(spent)
+    }
+
+    /// Gets the amount spent and the income of a budget period
+    ///
+    /// Spent is expenses less refunds, income is income plus net transfers
+    fn budget_period_totals(&self, budget: &Budget, period: &BudgetPeriod) -> Result<(f64, f64), Error> {
+        let (start, end) = period_bounds(period)?;
+        let now = get_current_date_time(&self.budget_timezone(budget)?);
+
+        let mut stmt = self.db_conn.prepare(&format!(
+            "SELECT
+                SUM(CASE kind WHEN 'expense' THEN amount WHEN 'refund' THEN -amount ELSE 0 END),
+                SUM(CASE kind WHEN 'income' THEN amount WHEN 'transfer' THEN amount ELSE 0 END)
+            FROM transactions WHERE {}",
+            IN_PERIOD_CONDITION
+        ))?;
+
+        let (spent, income): (Option<f64>, Option<f64>) =
+            stmt.query_row(params![budget.budget_id, start, end, now], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        Ok((spent.unwrap_or(0.0), income.unwrap_or(0.0)))
+    }
+
+    /// Gets the balance of every period of a budget up to and including the
+    /// current one, most recent first
+    ///
+    /// Each period's effective limit is its spend limit and income plus whatever
+    /// the budget's rollover policy carried over from the period before it
+    pub fn get_budget_period_balances(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+    ) -> Result<Vec<BudgetPeriodBalance>, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let current_period_id = self.current_budget_period_id(&budget)?;
+
+        self.budget_period_balances(&budget, current_period_id)
+    }
+
+    /// Gets spending summaries for the periods `first_period_id..=last_period_id`
+    /// of a budget, most recent first
+    ///
+    /// Projections assume spending continues at the average daily rate seen so
+    /// far in each period
+    pub fn get_budget_period_summaries(
+        &self,
+        access_token: &str,
+        budget_id: i64,
+        first_period_id: i64,
+        last_period_id: i64,
+    ) -> Result<Vec<BudgetPeriodSummary>, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        if first_period_id < 0 || last_period_id < first_period_id {
+            return Err(Error::EntryNotFound);
+        }
+
+        // Every period up to the last one is totalled to carry balances forward,
+        // so the range is kept close to the present
+        let current_period_id = self.current_budget_period_id(&budget)?;
+
+        if last_period_id - first_period_id >= MAX_SUMMARY_PERIODS
+            || last_period_id > current_period_id + MAX_FUTURE_PERIODS
+        {
+            return Err(Error::InvalidPeriod);
+        }
+
+        let today = get_today(&self.budget_timezone(&budget)?);
+
+        let mut res: Vec<BudgetPeriodSummary> = Vec::new();
+
+        for balance in self.budget_period_balances(&budget, last_period_id)? {
+            if balance.period_id < first_period_id {
+                continue;
+            }
+
+            let start_date = from_sqlite_date(&balance.start_date)?;
+            let end_date = from_sqlite_date(&balance.end_date)?;
+
+            let total_days = end_date.signed_duration_since(start_date).num_days() + 1;
+            let elapsed_days = if today < start_date {
+                0
+            } else if today > end_date {
+                total_days
+            } else {
+                today.signed_duration_since(start_date).num_days() + 1
+            };
+
+            let limit = balance.effective_limit;
+            let spent = balance.spent;
+
+            let daily_burn_rate = if elapsed_days > 0 {
+                spent / elapsed_days as f64
+            } else {
+                0.0
+            };
+
+            res.push(BudgetPeriodSummary {
+                period_id: balance.period_id,
+                start_date: balance.start_date,
+                end_date: balance.end_date,
+                limit,
+                income: balance.income,
+                spent,
+                remaining: limit - spent,
+                percent_used: if limit > 0.0 {
+                    Some(spent / limit * 100.0)
+                } else {
+                    None
+                },
+                elapsed_days,
+                total_days,
+                daily_burn_rate,
+                projected_spent: if elapsed_days > 0 {
+                    daily_burn_rate * total_days as f64
+                } else {
+                    spent
+                },
+            });
+        }
+
+        Ok(res)
+    }
+
+    /// Gets the ID of the current period of a budget
+    pub fn get_current_budget_period_id(&self, access_token: &str, budget_id: i64) -> Result<i64, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        self.current_budget_period_id(&budget)
+    }
+
+    /// Gets the balances of periods `0..=last_period_id`, most recent first
+    fn budget_period_balances(
+        &self,
+        budget: &Budget,
+        last_period_id: i64,
+    ) -> Result<Vec<BudgetPeriodBalance>, Error> {
+        let mut res: Vec<BudgetPeriodBalance> = Vec::new();
+        let mut carried = 0.0;
+
+        for period_id in 0..=last_period_id {
+            let period = match self.budget_period(budget, period_id)? {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let (spent, income) = self.budget_period_totals(budget, &period)?;
+            let effective_limit = budget.spend_limit + carried + income;
+
+            res.insert(0, BudgetPeriodBalance {
+                period_id,
+                start_date: period.start_date,
+                end_date: period.end_date,
+                spend_limit: budget.spend_limit,
+                carried,
+                income,
+                effective_limit,
+                spent,
+            });
+
+            carried = budget.rollover_policy.carry(effective_limit - spent);
+        }
+
+        Ok(res)
+    }
+
+    pub fn get_current_budget_period(
+        &self,
+        access_token: &str,
+        budget_id: i64
+    ) -> Result<BudgetPeriod, Error> {
+        let budget = match self.get_available_budget(access_token, budget_id)? {
+            Some(x) => x,
+            None => return Err(Error::InvalidCredentials),
+        };
+
+        let period_id = self.current_budget_period_id(&budget)?;
+
+        match self.budget_period(&budget, period_id)? {
+            Some(period) => Ok(period),
+            None => Err(Error::EntryNotFound),
+        }
+    }
+}
+
+/// Gets the half-open range `[start, end)` of timestamps that fall on the
+/// days of `period`, for comparison against canonical transaction dates
+///
+/// Every query comparing transaction dates against a period must use this so
+/// that the last day of the period is always included.
+fn period_bounds(period: &BudgetPeriod) -> Result<(String, String), Error> {
+    let start = from_sqlite_date(&period.start_date)?;
+    let end = match from_sqlite_date(&period.end_date)?.succ_opt() {
+        Some(x) => x,
+        None => return Err(Error::InvalidPeriod),
+    };
+
+    Ok((
+        to_sqlite_date_time(&start.and_hms(0, 0, 0)),
+        to_sqlite_date_time(&end.and_hms(0, 0, 0)),
+    ))
+}
+
+fn sqlite_error(error: rusqlite::Error) -> Error {
+    match error {
+        SqliteFailure(error, desc) => Error::SqliteError(error, desc),
+        _ => Error::UnknownError,
+    }
+}
+
+fn audit_entry_from_row(row: &Row) -> rusqlite::Result<AuditEntry> {
+    let before: Option<String> = row.get(6)?;
+    let after: Option<String> = row.get(7)?;
+
+    Ok(AuditEntry {
+        audit_id: row.get(0)?,
+        actor: row.get(1)?,
+        action: row.get(2)?,
+        target_type: row.get(3)?,
+        target_id: row.get(4)?,
+        budget_id: row.get(5)?,
+        before: before.and_then(|x| serde_json::from_str(&x).ok()),
+        after: after.and_then(|x| serde_json::from_str(&x).ok()),
+        date: row.get(8)?,
+    })
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        email: row.get(0)?,
+        first_name: row.get(1)?,
+        last_name: row.get(2)?,
+        password: row.get(3)?,
+        access_token: row.get(4)?,
+        is_admin: row.get(5)?,
+        timezone: row.get(6)?,
+        email_verified: row.get(7)?,
+        approved: row.get(8)?,
+    })
+}
+
+fn budget_from_row(row: &Row) -> rusqlite::Result<Budget> {
+    let period_length: i64 = row.get(4)?;
+    let period_rule: Option<String> = row.get(8)?;
+    let rollover_policy: Option<String> = row.get(9)?;
+
+    Ok(Budget {
+        budget_id: row.get(0)?,
+        owner: row.get(1)?,
+        name: row.get(2)?,
+        spend_limit: row.get(3)?,
+        period_length,
+        start_date: row.get(5)?,
+        archived: row.get(6)?,
+        timezone: row.get(7)?,
+        period_rule: period_rule
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or(PeriodRule::Days { count: period_length }),
+        rollover_policy: rollover_policy
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or(RolloverPolicy::None),
+    })
+}
+
+/// Reads an `ApiKey` from `API_KEY_COLUMNS` starting at column `offset`
+fn api_key_from_row(row: &Row, offset: usize) -> rusqlite::Result<ApiKey> {
+    let budget_ids: Option<String> = row.get(offset + 4)?;
+
+    Ok(ApiKey {
+        api_key_id: row.get(offset)?,
+        name: row.get(offset + 1)?,
+        prefix: row.get(offset + 2)?,
+        scope: row.get(offset + 3)?,
+        // Unreadable lists deny every budget rather than allowing all of them
+        budget_ids: budget_ids.map(|x| serde_json::from_str(&x).unwrap_or_default()),
+        created_at: row.get(offset + 5)?,
+        last_used_at: row.get(offset + 6)?,
+        revoked_at: row.get(offset + 7)?,
+    })
+}
+
+fn transaction_from_row(row: &Row) -> rusqlite::Result<Transaction> {
+    Ok(Transaction {
+        transaction_id: row.get(0)?,
+        budget_id: row.get(1)?,
+        email: row.get(2)?,
+        name: row.get(3)?,
+        description: row.get(4)?,
+        date: row.get(5)?,
+        amount: row.get(6)?,
+        recur_days: row.get(7)?,
+        recur_until: row.get(8)?,
+        deleted_at: row.get(9)?,
+        kind: row.get(10)?,
+        external_id: row.get(11)?,
+    })
+}
+
+/// HTML escapes a search snippet and turns its match markers into `<mark>` tags
+fn snippet_to_html(snippet: &str) -> String {
+    escape_html(snippet)
+        .replace(SNIPPET_MATCH_START, "<mark>")
+        .replace(SNIPPET_MATCH_END, "</mark>")
+}
+
+fn rollback(path: &str, error: Error) {
+    // Do rollback
+    println!("Error occurred while setting up database, rolling back changes...");
+    fs::remove_file(Path::new(path)).unwrap();
+
+    panic!("Database setup failed:\n{:#?}", error);
+}
+
+// --- Database Types ---
+
+// --- Caller Type ---
+/// Who a request is from, see `Database::get_caller`
+struct Caller {
+    user: User,
+    // Set when the request was made with an API key rather than an access token
+    api_key: Option<ApiKey>,
+}
+
+impl Caller {
+    fn can_access_budget(&self, budget_id: i64) -> bool {
+        self.api_key.as_ref().map_or(true, |x| x.allows_budget(budget_id))
+    }
+}
+
+// --- User Type ---
+pub struct User {
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub password: String,
+    pub access_token: String,
+    pub is_admin: bool,
+    pub timezone: Option<String>,
+    pub email_verified: bool,
+    pub approved: bool,
+}
+
+impl User {
+    /// Constructs a new `User`
+    ///
+    /// Note: The password field should be plaintext (it is hashed here)
+    pub fn new(
+        database: &Database,
+        email: &String,
+        first_name: &String,
+        last_name: &String,
+        password: &String,
+        is_admin: bool,
+    ) -> User {
+        let hpassword = database.hash(password);
+
+        let access_token = User::generate_access_token(database, email, &hpassword);
+
+        User {
+            email: email.clone(),
+            first_name: first_name.clone(),
+            last_name: last_name.clone(),
+            password: hpassword,
+            access_token,
+            is_admin,
+            timezone: None,
+            email_verified: false,
+            approved: true,
+        }
+    }
+
+    fn generate_access_token(database: &Database, email: &String, hpassword: &String) -> String {
+        let mut s = email.clone();
+        s.push_str("::::");
+        s.push_str(hpassword);
+
+        database.hash(&s)
+    }
+
+    pub fn change_password(&mut self, database: &Database, hpassword: &String) {
+        self.password = String::from(hpassword);
+        self.access_token = User::generate_access_token(database, &self.email, &self.password);
+    }
+
+    /// Public details of the user, safe for use in the audit log
+    pub fn profile(&self) -> serde_json::Value {
+        json!({
+            "email": self.email,
+            "first_name": self.first_name,
+            "last_name": self.last_name,
+            "is_admin": self.is_admin,
+            "timezone": self.timezone,
+            "email_verified": self.email_verified,
+            "approved": self.approved
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ofx_import::parse_ofx;
+    use crate::transaction::TransactionKind;
+
+    use chrono::NaiveDate;
+
+    fn test_database() -> Database {
+        Database::new(String::from("test-secret"), Tz::UTC, ":memory:").unwrap()
+    }
+
+    /// Adds a user with a verified email
+    fn add_user(database: &Database, email: &str) -> User {
+        let mut user = User::new(
+            database,
+            &email.to_string(),
+            &String::from("Test"),
+            &String::from("User"),
+            &String::from("password"),
+            false,
+        );
+        user.email_verified = true;
+
+        database.insert_user(&user).unwrap();
+
+        user
+    }
+
+    fn add_budget(database: &Database, user: &User) -> Budget {
+        let budget = Budget::new(String::from("Groceries"), 500.0, 7, String::from("2020-01-01"));
+
+        database.add_budget(&user.access_token, &budget).unwrap()
+    }
+
+    fn add_expense(database: &Database, user: &User, budget_id: i64, amount: f64) -> Transaction {
+        let transaction = Transaction::new(budget_id, String::from("Item"), String::new(), amount, Some(0), None);
+
+        database.add_transaction(&user.access_token, &transaction).unwrap()
+    }
+
+    fn count_rows(database: &Database, table: &str, budget_id: i64) -> i64 {
+        database
+            .db_conn
+            .query_row(
+                &format!("SELECT COUNT(*) FROM {} WHERE budget_id = ?1", table),
+                params![budget_id],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn archived_budgets_are_read_only() {
+        let database = test_database();
+        let alice = add_user(&database, "alice@example.com");
+        let bob = add_user(&database, "bob@example.com");
+        let budget_id = add_budget(&database, &alice).budget_id.unwrap();
+
+        database.add_can_access_budget(&alice.access_token, budget_id, &bob.email).unwrap();
+        add_expense(&database, &bob, budget_id, 5.0);
+
+        // Only the owner can archive
+        assert!(database.set_budget_archived(&bob.access_token, budget_id, true).is_err());
+        database.set_budget_archived(&alice.access_token, budget_id, true).unwrap();
+
+        for user in [&alice, &bob].iter() {
+            assert!(database.get_available_budgets(&user.access_token, false).unwrap().is_empty());
+            assert_eq!(database.get_available_budgets(&user.access_token, true).unwrap().len(), 1);
+        }
+
+        let transaction = Transaction::new(budget_id, String::from("Item"), String::new(), 1.0, Some(0), None);
+        match database.add_transaction(&bob.access_token, &transaction) {
+            Err(Error::BudgetArchived) => (),
+            x => panic!("transaction added to an archived budget: {:?}", x.map(|x| x.transaction_id)),
+        }
+        match database.delete_can_access_budget(&alice.access_token, budget_id, &bob.email) {
+            Err(Error::BudgetArchived) => (),
+            x => panic!("sharing changed on an archived budget: {:?}", x),
+        }
+
+        // History is kept and unarchiving makes it editable again
+        assert_eq!(count_rows(&database, "transactions", budget_id), 1);
+
+        database.set_budget_archived(&alice.access_token, budget_id, false).unwrap();
+        add_expense(&database, &bob, budget_id, 1.0);
+    }
+
+    #[test]
+    fn deleting_a_budget_removes_its_rows() {
+        let database = test_database();
+        let alice = add_user(&database, "alice@example.com");
+        let bob = add_user(&database, "bob@example.com");
+        let budget_id = add_budget(&database, &alice).budget_id.unwrap();
+        let kept_id = add_budget(&database, &alice).budget_id.unwrap();
+
+        for id in [budget_id, kept_id].iter() {
+            database.add_can_access_budget(&alice.access_token, *id, &bob.email).unwrap();
+            add_expense(&database, &alice, *id, 5.0);
+            let deleted = add_expense(&database, &bob, *id, 2.0);
+            database.delete_transaction(&bob.access_token, deleted.transaction_id.unwrap()).unwrap();
+        }
+
+        // Shared users can't delete, and archived budgets can still be deleted
+        assert!(database.delete_budget(&bob.access_token, budget_id).is_err());
+        database.set_budget_archived(&alice.access_token, budget_id, true).unwrap();
+        database.delete_budget(&alice.access_token, budget_id).unwrap();
+
+        assert!(database.get_budget(budget_id).unwrap().is_none());
+        assert_eq!(count_rows(&database, "transactions", budget_id), 0);
+        assert_eq!(count_rows(&database, "can_access_budget", budget_id), 0);
+
+        assert!(database.get_budget(kept_id).unwrap().is_some());
+        assert_eq!(count_rows(&database, "transactions", kept_id), 2);
+        assert_eq!(count_rows(&database, "can_access_budget", kept_id), 1);
+    }
+
+    #[test]
+    fn deleted_transactions_go_to_the_trash() {
+        let database = test_database();
+        let alice = add_user(&database, "alice@example.com");
+        let bob = add_user(&database, "bob@example.com");
+        let budget_id = add_budget(&database, &alice).budget_id.unwrap();
+        let period_id = database.get_current_budget_period_id(&alice.access_token, budget_id).unwrap();
+
+        let spent = || {
+            database
+                .get_budget_period_amount_spent(&alice.access_token, budget_id, period_id)
+                .unwrap()
+        };
+
+        database.add_can_access_budget(&alice.access_token, budget_id, &bob.email).unwrap();
+        add_expense(&database, &alice, budget_id, 5.0);
+        let transaction_id = add_expense(&database, &alice, budget_id, 2.0).transaction_id.unwrap();
+
+        // Only the author or the budget owner can delete
+        match database.delete_transaction(&bob.access_token, transaction_id) {
+            Err(Error::UserDeniedError) => (),
+            x => panic!("shared user deleted someone else's transaction: {:?}", x),
+        }
+
+        database.delete_transaction(&alice.access_token, transaction_id).unwrap();
+
+        let trash = database.get_budget_deleted_transactions(&bob.access_token, budget_id).unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].transaction_id, Some(transaction_id));
+        assert!(trash[0].deleted_at.is_some());
+
+        // Deleted transactions don't count or show up anywhere else
+        let page = database
+            .get_budget_transactions(&alice.access_token, budget_id, &TransactionQuery::default())
+            .unwrap();
+        assert_eq!(page.total_count, 1);
+        assert_eq!(spent(), 5.0);
+
+        match database.delete_transaction(&alice.access_token, transaction_id) {
+            Err(Error::EntryNotFound) => (),
+            x => panic!("transaction deleted twice: {:?}", x),
+        }
+
+        database.restore_transaction(&alice.access_token, transaction_id).unwrap();
+
+        assert!(database.get_budget_deleted_transactions(&alice.access_token, budget_id).unwrap().is_empty());
+        assert_eq!(spent(), 7.0);
+
+        match database.restore_transaction(&alice.access_token, transaction_id) {
+            Err(Error::EntryNotFound) => (),
+            x => panic!("live transaction restored: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn trash_is_purged_after_the_retention_period() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget_id = add_budget(&database, &user).budget_id.unwrap();
+
+        let live = add_expense(&database, &user, budget_id, 1.0).transaction_id.unwrap();
+        let recent = add_expense(&database, &user, budget_id, 2.0).transaction_id.unwrap();
+        let expired = add_expense(&database, &user, budget_id, 3.0).transaction_id.unwrap();
+
+        database.delete_transaction(&user.access_token, recent).unwrap();
+        database.delete_transaction(&user.access_token, expired).unwrap();
+
+        let long_ago = get_now(&Tz::UTC).naive_local() - Duration::days(31);
+        database
+            .db_conn
+            .execute(
+                "UPDATE transactions SET deleted_at = ?1 WHERE transaction_id = ?2",
+                params![to_sqlite_date_time(&long_ago), expired],
+            )
+            .unwrap();
+
+        assert_eq!(database.purge_deleted_transactions(30).unwrap(), 1);
+        assert_eq!(database.purge_deleted_transactions(30).unwrap(), 0);
+
+        assert!(database.get_transaction(expired).unwrap().is_none());
+        assert!(database.get_transaction(recent).unwrap().unwrap().deleted_at.is_some());
+        assert!(database.get_transaction(live).unwrap().unwrap().deleted_at.is_none());
+
+        // Purges are audited against the system rather than a user
+        let purged: i64 = database
+            .db_conn
+            .query_row(
+                "SELECT COUNT(*) FROM audit_log WHERE action = 'transaction.purge' AND actor = ?1",
+                params![SYSTEM_ACTOR],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(purged, 1);
+    }
+
+    #[test]
+    fn timezones_fall_back_from_budget_to_owner_to_server() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget = add_budget(&database, &user);
+        let budget_id = budget.budget_id.unwrap();
+
+        let budget_timezone = || {
+            let budget = database.get_budget(budget_id).unwrap().unwrap();
+            database.budget_timezone(&budget).unwrap()
+        };
+
+        assert_eq!(database.get_user_timezone(&user.access_token).unwrap(), Tz::UTC);
+        assert_eq!(budget_timezone(), Tz::UTC);
+
+        match database.set_user_timezone(&user.access_token, Some("Mars/Olympus_Mons")) {
+            Err(Error::InvalidTimezone) => (),
+            x => panic!("unknown timezone accepted: {:?}", x),
+        }
+
+        database.set_user_timezone(&user.access_token, Some("Australia/Hobart")).unwrap();
+        assert_eq!(database.get_user_timezone(&user.access_token).unwrap(), Tz::Australia__Hobart);
+        assert_eq!(budget_timezone(), Tz::Australia__Hobart);
+
+        database.set_budget_timezone(&user.access_token, budget_id, Some("Europe/London")).unwrap();
+        assert_eq!(budget_timezone(), Tz::Europe__London);
+
+        database.set_budget_timezone(&user.access_token, budget_id, None).unwrap();
+        assert_eq!(budget_timezone(), Tz::Australia__Hobart);
+
+        database.set_user_timezone(&user.access_token, None).unwrap();
+        assert_eq!(budget_timezone(), Tz::UTC);
+    }
+
+    #[test]
+    fn transactions_are_dated_in_their_budget_timezone() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        let mut dates: Vec<NaiveDate> = Vec::new();
+
+        // 25 hours apart, so the two are never on the same day
+        for timezone in ["Pacific/Pago_Pago", "Pacific/Kiritimati"].iter() {
+            let mut budget = Budget::new(String::from("Travel"), 100.0, 7, String::from("2020-01-01"));
+            budget.timezone = Some(timezone.to_string());
+            let budget_id = database.add_budget(&user.access_token, &budget).unwrap().budget_id.unwrap();
+
+            let transaction = add_expense(&database, &user, budget_id, 1.0);
+            dates.push(from_sqlite_date_time(transaction.date.as_ref().unwrap()).unwrap().date());
+
+            // Undated transactions are in the current period of their budget
+            let period_id = database.get_current_budget_period_id(&user.access_token, budget_id).unwrap();
+            let spent = database
+                .get_budget_period_amount_spent(&user.access_token, budget_id, period_id)
+                .unwrap();
+            assert_eq!(spent, 1.0, "{}", timezone);
+        }
+
+        assert!(dates[1] > dates[0], "{:?}", dates);
+    }
+
+    /// Adds a transaction at noon on `date`
+    fn add_dated(database: &Database, user: &User, budget_id: i64, date: &str, kind: TransactionKind, amount: f64) {
+        let mut transaction = Transaction::new(budget_id, String::from("Item"), String::new(), amount, Some(0), None);
+        transaction.date = Some(format!("{} 12:00:00", date));
+        transaction.kind = kind;
+
+        database.add_transaction(&user.access_token, &transaction).unwrap();
+    }
+
+    #[test]
+    fn balances_carry_between_periods() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        // Weekly periods with a limit of 500, the first underspent by 40 and
+        // the second overspent by 50 before anything is carried
+        let budget_id = add_budget(&database, &user).budget_id.unwrap();
+        add_dated(&database, &user, budget_id, "2020-01-02", TransactionKind::Expense, 460.0);
+        add_dated(&database, &user, budget_id, "2020-01-09", TransactionKind::Expense, 550.0);
+        add_dated(&database, &user, budget_id, "2020-01-16", TransactionKind::Expense, 10.0);
+
+        // Policy, then the amount carried into the second and third periods
+        let cases = [
+            (RolloverPolicy::None, 0.0, 0.0),
+            (RolloverPolicy::CarrySurplus, 40.0, 0.0),
+            (RolloverPolicy::CarryDeficit, 0.0, -50.0),
+            (RolloverPolicy::Both, 40.0, -10.0),
+            (RolloverPolicy::Capped { max_surplus: 25.0, max_deficit: 5.0 }, 25.0, -5.0),
+        ];
+
+        for (policy, second, third) in cases.iter() {
+            database.set_budget_rollover_policy(&user.access_token, budget_id, policy).unwrap();
+
+            let balances = database.get_budget_period_balances(&user.access_token, budget_id).unwrap();
+
+            // Most recent first, down to the first period
+            let balance = |period_id: i64| &balances[balances.len() - 1 - period_id as usize];
+
+            assert_eq!(balance(0).period_id, 0);
+            assert_eq!(balance(0).carried, 0.0);
+            assert_eq!(balance(0).effective_limit, 500.0);
+            assert_eq!(balance(0).spent, 460.0);
+
+            assert_eq!(balance(1).carried, *second, "{:?}", policy);
+            assert_eq!(balance(1).effective_limit, 500.0 + second, "{:?}", policy);
+            assert_eq!(balance(1).spent, 550.0);
+
+            assert_eq!(balance(2).carried, *third, "{:?}", policy);
+            assert_eq!(balance(2).effective_limit, 500.0 + third, "{:?}", policy);
+        }
+
+        match database.set_budget_rollover_policy(
+            &user.access_token,
+            budget_id,
+            &RolloverPolicy::Capped { max_surplus: -1.0, max_deficit: 0.0 },
+        ) {
+            Err(Error::InvalidRolloverPolicy) => (),
+            x => panic!("negative cap accepted: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn summaries_project_spending_at_the_current_rate() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        // Ten day periods starting today, so one day of the first has elapsed
+        let today = to_sqlite_date(&get_today(&Tz::UTC));
+        let budget = Budget::new(String::from("Holiday"), 500.0, 10, today);
+        let budget_id = database.add_budget(&user.access_token, &budget).unwrap().budget_id.unwrap();
+
+        add_expense(&database, &user, budget_id, 30.0);
+
+        let summaries = database
+            .get_budget_period_summaries(&user.access_token, budget_id, 0, 1)
+            .unwrap();
+
+        // Most recent first, the next period hasn't started
+        let next = &summaries[0];
+        assert_eq!(next.period_id, 1);
+        assert_eq!((next.elapsed_days, next.total_days), (0, 10));
+        assert_eq!((next.daily_burn_rate, next.projected_spent), (0.0, 0.0));
+
+        let current = &summaries[1];
+        assert_eq!(current.period_id, 0);
+        assert_eq!((current.elapsed_days, current.total_days), (1, 10));
+        assert_eq!(current.limit, 500.0);
+        assert_eq!(current.spent, 30.0);
+        assert_eq!(current.remaining, 470.0);
+        assert_eq!(current.percent_used, Some(6.0));
+        assert_eq!(current.daily_burn_rate, 30.0);
+        assert_eq!(current.projected_spent, 300.0);
+    }
+
+    #[test]
+    fn summaries_of_past_periods_project_what_was_spent() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        let budget = Budget::new(String::from("Nothing"), 0.0, 7, String::from("2020-01-01"));
+        let budget_id = database.add_budget(&user.access_token, &budget).unwrap().budget_id.unwrap();
+
+        add_dated(&database, &user, budget_id, "2020-01-03", TransactionKind::Expense, 70.0);
+
+        let summary = &database
+            .get_budget_period_summaries(&user.access_token, budget_id, 0, 0)
+            .unwrap()[0];
+
+        assert_eq!((summary.start_date.as_str(), summary.end_date.as_str()), ("2020-01-01", "2020-01-07"));
+        assert_eq!((summary.elapsed_days, summary.total_days), (7, 7));
+        assert_eq!(summary.daily_burn_rate, 10.0);
+        assert_eq!(summary.projected_spent, 70.0);
+        assert_eq!(summary.remaining, -70.0);
+
+        // No percentage of a limit of nothing
+        assert_eq!(summary.percent_used, None);
+    }
+
+    #[test]
+    fn summary_ranges_are_limited() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget_id = add_budget(&database, &user).budget_id.unwrap();
+        let current = database.get_current_budget_period_id(&user.access_token, budget_id).unwrap();
+
+        let summaries = |first, last| database.get_budget_period_summaries(&user.access_token, budget_id, first, last);
+
+        match summaries(2, 1) {
+            Err(Error::EntryNotFound) => (),
+            x => panic!("backwards range accepted: {:?}", x.map(|x| x.len())),
+        }
+        match summaries(0, MAX_SUMMARY_PERIODS) {
+            Err(Error::InvalidPeriod) => (),
+            x => panic!("too many periods accepted: {:?}", x.map(|x| x.len())),
+        }
+        match summaries(current, current + MAX_FUTURE_PERIODS + 1) {
+            Err(Error::InvalidPeriod) => (),
+            x => panic!("distant future accepted: {:?}", x.map(|x| x.len())),
+        }
+
+        let upcoming = summaries(current - 2, current + MAX_FUTURE_PERIODS).unwrap();
+        assert_eq!(upcoming.len() as i64, MAX_FUTURE_PERIODS + 3);
+    }
+
+    #[test]
+    fn refunds_reduce_spending_and_income_raises_the_limit() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget_id = add_budget(&database, &user).budget_id.unwrap();
+
+        let transactions = [
+            (TransactionKind::Expense, 100.0),
+            (TransactionKind::Expense, 50.0),
+            (TransactionKind::Refund, 30.0),
+            (TransactionKind::Income, 200.0),
+            (TransactionKind::Transfer, 40.0),
+            (TransactionKind::Transfer, -15.0),
+        ];
+
+        for (kind, amount) in transactions.iter() {
+            add_dated(&database, &user, budget_id, "2020-01-02", *kind, *amount);
+        }
+
+        // Something in the next period so it's clear only the first is counted
+        add_dated(&database, &user, budget_id, "2020-01-08", TransactionKind::Income, 1000.0);
+
+        let budget = database.get_budget(budget_id).unwrap().unwrap();
+        let period = database.budget_period(&budget, 0).unwrap().unwrap();
+
+        assert_eq!(database.budget_period_totals(&budget, &period).unwrap(), (120.0, 225.0));
+        assert_eq!(database.get_budget_period_amount_spent(&user.access_token, budget_id, 0).unwrap(), 120.0);
+
+        let balances = database.get_budget_period_balances(&user.access_token, budget_id).unwrap();
+        let first = balances.last().unwrap();
+
+        assert_eq!(first.income, 225.0);
+        assert_eq!(first.effective_limit, 500.0 + 225.0);
+        assert_eq!(first.spent, 120.0);
+    }
+
+    #[test]
+    fn future_transactions_are_scheduled_until_their_date() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget = add_budget(&database, &user);
+        let budget_id = budget.budget_id.unwrap();
+
+        let now = get_now(&Tz::UTC).naive_local();
+        let later = now + Duration::days(2);
+        let soon = now + Duration::hours(1);
+
+        for (date, amount) in [(later, 20.0), (soon, 10.0)].iter() {
+            let mut transaction = Transaction::new(budget_id, String::from("Rent"), String::new(), *amount, Some(0), None);
+            transaction.date = Some(to_sqlite_date_time(date));
+            database.add_transaction(&user.access_token, &transaction).unwrap();
+        }
+
+        let scheduled = database.get_budget_scheduled_transactions(&user.access_token, budget_id).unwrap();
+        assert_eq!(scheduled.iter().map(|x| x.amount).collect::<Vec<f64>>(), vec![10.0, 20.0]);
+
+        let page = database
+            .get_budget_transactions(&user.access_token, budget_id, &TransactionQuery::default())
+            .unwrap();
+        assert_eq!(page.total_count, 0);
+
+        let start_date = from_sqlite_date(&budget.start_date).unwrap();
+        let period_id = budget.period_rule.period_containing(start_date, soon.date()).unwrap();
+        let spent = || {
+            database
+                .get_budget_period_amount_spent(&user.access_token, budget_id, period_id)
+                .unwrap()
+        };
+
+        assert_eq!(spent(), 0.0);
+
+        // Once its date arrives a scheduled transaction counts like any other
+        let arrived = to_sqlite_date_time(&(now - Duration::minutes(1)));
+        database
+            .db_conn
+            .execute(
+                "UPDATE transactions SET date = ?1 WHERE transaction_id = ?2",
+                params![arrived, scheduled[0].transaction_id],
+            )
+            .unwrap();
+
+        let scheduled = database.get_budget_scheduled_transactions(&user.access_token, budget_id).unwrap();
+
+        assert_eq!(spent(), 10.0);
+        assert_eq!(scheduled.len(), 1);
+    }
+
+    #[test]
+    fn backdated_transactions_must_be_in_the_budget() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget_id = add_budget(&database, &user).budget_id.unwrap();
+
+        let dated = |date: &str| {
+            let mut transaction = Transaction::new(budget_id, String::from("Item"), String::new(), 1.0, Some(0), None);
+            transaction.date = Some(date.to_string());
+            database.add_transaction(&user.access_token, &transaction)
+        };
+
+        match dated("2019-12-31 23:59:59") {
+            Err(Error::DateBeforeBudgetStart) => (),
+            x => panic!("transaction before the budget accepted: {:?}", x.map(|x| x.date)),
+        }
+        match dated("31/01/2020") {
+            Err(Error::InvalidDate) => (),
+            x => panic!("invalid date accepted: {:?}", x.map(|x| x.date)),
+        }
+
+        // Dates are stored in canonical form, plain dates at midnight
+        assert_eq!(dated("2020-01-01").unwrap().date, Some(String::from("2020-01-01 00:00:00.000")));
+        assert_eq!(dated("2020-01-02 09:30:00").unwrap().date, Some(String::from("2020-01-02 09:30:00.000")));
+    }
+
+    #[test]
+    fn account_imports_remap_ids() {
+        let source = test_database();
+        let alice = add_user(&source, "alice@example.com");
+        let bob = add_user(&source, "bob@example.com");
+        let carol = add_user(&source, "carol@example.com");
+        let dave = add_user(&source, "dave@example.com");
+
+        let groceries = add_budget(&source, &alice).budget_id.unwrap();
+        let travel = add_budget(&source, &alice).budget_id.unwrap();
+        let daves = add_budget(&source, &dave).budget_id.unwrap();
+
+        source.add_can_access_budget(&alice.access_token, groceries, &bob.email).unwrap();
+        source.add_can_access_budget(&alice.access_token, groceries, &carol.email).unwrap();
+        source.add_can_access_budget(&dave.access_token, daves, &alice.email).unwrap();
+
+        add_expense(&source, &alice, groceries, 1.0);
+        add_expense(&source, &bob, groceries, 2.0);
+        add_expense(&source, &carol, groceries, 3.0);
+        let trashed = add_expense(&source, &alice, travel, 4.0).transaction_id.unwrap();
+        source.delete_transaction(&alice.access_token, trashed).unwrap();
+        add_expense(&source, &alice, daves, 5.0);
+
+        // Archives are downloaded as JSON and uploaded again
+        let archive = source.export_account(&alice.access_token).unwrap();
+        let archive: AccountArchive = serde_json::from_str(&serde_json::to_string(&archive).unwrap()).unwrap();
+
+        assert_eq!(archive.budgets.len(), 2);
+        assert_eq!(archive.shared_budgets.len(), 1);
+
+        // Only Bob has an account on the new server, and IDs are already taken there
+        let target = test_database();
+        let mut admin = add_user(&target, "admin@example.com");
+        admin.is_admin = true;
+        target.update_user(&admin).unwrap();
+        let new_bob = add_user(&target, "bob@example.com");
+        let existing = add_budget(&target, &admin).budget_id.unwrap();
+        for _ in 0..3 {
+            add_expense(&target, &admin, existing, 1.0);
+        }
+
+        match target.import_account(&new_bob.access_token, &archive) {
+            Err(Error::UserDeniedError) => (),
+            x => panic!("non-admin imported an account: {:?}", x.map(|x| x.email)),
+        }
+
+        let import = target.import_account(&admin.access_token, &archive).unwrap();
+
+        assert_eq!(import.skipped_grants, vec![carol.email.clone()]);
+        assert_eq!(import.reassigned_transactions, 1);
+        assert_eq!(import.skipped_shared_transactions, 1);
+        assert_eq!(import.budget_ids.len(), 2);
+        assert_eq!(import.transaction_ids.len(), 4);
+
+        let new_budget_id = |old_id: i64| {
+            import.budget_ids.iter().find(|x| x.old_id == old_id).unwrap().new_id
+        };
+
+        for archived in &archive.budgets {
+            let old_id = archived.budget.budget_id.unwrap();
+            let budget = target.get_budget(new_budget_id(old_id)).unwrap().unwrap();
+
+            assert_ne!(budget.budget_id, Some(existing));
+            assert_eq!(budget.owner, Some(alice.email.clone()));
+            assert_eq!(budget.name, archived.budget.name);
+
+            for old in &archived.transactions {
+                let mapping = import
+                    .transaction_ids
+                    .iter()
+                    .find(|x| Some(x.old_id) == old.transaction_id)
+                    .unwrap();
+                let new = target.get_transaction(mapping.new_id).unwrap().unwrap();
+
+                assert_eq!(new.budget_id, budget.budget_id.unwrap());
+                assert_eq!(new.amount, old.amount);
+                assert_eq!(new.date, old.date);
+                assert_eq!(new.deleted_at.is_some(), old.transaction_id == Some(trashed));
+
+                // Carol isn't on this server so her transaction becomes Alice's
+                let author = match &old.email {
+                    Some(x) if *x == carol.email => &alice.email,
+                    x => x.as_ref().unwrap(),
+                };
+                assert_eq!(new.email.as_ref(), Some(author));
+            }
+        }
+
+        // Bob still has access, and Alice signs in with the temporary password
+        assert!(target.get_available_budget(&new_bob.access_token, new_budget_id(groceries)).unwrap().is_some());
+
+        let new_alice = target.get_user_by_email(&alice.email).unwrap().unwrap();
+        assert_eq!(new_alice.password, target.hash(&import.temporary_password));
+        assert!(!new_alice.is_admin);
+
+        match target.import_account(&admin.access_token, &archive) {
+            Err(Error::UserAlreadyExists) => (),
+            x => panic!("account imported twice: {:?}", x.map(|x| x.email)),
+        }
+    }
+
+    /// A user as they are right after registering, with an unverified email
+    fn new_user(database: &Database, email: &str) -> User {
+        User::new(
+            database,
+            &email.to_string(),
+            &String::from("New"),
+            &String::from("User"),
+            &String::from("password"),
+            false,
+        )
+    }
+
+    #[test]
+    fn unverified_users_cant_create_anything() {
+        let database = test_database();
+        let user = new_user(&database, "alice@example.com");
+        database.insert_user(&user).unwrap();
+
+        // Signing in works, creating budgets doesn't
+        assert!(database.get_user_by_access_token(&user.access_token).unwrap().is_some());
+
+        let budget = Budget::new(String::from("Food"), 100.0, 7, String::from("2020-01-01"));
+        match database.add_budget(&user.access_token, &budget) {
+            Err(Error::EmailNotVerified) => (),
+            x => panic!("unverified user added a budget: {:?}", x.map(|x| x.budget_id)),
+        }
+
+        // Asking again replaces the earlier token
+        let lifetime = Duration::hours(24);
+        let first = database.request_email_verification(&user.email, lifetime).unwrap().unwrap();
+        let token = database.request_email_verification(&user.email, lifetime).unwrap().unwrap();
+
+        match database.verify_email(&first) {
+            Err(Error::InvalidToken) => (),
+            x => panic!("replaced token verified the email: {:?}", x),
+        }
+
+        database.verify_email(&token).unwrap();
+
+        match database.verify_email(&token) {
+            Err(Error::InvalidToken) => (),
+            x => panic!("token used twice: {:?}", x),
+        }
+
+        assert!(database.get_user_by_email(&user.email).unwrap().unwrap().email_verified);
+        assert_eq!(database.request_email_verification(&user.email, lifetime).unwrap(), None);
+
+        database.add_budget(&user.access_token, &budget).unwrap();
+    }
+
+    #[test]
+    fn verification_tokens_expire() {
+        let database = test_database();
+        let user = new_user(&database, "alice@example.com");
+        database.insert_user(&user).unwrap();
+
+        let token = database
+            .request_email_verification(&user.email, Duration::seconds(-1))
+            .unwrap()
+            .unwrap();
+
+        match database.verify_email(&token) {
+            Err(Error::InvalidToken) => (),
+            x => panic!("expired token verified the email: {:?}", x),
+        }
+
+        assert!(!database.get_user_by_email(&user.email).unwrap().unwrap().email_verified);
+    }
+
+    #[test]
+    fn search_snippets_are_html_escaped() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget = add_budget(&database, &user);
+
+        let transaction = Transaction::new(
+            budget.budget_id.unwrap(),
+            String::from("<script>alert(1)</script> groceries"),
+            String::from("Tom & Jerry's \"deli\""),
+            12.5,
+            Some(0),
+            None,
+        );
+        database.add_transaction(&user.access_token, &transaction).unwrap();
+
+        let hits = database.search_transactions(&user.access_token, "groceries", None).unwrap();
+
+        assert_eq!(hits.len(), 1);
+        assert_eq!(
+            hits[0].name_snippet,
+            "&lt;script&gt;alert(1)&lt;/script&gt; <mark>groceries</mark>"
+        );
+        assert_eq!(hits[0].description_snippet, "Tom &amp; Jerry&#39;s &quot;deli&quot;");
+
+        // The transaction itself is returned unchanged
+        assert_eq!(hits[0].transaction.name, transaction.name);
+    }
+
+    #[test]
+    fn transaction_pages_are_always_limited() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget = add_budget(&database, &user);
+        let budget_id = budget.budget_id.unwrap();
+
+        for i in 0..1005 {
+            let transaction = Transaction::new(budget_id, format!("Item {}", i), String::new(), 1.0, Some(0), None);
+            database.add_transaction(&user.access_token, &transaction).unwrap();
+        }
+
+        let page = database
+            .get_budget_transactions(&user.access_token, budget_id, &TransactionQuery::default())
+            .unwrap();
+
+        assert_eq!(page.transactions.len() as i64, DEFAULT_TRANSACTION_PAGE_SIZE);
+        assert_eq!(page.total_count, 1005);
+        assert!(page.next_cursor.is_some());
+
+        let mut query = TransactionQuery {
+            limit: Some(5000),
+            ..TransactionQuery::default()
+        };
+
+        let page = database.get_budget_transactions(&user.access_token, budget_id, &query).unwrap();
+
+        assert_eq!(page.transactions.len() as i64, MAX_TRANSACTION_PAGE_SIZE);
+
+        query.cursor = page.next_cursor;
+
+        let page = database.get_budget_transactions(&user.access_token, budget_id, &query).unwrap();
+
+        assert_eq!(page.transactions.len(), 5);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn api_keys_are_limited_to_their_scope_and_budgets() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let allowed = add_budget(&database, &user).budget_id.unwrap();
+        let other = add_budget(&database, &user).budget_id.unwrap();
+
+        let read_only = database
+            .create_api_key(&user.access_token, "read", ApiKeyScope::ReadOnly, Some(vec![allowed]))
+            .unwrap()
+            .key;
+        let writer = database
+            .create_api_key(&user.access_token, "write", ApiKeyScope::WriteTransactions, None)
+            .unwrap()
+            .key;
+
+        // Keys are only accepted where a scope is asked for, and only if they cover it
+        match database.get_caller(&writer, None) {
+            Err(Error::ApiKeyScopeDenied) => (),
+            x => panic!("key accepted without a scope: {:?}", x.map(|x| x.is_some())),
+        }
+        match database.get_caller(&read_only, Some(ApiKeyScope::WriteTransactions)) {
+            Err(Error::ApiKeyScopeDenied) => (),
+            x => panic!("read only key allowed to write: {:?}", x.map(|x| x.is_some())),
+        }
+
+        let caller = database.get_caller(&read_only, Some(ApiKeyScope::ReadOnly)).unwrap().unwrap();
+        assert_eq!(caller.user.email, user.email);
+        assert!(caller.can_access_budget(allowed));
+        assert!(!caller.can_access_budget(other));
+
+        // Budgets the key isn't limited to look like they don't exist
+        assert!(database.get_available_budget(&read_only, allowed).unwrap().is_some());
+        assert!(database.get_available_budget(&read_only, other).unwrap().is_none());
+        assert_eq!(database.get_available_budgets(&read_only, true).unwrap().len(), 1);
+
+        let transaction = Transaction::new(other, String::from("Milk"), String::new(), 2.0, Some(0), None);
+        match database.add_transaction(&read_only, &transaction) {
+            Err(Error::ApiKeyScopeDenied) => (),
+            x => panic!("read only key added a transaction: {:?}", x.map(|x| x.transaction_id)),
+        }
+        database.add_transaction(&writer, &transaction).unwrap();
+
+        // Unknown keys are treated like unknown access tokens
+        assert!(database.get_caller("btk_unknown", Some(ApiKeyScope::ReadOnly)).unwrap().is_none());
+    }
+
+    #[test]
+    fn changing_the_password_revokes_api_keys() {
+        let database = test_database();
+        let mut user = add_user(&database, "alice@example.com");
+
+        let key = database
+            .create_api_key(&user.access_token, "read", ApiKeyScope::ReadOnly, None)
+            .unwrap()
+            .key;
+
+        // Other changes leave keys alone
+        user.first_name = String::from("Alice");
+        database.update_user(&user).unwrap();
+        assert!(database.get_caller(&key, Some(ApiKeyScope::ReadOnly)).unwrap().is_some());
+
+        user.change_password(&database, &database.hash(&String::from("new password")));
+        database.update_user(&user).unwrap();
+
+        assert!(database.get_caller(&key, Some(ApiKeyScope::ReadOnly)).unwrap().is_none());
+        assert!(database
+            .get_api_keys(&user.access_token)
+            .unwrap()
+            .iter()
+            .all(|x| x.revoked_at.is_some()));
+    }
+
+    #[test]
+    fn password_resets_revoke_api_keys() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        let key = database
+            .create_api_key(&user.access_token, "read", ApiKeyScope::ReadOnly, None)
+            .unwrap()
+            .key;
+
+        let token = database
+            .request_password_reset(&user.email, Duration::minutes(30))
+            .unwrap()
+            .unwrap();
+        let access_token = database
+            .confirm_password_reset(&token, &String::from("new password"))
+            .unwrap()
+            .unwrap();
+
+        assert_ne!(access_token, user.access_token);
+        assert!(database.get_caller(&key, Some(ApiKeyScope::ReadOnly)).unwrap().is_none());
+    }
+
+    #[test]
+    fn period_spending_counts_each_day_once() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        // Anchored on the 31st so that shorter months start on their last day
+        let mut budget = Budget::new(String::from("Monthly"), 500.0, 30, String::from("2020-01-31"));
+        budget.period_rule = PeriodRule::Months { count: 1, anchor_day: 31 };
+        let budget = database.add_budget(&user.access_token, &budget).unwrap();
+        let budget_id = budget.budget_id.unwrap();
+
+        let period = database.budget_period(&budget, 1).unwrap().unwrap();
+        let first_day = from_sqlite_date(&period.start_date).unwrap();
+        let last_day = from_sqlite_date(&period.end_date).unwrap();
+
+        // Amounts are powers of two so each total shows exactly which transactions it counted
+        let dates = [
+            ((first_day - Duration::days(1)).and_hms_milli(23, 59, 59, 999), 1.0),
+            (first_day.and_hms(0, 0, 0), 2.0),
+            (last_day.and_hms(0, 0, 0), 4.0),
+            (last_day.and_hms_milli(23, 59, 59, 999), 8.0),
+            ((last_day + Duration::days(1)).and_hms(0, 0, 0), 16.0),
+        ];
+
+        for (date, amount) in dates.iter() {
+            let mut transaction = Transaction::new(budget_id, String::from("Item"), String::new(), *amount, Some(0), None);
+            transaction.date = Some(to_sqlite_date_time(date));
+            database.add_transaction(&user.access_token, &transaction).unwrap();
+        }
+
+        let spent = |period_id| {
+            database
+                .get_budget_period_amount_spent(&user.access_token, budget_id, period_id)
+                .unwrap()
+        };
+
+        assert_eq!(spent(0), 1.0);
+        assert_eq!(spent(1), 2.0 + 4.0 + 8.0);
+        assert_eq!(spent(2), 16.0);
+    }
+
+    #[test]
+    fn reimporting_a_statement_finds_duplicates_by_fitid() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let budget_id = add_budget(&database, &user).budget_id.unwrap();
+
+        let statement = "<OFX>
+<STMTTRN><DTPOSTED>20200131<TRNAMT>-12.50<FITID>1001<NAME>Corner Shop
+<STMTTRN><DTPOSTED>20200131<TRNAMT>-12.50<FITID>1002<NAME>Corner Shop
+<STMTTRN><DTPOSTED>20200201<TRNAMT>-3.00<FITID>1002<NAME>Repeated FITID
+</OFX>";
+        let rows = || parse_ofx(statement, TransactionKind::Refund).unwrap();
+
+        // Dry runs classify rows the same way but write nothing
+        let preview = database.import_transactions(&user.access_token, budget_id, rows(), true).unwrap();
+        assert_eq!((preview.new_entries, preview.duplicates), (2, 1));
+        let page = database
+            .get_budget_transactions(&user.access_token, budget_id, &TransactionQuery::default())
+            .unwrap();
+        assert_eq!(page.total_count, 0);
+
+        let first = database.import_transactions(&user.access_token, budget_id, rows(), false).unwrap();
+
+        // Same day and amount but a different FITID is a different transaction
+        assert_eq!(first.rows[0].status, ImportStatus::New);
+        assert_eq!(first.rows[1].status, ImportStatus::New);
+        assert_eq!(first.rows[2].status, ImportStatus::Duplicate);
+        assert_eq!(first.rows[2].reason, Some(String::from("reference appears earlier in the statement")));
+
+        let second = database.import_transactions(&user.access_token, budget_id, rows(), false).unwrap();
+
+        assert_eq!((second.new_entries, second.duplicates), (0, 3));
+
+        for (before, after) in first.rows.iter().zip(second.rows.iter()).take(2) {
+            assert_eq!(after.status, ImportStatus::Duplicate);
+            assert_eq!(after.duplicate_of, before.transaction_id);
+        }
+
+        let page = database
+            .get_budget_transactions(&user.access_token, budget_id, &TransactionQuery::default())
+            .unwrap();
+        assert_eq!(page.total_count, 2);
+    }
+
+    fn sso_claims(email: &str) -> IdTokenClaims {
+        IdTokenClaims {
+            issuer: String::from("https://id.example.com"),
+            subject: String::from("subject-1"),
+            email: Some(email.to_string()),
+            email_verified: true,
+            given_name: None,
+            family_name: None,
+        }
+    }
+
+    #[test]
+    fn sso_doesnt_link_unverified_accounts() {
+        let database = test_database();
+
+        // Someone registers an account under the victim's address but can't verify it
+        let mut squatter = User::new(
+            &database,
+            &String::from("victim@example.com"),
+            &String::from("Not"),
+            &String::from("Victim"),
+            &String::from("password"),
+            false,
+        );
+        squatter.email_verified = false;
+        database.insert_user(&squatter).unwrap();
+
+        let claims = sso_claims("victim@example.com");
+
+        match database.login_with_external_identity(&claims, true, true, RegistrationMode::Open) {
+            Err(Error::NoLinkedAccount) => (),
+            x => panic!("unverified account was signed in: {:?}", x.map(|x| x.email)),
+        }
+
+        // Nothing was linked, so later sign ins don't reach the account either
+        match database.login_with_external_identity(&claims, true, true, RegistrationMode::Open) {
+            Err(Error::NoLinkedAccount) => (),
+            x => panic!("unverified account was signed in: {:?}", x.map(|x| x.email)),
+        }
+    }
+
+    #[test]
+    fn sso_links_verified_accounts_only_when_enabled() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+        let claims = sso_claims("alice@example.com");
+
+        match database.login_with_external_identity(&claims, false, true, RegistrationMode::Open) {
+            Err(Error::NoLinkedAccount) => (),
+            x => panic!("account was linked without link_existing_users: {:?}", x.map(|x| x.email)),
+        }
+
+        let linked = database
+            .login_with_external_identity(&claims, true, false, RegistrationMode::Open)
+            .unwrap();
+
+        assert_eq!(linked.email, user.email);
+
+        // Once linked the identity signs in even with linking turned off
+        let again = database
+            .login_with_external_identity(&claims, false, false, RegistrationMode::Open)
+            .unwrap();
+
+        assert_eq!(again.email, user.email);
+    }
+
+    #[test]
+    fn sso_links_from_a_signed_in_session() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        // The provider's email doesn't need to match when the user links it themselves
+        let claims = sso_claims("alice.work@example.com");
+
+        database.link_external_identity(&user.access_token, &claims).unwrap();
+
+        let linked = database
+            .login_with_external_identity(&claims, false, false, RegistrationMode::Open)
+            .unwrap();
+
+        assert_eq!(linked.email, user.email);
+    }
+
+    /// Adds a verified user and makes them an admin
+    fn add_admin(database: &Database, email: &str) -> User {
+        let mut admin = add_user(database, email);
+        admin.is_admin = true;
+
+        database.update_user(&admin).unwrap();
+
+        admin
+    }
+
+    #[test]
+    fn registration_follows_the_mode() {
+        let database = test_database();
+
+        database
+            .register_user(&mut new_user(&database, "alice@example.com"), RegistrationMode::Open, None)
+            .unwrap();
+        assert!(database.get_user_by_email("alice@example.com").unwrap().unwrap().approved);
+
+        match database.register_user(&mut new_user(&database, "bob@example.com"), RegistrationMode::Disabled, None) {
+            Err(Error::RegistrationDisabled) => (),
+            x => panic!("registered while disabled: {:?}", x),
+        }
+        assert!(database.get_user_by_email("bob@example.com").unwrap().is_none());
+    }
+
+    #[test]
+    fn invite_codes_are_used_once() {
+        let database = test_database();
+        let admin = add_admin(&database, "admin@example.com");
+        let invite = database.create_invite_code(&admin.access_token, Duration::days(7)).unwrap();
+
+        for code in [None, Some("not-a-code")].iter() {
+            let mut user = new_user(&database, "alice@example.com");
+
+            match database.register_user(&mut user, RegistrationMode::InviteOnly, *code) {
+                Err(Error::InvalidInviteCode) => (),
+                x => panic!("registered with invite code {:?}: {:?}", code, x),
+            }
+        }
+        assert!(database.get_user_by_email("alice@example.com").unwrap().is_none());
+
+        let mut alice = new_user(&database, "alice@example.com");
+        database
+            .register_user(&mut alice, RegistrationMode::InviteOnly, Some(&invite.code))
+            .unwrap();
+
+        let mut bob = new_user(&database, "bob@example.com");
+        match database.register_user(&mut bob, RegistrationMode::InviteOnly, Some(&invite.code)) {
+            Err(Error::InvalidInviteCode) => (),
+            x => panic!("invite code used twice: {:?}", x),
+        }
+        assert!(database.get_user_by_email(&bob.email).unwrap().is_none());
+
+        // Other modes ignore the code entirely
+        database
+            .register_user(&mut bob, RegistrationMode::Open, Some(&invite.code))
+            .unwrap();
+    }
+
+    #[test]
+    fn invite_codes_expire() {
+        let database = test_database();
+        let admin = add_admin(&database, "admin@example.com");
+        let invite = database.create_invite_code(&admin.access_token, Duration::seconds(-1)).unwrap();
+
+        let mut user = new_user(&database, "alice@example.com");
+        match database.register_user(&mut user, RegistrationMode::InviteOnly, Some(&invite.code)) {
+            Err(Error::InvalidInviteCode) => (),
+            x => panic!("registered with an expired invite code: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn only_admins_manage_registrations() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        assert!(database.create_invite_code(&user.access_token, Duration::days(7)).is_err());
+        assert!(database.get_pending_users(&user.access_token).is_err());
+        assert!(database.approve_user(&user.access_token, &user.email).is_err());
+        assert!(database.reject_user(&user.access_token, &user.email).is_err());
+    }
+
+    #[test]
+    fn approval_gates_sign_in() {
+        let database = test_database();
+        let admin = add_admin(&database, "admin@example.com");
+
+        let mut alice = new_user(&database, "alice@example.com");
+        let mut bob = new_user(&database, "bob@example.com");
+        for user in [&mut alice, &mut bob].iter_mut() {
+            database.register_user(user, RegistrationMode::AdminApproval, None).unwrap();
+            assert!(database.get_user_by_access_token(&user.access_token).unwrap().is_none());
+        }
+
+        let pending = database.get_pending_users(&admin.access_token).unwrap();
+        let emails: Vec<&str> = pending.iter().map(|x| x.email.as_str()).collect();
+        assert_eq!(emails, vec!["alice@example.com", "bob@example.com"]);
+
+        database.approve_user(&admin.access_token, &alice.email).unwrap();
+        assert!(database.get_user_by_access_token(&alice.access_token).unwrap().is_some());
+
+        // Approved accounts are no longer pending, so they can't be rejected
+        assert!(database.reject_user(&admin.access_token, &alice.email).is_err());
+
+        database.reject_user(&admin.access_token, &bob.email).unwrap();
+        assert!(database.get_user_by_email(&bob.email).unwrap().is_none());
+        assert!(database.get_pending_users(&admin.access_token).unwrap().is_empty());
+
+        // The rejected email can register again
+        database
+            .register_user(&mut new_user(&database, &bob.email), RegistrationMode::Open, None)
+            .unwrap();
+    }
+
+    fn assert_login(database: &Database, email: &str, expected: Option<Error>) {
+        match (database.check_login_allowed(email), expected) {
+            (Ok(()), None) => (),
+            (Err(Error::TooManyAttempts), Some(Error::TooManyAttempts)) => (),
+            (Err(Error::AccountLocked), Some(Error::AccountLocked)) => (),
+            (x, expected) => panic!("login for {} gave {:?}, expected {:?}", email, x, expected),
+        }
+    }
+
+    #[test]
+    fn failed_logins_back_off_then_lock() {
+        let database = test_database();
+        let admin = add_admin(&database, "admin@example.com");
+        let user = add_user(&database, "alice@example.com");
+        let lockout = Duration::minutes(15);
+
+        // The first few failures don't slow anyone down
+        for _ in 0..2 {
+            database.record_login_failure(&user.email, 5, lockout).unwrap();
+            assert_login(&database, &user.email, None);
+        }
+
+        database.record_login_failure(&user.email, 5, lockout).unwrap();
+        assert_login(&database, &user.email, Some(Error::TooManyAttempts));
+
+        // A successful login starts the count again
+        database.clear_login_failures(&user.email).unwrap();
+        assert_login(&database, &user.email, None);
+
+        for _ in 0..5 {
+            database.record_login_failure(&user.email, 5, lockout).unwrap();
+        }
+        assert_login(&database, &user.email, Some(Error::AccountLocked));
+
+        let locked = database.get_locked_accounts(&admin.access_token).unwrap();
+        assert_eq!(locked.len(), 1);
+        assert_eq!(locked[0].email, user.email);
+
+        assert!(database.get_locked_accounts(&user.access_token).is_err());
+        assert!(database.unlock_account(&user.access_token, &user.email).is_err());
+
+        database.unlock_account(&admin.access_token, &user.email).unwrap();
+        assert_login(&database, &user.email, None);
+        assert!(database.get_locked_accounts(&admin.access_token).unwrap().is_empty());
+
+        match database.unlock_account(&admin.access_token, &user.email) {
+            Err(Error::EntryNotFound) => (),
+            x => panic!("unlocked an account that wasn't locked: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn unknown_emails_lock_like_accounts() {
+        let database = test_database();
+        let admin = add_admin(&database, "admin@example.com");
+        let email = "nobody@example.com";
+
+        for _ in 0..5 {
+            database.record_login_failure(email, 5, Duration::minutes(15)).unwrap();
+        }
+        assert_login(&database, email, Some(Error::AccountLocked));
+
+        // Admins only see real accounts
+        assert!(database.get_locked_accounts(&admin.access_token).unwrap().is_empty());
+    }
+
+    #[test]
+    fn account_locks_expire() {
+        let database = test_database();
+        let user = add_user(&database, "alice@example.com");
+
+        for _ in 0..5 {
+            database.record_login_failure(&user.email, 5, Duration::seconds(-1)).unwrap();
+        }
+
+        // The failures were reset when the account locked
+        assert_login(&database, &user.email, None);
     }
 }
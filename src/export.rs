@@ -0,0 +1,441 @@
+use serde::{Deserialize, Serialize};
+
+use bytes::Bytes;
+
+use crate::budget::Budget;
+use crate::budget_period::BudgetPeriod;
+use crate::database::Error;
+use crate::transaction::Transaction;
+use crate::util::*;
+
+/// Number of transactions read from the database for each chunk of an export
+pub const EXPORT_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Csv,
+    /// CSV that spreadsheet programs such as Excel open correctly, with a byte
+    /// order mark, CRLF line endings and cells that look like formulas escaped
+    Excel,
+    Json
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            ExportFormat::Csv | ExportFormat::Excel => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ExportFormat::Csv | ExportFormat::Excel => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+/// Everything about an exported budget except its transactions
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BudgetExport {
+    pub budget: Budget,
+    pub period: Option<BudgetPeriod>, // Set when only one period is exported
+    pub exported_at: String
+}
+
+impl BudgetExport {
+    /// Suggested file name, made up of the budget name and the period start date
+    pub fn file_name(&self, format: ExportFormat) -> String {
+        let name: String = self
+            .budget
+            .name
+            .chars()
+            .map(|x| if x.is_ascii_alphanumeric() || x == '-' { x } else { '_' })
+            .collect();
+
+        match &self.period {
+            Some(period) => format!("{}-{}.{}", name, period.start_date, format.extension()),
+            None => format!("{}.{}", name, format.extension()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedTransaction<'a> {
+    #[serde(flatten)]
+    transaction: &'a Transaction,
+    period_id: Option<i64>,
+    period_start: Option<String>,
+    period_end: Option<String>
+}
+
+const CSV_HEADER: &[&str] = &[
+    "budget_id", "budget_name", "transaction_id", "date", "period_id", "period_start", "period_end",
+    "name", "description", "kind", "amount", "email", "recur_days", "recur_until", "external_id",
+];
+
+enum ExportState {
+    Header,
+    Rows,
+    Done
+}
+
+/// Produces an export a chunk at a time so that large histories never have
+/// to be held in memory
+///
+/// `fetch` is given the ID of the last exported transaction, or None for the
+/// first page, and returns the next page of transactions ordered by ID.
+pub struct ExportChunks<F> {
+    format: ExportFormat,
+    export: BudgetExport,
+    fetch: F,
+    after_id: Option<i64>,
+    state: ExportState
+}
+
+impl<F> ExportChunks<F>
+where
+    F: FnMut(Option<i64>) -> Result<Vec<Transaction>, Error>,
+{
+    pub fn new(format: ExportFormat, export: BudgetExport, fetch: F) -> ExportChunks<F> {
+        ExportChunks {
+            format,
+            export,
+            fetch,
+            after_id: None,
+            state: ExportState::Header
+        }
+    }
+
+    fn header(&self) -> Result<Vec<u8>, Error> {
+        match self.format {
+            ExportFormat::Csv => self.csv_records(&[CSV_HEADER.iter().map(|x| x.to_string()).collect()]),
+            ExportFormat::Excel => {
+                let mut result = "\u{feff}".as_bytes().to_vec();
+                result.extend(self.csv_records(&[CSV_HEADER.iter().map(|x| x.to_string()).collect()])?);
+
+                Ok(result)
+            }
+            ExportFormat::Json => {
+                let metadata = serde_json::to_string(&self.export).map_err(|_| Error::UnknownError)?;
+
+                // Leave the metadata object open so that transactions can follow
+                let mut result = metadata[..metadata.len() - 1].as_bytes().to_vec();
+                result.extend(b",\"transactions\":[");
+
+                Ok(result)
+            }
+        }
+    }
+
+    fn rows(&self, transactions: &[Transaction]) -> Result<Vec<u8>, Error> {
+        match self.format {
+            ExportFormat::Csv | ExportFormat::Excel => {
+                let records: Vec<Vec<String>> = transactions.iter().map(|x| self.csv_row(x)).collect();
+
+                self.csv_records(&records)
+            }
+            ExportFormat::Json => {
+                let mut result: Vec<u8> = Vec::new();
+
+                for transaction in transactions {
+                    let (period_id, period_start, period_end) = self.transaction_period(transaction);
+
+                    // Every page but the first follows an earlier transaction
+                    if !result.is_empty() || self.after_id.is_some() {
+                        result.push(b',');
+                    }
+
+                    let exported = ExportedTransaction {
+                        transaction,
+                        period_id,
+                        period_start,
+                        period_end,
+                    };
+
+                    result.extend(serde_json::to_vec(&exported).map_err(|_| Error::UnknownError)?);
+                }
+
+                Ok(result)
+            }
+        }
+    }
+
+    fn footer(&self) -> Vec<u8> {
+        match self.format {
+            ExportFormat::Csv | ExportFormat::Excel => Vec::new(),
+            ExportFormat::Json => b"]}".to_vec(),
+        }
+    }
+
+    fn csv_records(&self, records: &[Vec<String>]) -> Result<Vec<u8>, Error> {
+        let terminator = match self.format {
+            ExportFormat::Excel => csv::Terminator::CRLF,
+            _ => csv::Terminator::Any(b'\n'),
+        };
+
+        let mut writer = csv::WriterBuilder::new().terminator(terminator).from_writer(Vec::new());
+
+        for record in records {
+            writer.write_record(record).map_err(|_| Error::UnknownError)?;
+        }
+
+        writer.into_inner().map_err(|_| Error::UnknownError)
+    }
+
+    fn csv_row(&self, transaction: &Transaction) -> Vec<String> {
+        let (period_id, period_start, period_end) = self.transaction_period(transaction);
+
+        let text = |x: &str| match self.format {
+            ExportFormat::Excel => escape_formula(x),
+            _ => x.to_string(),
+        };
+
+        vec![
+            self.export.budget.budget_id.map(|x| x.to_string()).unwrap_or_default(),
+            text(&self.export.budget.name),
+            transaction.transaction_id.map(|x| x.to_string()).unwrap_or_default(),
+            transaction.date.clone().unwrap_or_default(),
+            period_id.map(|x| x.to_string()).unwrap_or_default(),
+            period_start.unwrap_or_default(),
+            period_end.unwrap_or_default(),
+            text(&transaction.name),
+            text(&transaction.description),
+            transaction.kind.as_str().to_string(),
+            format!("{:.2}", transaction.amount),
+            text(transaction.email.as_ref().map(|x| x.as_str()).unwrap_or("")),
+            transaction.recur_days.map(|x| x.to_string()).unwrap_or_default(),
+            transaction.recur_until.clone().unwrap_or_default(),
+            text(transaction.external_id.as_ref().map(|x| x.as_str()).unwrap_or("")),
+        ]
+    }
+
+    /// Period ID and inclusive bounds of the period a transaction falls in
+    fn transaction_period(&self, transaction: &Transaction) -> (Option<i64>, Option<String>, Option<String>) {
+        if let Some(period) = &self.export.period {
+            return (
+                Some(period.period_id),
+                Some(period.start_date.clone()),
+                Some(period.end_date.clone()),
+            );
+        }
+
+        let budget = &self.export.budget;
+
+        let dates = (
+            from_sqlite_date(&budget.start_date),
+            transaction.date.as_ref().map(|x| from_sqlite_date_time(x)),
+        );
+
+        match dates {
+            (Ok(start_date), Some(Ok(date))) => {
+                let period_id = match budget.period_rule.period_containing(start_date, date.date()) {
+                    Some(x) if x >= 0 => x,
+                    _ => return (None, None, None),
+                };
+
+                match budget.period_rule.period_bounds(start_date, period_id) {
+                    Some((start, end)) => {
+                        (Some(period_id), Some(to_sqlite_date(&start)), Some(to_sqlite_date(&end)))
+                    }
+                    None => (None, None, None),
+                }
+            }
+            _ => (None, None, None),
+        }
+    }
+}
+
+impl<F> Iterator for ExportChunks<F>
+where
+    F: FnMut(Option<i64>) -> Result<Vec<Transaction>, Error>,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn next(&mut self) -> Option<Result<Bytes, Error>> {
+        let chunk = match self.state {
+            ExportState::Header => {
+                self.state = ExportState::Rows;
+                self.header()
+            }
+            ExportState::Rows => match (self.fetch)(self.after_id) {
+                Ok(ref transactions) if transactions.is_empty() => {
+                    self.state = ExportState::Done;
+                    Ok(self.footer())
+                }
+                Ok(transactions) => {
+                    let rows = self.rows(&transactions);
+                    self.after_id = transactions.last().and_then(|x| x.transaction_id);
+                    rows
+                }
+                Err(error) => {
+                    self.state = ExportState::Done;
+                    Err(error)
+                }
+            },
+            ExportState::Done => return None,
+        };
+
+        Some(chunk.map(Bytes::from))
+    }
+}
+
+/// Stops spreadsheet programs from treating text as a formula, including
+/// formulas hidden behind a leading tab or carriage return
+fn escape_formula(text: &str) -> String {
+    if text.starts_with(|x| x == '=' || x == '+' || x == '-' || x == '@' || x == '\t' || x == '\r') {
+        format!("'{}", text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget_export(name: &str, period: Option<BudgetPeriod>) -> BudgetExport {
+        let mut budget = Budget::new(name.to_string(), 100.0, 7, String::from("2020-01-01"));
+        budget.budget_id = Some(1);
+
+        BudgetExport {
+            budget,
+            period,
+            exported_at: String::from("2020-02-01 00:00:00.000"),
+        }
+    }
+
+    fn transactions(count: i64) -> Vec<Transaction> {
+        (1..=count)
+            .map(|i| {
+                let mut transaction = Transaction::new(1, format!("Item {}", i), String::new(), i as f64, Some(0), None);
+                transaction.transaction_id = Some(i);
+                transaction.date = Some(String::from("2020-01-09 12:00:00.000"));
+                transaction
+            })
+            .collect()
+    }
+
+    /// Runs an export whose transactions are fetched `page_size` at a time
+    fn export(format: ExportFormat, name: &str, transactions: Vec<Transaction>, page_size: usize) -> Vec<u8> {
+        let fetch = move |after_id: Option<i64>| {
+            Ok(transactions
+                .iter()
+                .filter(|x| after_id.map_or(true, |id| x.transaction_id.unwrap() > id))
+                .take(page_size)
+                .cloned()
+                .collect())
+        };
+
+        ExportChunks::new(format, budget_export(name, None), fetch)
+            .map(|x| x.unwrap().to_vec())
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn json_pages_join_into_one_document() {
+        for count in 0..=5 {
+            let output = export(ExportFormat::Json, "Food", transactions(count), 2);
+            let document: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+            assert_eq!(document["budget"]["name"], "Food");
+
+            let exported = document["transactions"].as_array().unwrap();
+            assert_eq!(exported.len() as i64, count);
+
+            for (i, transaction) in exported.iter().enumerate() {
+                assert_eq!(transaction["transaction_id"], i as i64 + 1);
+                // Weekly periods from 2020-01-01, so the 9th is in the second
+                assert_eq!(transaction["period_id"], 1);
+                assert_eq!(transaction["period_start"], "2020-01-08");
+            }
+        }
+    }
+
+    #[test]
+    fn fetch_errors_end_the_export() {
+        let mut chunks = ExportChunks::new(ExportFormat::Csv, budget_export("Food", None), |_| {
+            Err(Error::UnknownError)
+        });
+
+        assert!(chunks.next().unwrap().is_ok());
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn csv_lines_end_with_line_feeds() {
+        let output = String::from_utf8(export(ExportFormat::Csv, "Food", transactions(3), 2)).unwrap();
+
+        assert!(output.starts_with("budget_id,budget_name,"));
+        assert!(!output.contains('\r'));
+        assert_eq!(output.lines().count(), 4);
+        assert!(output.contains("\n1,Food,2,2020-01-09 12:00:00.000,1,2020-01-08,2020-01-14,Item 2,,expense,2.00,"));
+    }
+
+    #[test]
+    fn excel_output_has_a_bom_and_crlf_line_endings() {
+        let output = String::from_utf8(export(ExportFormat::Excel, "Food", transactions(3), 2)).unwrap();
+
+        assert!(output.starts_with("\u{feff}budget_id,budget_name,"));
+        assert_eq!(output.matches("\r\n").count(), 4);
+        assert_eq!(output.matches('\n').count(), 4);
+    }
+
+    #[test]
+    fn excel_output_escapes_formulas() {
+        let mut transactions = transactions(1);
+        transactions[0].name = String::from("=HYPERLINK(\"http://example.com\")");
+        transactions[0].description = String::from("\t=1+1");
+
+        let output = String::from_utf8(export(ExportFormat::Excel, "@Food", transactions.clone(), 2)).unwrap();
+
+        assert!(output.contains(",'@Food,"));
+        assert!(output.contains(",\"'=HYPERLINK(\"\"http://example.com\"\")\","));
+        assert!(output.contains(",'\t=1+1,"));
+
+        // Plain CSV is left as it is
+        let output = String::from_utf8(export(ExportFormat::Csv, "@Food", transactions, 2)).unwrap();
+
+        assert!(output.contains(",@Food,"));
+    }
+
+    #[test]
+    fn formulas_are_escaped() {
+        let cases = [
+            ("=1+1", "'=1+1"),
+            ("+1", "'+1"),
+            ("-1", "'-1"),
+            ("@SUM(A1)", "'@SUM(A1)"),
+            ("\t=1+1", "'\t=1+1"),
+            ("\r=1+1", "'\r=1+1"),
+            ("Groceries", "Groceries"),
+            ("1+1", "1+1"),
+            ("", ""),
+        ];
+
+        for (text, escaped) in cases.iter() {
+            assert_eq!(escape_formula(text), *escaped);
+        }
+    }
+
+    #[test]
+    fn file_names_are_sanitised() {
+        let period = BudgetPeriod::new(2, String::from("2020-01-15"), String::from("2020-01-21"));
+
+        let cases = [
+            ("Food", None, ExportFormat::Csv, "Food.csv"),
+            ("Food & Drink", None, ExportFormat::Json, "Food___Drink.json"),
+            ("../../etc/passwd", None, ExportFormat::Csv, "______etc_passwd.csv"),
+            ("Café \"2020\"", None, ExportFormat::Excel, "Caf___2020_.csv"),
+            ("Food", Some(period), ExportFormat::Csv, "Food-2020-01-15.csv"),
+        ];
+
+        for (name, period, format, file_name) in cases.iter() {
+            assert_eq!(budget_export(name, period.clone()).file_name(*format), *file_name);
+        }
+    }
+}
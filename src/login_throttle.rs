@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Failed logins allowed before delays start
+const FREE_ATTEMPTS: u32 = 3;
+/// Delay after the first failure past the free attempts, doubled for each one after
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+/// Failures from an address are forgotten after this long without another one
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Time to wait after the given number of consecutive failures before trying again
+pub fn backoff_delay(failures: u32) -> Duration {
+    if failures < FREE_ATTEMPTS {
+        return Duration::from_secs(0);
+    }
+
+    // Capping the exponent avoids overflow, the delay is capped well before it
+    let exponent = (failures - FREE_ATTEMPTS).min(16);
+
+    (BASE_DELAY * 2u32.pow(exponent)).min(MAX_DELAY)
+}
+
+struct Failures {
+    count: u32,
+    last: Instant
+}
+
+/// Slows down repeated failed logins from the same IP address
+///
+/// Kept in memory, so restarting the server forgets every address. Accounts
+/// are throttled and locked separately in the database. Other keys, such as
+/// emails, can be throttled the same way.
+pub struct LoginThrottle<K = IpAddr> {
+    failures: HashMap<K, Failures>
+}
+
+impl<K: Hash + Eq> LoginThrottle<K> {
+    pub fn new() -> LoginThrottle<K> {
+        LoginThrottle {
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Time the key has to wait before its next attempt, `None` when it may try now
+    pub fn retry_after(&self, key: &K, now: Instant) -> Option<Duration> {
+        let failures = self.failures.get(key)?;
+
+        let ready_at = failures.last + backoff_delay(failures.count);
+
+        if ready_at > now {
+            Some(ready_at - now)
+        } else {
+            None
+        }
+    }
+
+    pub fn record_failure(&mut self, key: K, now: Instant) {
+        // Stops the map growing without bound under a distributed attack
+        self.failures.retain(|_, x| now.duration_since(x.last) < FORGET_AFTER);
+
+        let failures = self.failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+        });
+
+        failures.count += 1;
+        failures.last = now;
+    }
+
+    pub fn record_success(&mut self, key: &K) {
+        self.failures.remove(key);
+    }
+}
+
+/// An account that can't sign in until `locked_until` after too many failed logins
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockedAccount {
+    pub email: String,
+    pub locked_until: String,
+    pub last_failure_at: String
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        for failures in 0..FREE_ATTEMPTS {
+            assert_eq!(backoff_delay(failures), Duration::from_secs(0));
+        }
+
+        assert_eq!(backoff_delay(3), Duration::from_secs(1));
+        assert_eq!(backoff_delay(4), Duration::from_secs(2));
+        assert_eq!(backoff_delay(8), Duration::from_secs(32));
+        assert_eq!(backoff_delay(13), MAX_DELAY);
+        assert_eq!(backoff_delay(u32::MAX), MAX_DELAY);
+    }
+
+    #[test]
+    fn throttles_each_key_separately() {
+        let mut throttle = LoginThrottle::new();
+        let now = Instant::now();
+
+        for _ in 0..FREE_ATTEMPTS {
+            assert_eq!(throttle.retry_after(&"alice", now), None);
+            throttle.record_failure("alice", now);
+        }
+
+        assert_eq!(throttle.retry_after(&"alice", now), Some(BASE_DELAY));
+        assert_eq!(throttle.retry_after(&"alice", now + BASE_DELAY), None);
+        assert_eq!(throttle.retry_after(&"bob", now), None);
+
+        throttle.record_success(&"alice");
+        assert_eq!(throttle.retry_after(&"alice", now), None);
+    }
+
+    #[test]
+    fn forgets_old_failures() {
+        let mut throttle = LoginThrottle::new();
+        let now = Instant::now();
+
+        for _ in 0..FREE_ATTEMPTS {
+            throttle.record_failure("alice", now);
+        }
+
+        // Another key's failure clears out keys that have been quiet long enough
+        throttle.record_failure("bob", now + FORGET_AFTER);
+        assert_eq!(throttle.failures.len(), 1);
+        assert_eq!(throttle.retry_after(&"alice", now), None);
+    }
+}
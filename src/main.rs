@@ -6,29 +6,92 @@ mod database;
 mod shared;
 mod budget;
 mod transaction;
+mod transaction_query;
+mod transaction_search_hit;
 mod can_access_budget;
 mod budget_period;
+mod period_rule;
+mod rollover_policy;
+mod budget_period_balance;
+mod budget_period_summary;
+mod audit_entry;
+mod backup;
+mod account_archive;
+mod export;
+mod statement_import;
+mod csv_import;
+mod ofx_import;
+mod qif_import;
 mod api;
 mod util;
 mod config;
+mod cli;
+mod notifier;
+mod registration;
+mod totp;
+mod user_token;
+mod login_throttle;
+mod oidc;
+mod api_key;
 
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 
 use database::*;
 use config::Config;
+use login_throttle::LoginThrottle;
+use notifier::{Message, Notifier};
+use oidc::OidcClient;
 
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::{self, Sender};
 use std::sync::{Mutex};
+use std::thread;
+use std::time::Duration;
 
 // Constants
 const DB_PATH: &str = "budget.db";
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 // Shares database connection with all web server workers
 struct AppState {
-    database: Mutex<Database>
+    database: Mutex<Database>,
+    config: Config,
+    notifier: Box<dyn Notifier>,
+    login_throttle: Mutex<LoginThrottle>,
+    // Password reset requests, throttled separately from logins by address and by email
+    reset_ip_throttle: Mutex<LoginThrottle>,
+    reset_email_throttle: Mutex<LoginThrottle<String>>,
+    // Messages sent in the background, so that responses don't wait on the mail server
+    outbox: Mutex<Sender<Message>>,
+    // Set when single sign-on is configured
+    oidc: Option<OidcClient>
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let invocation = match cli::Invocation::parse(&args) {
+        Ok(x) => x,
+        Err(message) => {
+            println!("Error: {}\n\n{}", message, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    match invocation.command {
+        cli::Command::Serve => serve(),
+        _ => {
+            if let Err(message) = cli::run(&invocation) {
+                println!("Error: {}", message);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn serve() {
     println!("Budget Tracker server starting...");
 
     // Load config
@@ -36,17 +99,81 @@ fn main() {
     let config = Config::load();
 
     println!("Loading database...");
-    let database = match Database::new(config.secret.clone(), DB_PATH) {
+    let database = match cli::open_database(&config) {
         Ok(database) => database,
-        Err(err) => {
-            panic!("Error occurred while loading database: {:?}", err);
-        }
+        Err(message) => panic!("Error occurred while loading database: {}", message)
+    };
+
+    // First run, the admin account is created interactively
+    if let Err(message) = cli::setup_admin(&database, None, false) {
+        panic!("Error occurred while creating admin user: {}", message);
+    }
+
+    let oidc = match config.oidc.as_ref().map(OidcClient::new) {
+        None => None,
+        Some(Ok(x)) => Some(x),
+        Some(Err(message)) => panic!("Error occurred while setting up single sign-on: {}", message)
     };
 
+    let (outbox, outbox_receiver) = mpsc::channel::<Message>();
+
     let state = web::Data::new(AppState {
-        database: Mutex::new(database)
+        database: Mutex::new(database),
+        config: config.clone(),
+        notifier: config.notifier.build(),
+        login_throttle: Mutex::new(LoginThrottle::new()),
+        reset_ip_throttle: Mutex::new(LoginThrottle::new()),
+        reset_email_throttle: Mutex::new(LoginThrottle::new()),
+        outbox: Mutex::new(outbox),
+        oidc
     });
 
+    // Send queued messages one at a time
+    {
+        let state = state.clone();
+
+        thread::spawn(move || {
+            for message in outbox_receiver {
+                if let Err(error) = state.notifier.send(&message) {
+                    println!("Error occurred while sending message to {}: {}", message.to, error);
+                }
+            }
+        });
+    }
+
+    // Periodically purge transactions that have been in the trash for too long
+    {
+        let state = state.clone();
+        let retention_days = config.trash_retention_days;
+
+        thread::spawn(move || loop {
+            match state.database.lock().unwrap().purge_deleted_transactions(retention_days) {
+                Ok(0) => (),
+                Ok(count) => println!("Purged {} deleted transactions.", count),
+                Err(error) => println!("Error occurred while purging deleted transactions: {:?}", error)
+            }
+
+            thread::sleep(PURGE_INTERVAL);
+        });
+    }
+
+    // Periodically back up the database
+    if config.backup_interval_hours > 0 {
+        let state = state.clone();
+        let interval = Duration::from_secs(config.backup_interval_hours * 60 * 60);
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+
+            let dir = Path::new(&state.config.backup_dir);
+
+            match state.database.lock().unwrap().backup(SYSTEM_ACTOR, dir, state.config.backup_keep) {
+                Ok(path) => println!("Backed up database to \"{}\".", path.display()),
+                Err(error) => println!("Error occurred while backing up database: {:?}", error)
+            }
+        });
+    }
+
     println!("Loading SSL keys...");
     let mut builder =
         SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+
+/// A message for a user, such as a password reset link
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String
+}
+
+impl Message {
+    /// Message carrying a password reset token, see `Database::request_password_reset`
+    pub fn password_reset(to: &str, token: &str, lifetime_minutes: i64) -> Message {
+        Message {
+            to: to.to_string(),
+            subject: String::from("Budget Tracker password reset"),
+            body: format!(
+                "A password reset was requested for your Budget Tracker account.\n\n\
+                Your reset code is: {}\n\n\
+                The code expires in {} minutes. If you didn't request a reset you can ignore this message.",
+                token, lifetime_minutes
+            ),
+        }
+    }
+}
+
+/// Delivers messages to users
+pub trait Notifier: Send + Sync {
+    fn send(&self, message: &Message) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Unencrypted, only for local test servers
+    Plain,
+    /// Upgraded to TLS with STARTTLS, usually port 587
+    StartTls,
+    /// TLS from the start, usually port 465
+    Tls
+}
+
+/// How messages are delivered, see `Config`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierConfig {
+    /// Printed to the server's output, for development and small deployments
+    Console,
+    /// Sent as email
+    Smtp {
+        host: String,
+        port: u16,
+        security: SmtpSecurity,
+        username: Option<String>,
+        password: Option<String>,
+        from: String
+    }
+}
+
+impl Default for NotifierConfig {
+    fn default() -> NotifierConfig {
+        NotifierConfig::Console
+    }
+}
+
+impl NotifierConfig {
+    pub fn build(&self) -> Box<dyn Notifier> {
+        match self {
+            NotifierConfig::Console => Box::new(ConsoleNotifier),
+            NotifierConfig::Smtp { .. } => Box::new(SmtpNotifier { config: self.clone() }),
+        }
+    }
+}
+
+pub struct ConsoleNotifier;
+
+impl Notifier for ConsoleNotifier {
+    fn send(&self, message: &Message) -> Result<(), String> {
+        println!("--- Message to {} ---\nSubject: {}\n\n{}\n---", message.to, message.subject, message.body);
+
+        Ok(())
+    }
+}
+
+pub struct SmtpNotifier {
+    config: NotifierConfig
+}
+
+impl Notifier for SmtpNotifier {
+    fn send(&self, message: &Message) -> Result<(), String> {
+        let (host, port, security, username, password, from) = match &self.config {
+            NotifierConfig::Smtp { host, port, security, username, password, from } => {
+                (host, *port, *security, username, password, from)
+            }
+            _ => return Err(String::from("not an SMTP config")),
+        };
+
+        let email = EmailBuilder::new()
+            .to(message.to.as_str())
+            .from(from.as_str())
+            .subject(message.subject.as_str())
+            .text(message.body.as_str())
+            .build()
+            .map_err(|error| error.to_string())?;
+
+        let tls = || -> Result<ClientTlsParameters, String> {
+            let connector = TlsConnector::new().map_err(|error| error.to_string())?;
+
+            Ok(ClientTlsParameters::new(host.clone(), connector))
+        };
+
+        let security = match security {
+            SmtpSecurity::Plain => ClientSecurity::None,
+            SmtpSecurity::StartTls => ClientSecurity::Required(tls()?),
+            SmtpSecurity::Tls => ClientSecurity::Wrapper(tls()?),
+        };
+
+        // A new connection is made for each message as transports can't be shared between threads
+        let mut client =
+            SmtpClient::new((host.as_str(), port), security).map_err(|error| format!("{:?}", error))?;
+
+        if let (Some(username), Some(password)) = (username, password) {
+            client = client.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        client
+            .transport()
+            .send(email.into())
+            .map(|_| ())
+            .map_err(|error| format!("{:?}", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Accepts one SMTP session on a loopback port and returns everything the
+    /// client sent, a stand-in for a real mail server
+    fn smtp_sink() -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received: Vec<String> = Vec::new();
+            let mut in_data = false;
+
+            writer.write_all(b"220 localhost SMTP sink\r\n").unwrap();
+
+            loop {
+                let mut line = String::new();
+
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+
+                let line = line.trim_end_matches(|x| x == '\r' || x == '\n').to_string();
+
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        writer.write_all(b"250 Queued\r\n").unwrap();
+                    } else {
+                        received.push(line);
+                    }
+
+                    continue;
+                }
+
+                let command = line.to_uppercase();
+                received.push(line);
+
+                if command.starts_with("EHLO") {
+                    writer.write_all(b"250-localhost\r\n250 8BITMIME\r\n").unwrap();
+                } else if command.starts_with("DATA") {
+                    in_data = true;
+                    writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").unwrap();
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    writer.write_all(b"250 OK\r\n").unwrap();
+                }
+            }
+
+            received
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn smtp_delivers_password_reset() {
+        let (port, sink) = smtp_sink();
+
+        let notifier = NotifierConfig::Smtp {
+            host: String::from("127.0.0.1"),
+            port,
+            security: SmtpSecurity::Plain,
+            username: None,
+            password: None,
+            from: String::from("budgets@example.com"),
+        }
+        .build();
+
+        let message = Message::password_reset("alice@example.com", "RESETTOKEN123", 30);
+
+        notifier.send(&message).unwrap();
+
+        let received = sink.join().unwrap();
+
+        assert!(received.iter().any(|x| x.contains("MAIL FROM:<budgets@example.com>")));
+        assert!(received.iter().any(|x| x.contains("RCPT TO:<alice@example.com>")));
+        assert!(received.iter().any(|x| x == "Subject: Budget Tracker password reset"));
+        assert!(received.iter().any(|x| x.contains("Your reset code is: RESETTOKEN123")));
+    }
+}
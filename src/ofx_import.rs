@@ -0,0 +1,256 @@
+use chrono::NaiveDate;
+
+use crate::database::Error;
+use crate::statement_import::{parse_amount, ImportRow, StatementEntry};
+use crate::transaction::TransactionKind;
+use crate::util::to_sqlite_date;
+
+/// Reads the transactions of an OFX or QFX statement, in either the older
+/// SGML form, where closing tags of values are optional, or the XML form
+///
+/// Each transaction keeps its FITID as the reference so that overlapping
+/// statements only import new entries.
+pub fn parse_ofx(text: &str, credit_kind: TransactionKind) -> Result<Vec<ImportRow>, Error> {
+    // Tag names are case insensitive, ASCII upper casing keeps byte offsets the same
+    let upper = text.to_ascii_uppercase();
+
+    if !upper.contains("<OFX>") {
+        return Err(Error::InvalidStatement(String::from("missing <OFX> element")));
+    }
+
+    let mut rows: Vec<ImportRow> = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = upper[offset..].find("<STMTTRN>") {
+        let start = offset + start;
+        let body_start = start + "<STMTTRN>".len();
+
+        let end = ["</STMTTRN>", "<STMTTRN>"]
+            .iter()
+            .filter_map(|x| upper[body_start..].find(x))
+            .min()
+            .map(|x| body_start + x)
+            .unwrap_or_else(|| text.len());
+
+        let line = text[..start].matches('\n').count() as u64 + 1;
+
+        rows.push(match parse_transaction(&parse_elements(&text[body_start..end]), credit_kind) {
+            Ok(entry) => ImportRow::parsed(line, entry),
+            Err(reason) => ImportRow::rejected(line, reason),
+        });
+
+        offset = end;
+    }
+
+    Ok(rows)
+}
+
+/// Collects the value of every element in an aggregate as upper case tag
+/// name and value pairs, nested aggregates are flattened
+fn parse_elements(text: &str) -> Vec<(String, String)> {
+    let mut elements: Vec<(String, String)> = Vec::new();
+
+    for part in text.split('<').skip(1) {
+        // Closing tags and aggregates have no value of their own
+        if part.starts_with('/') {
+            continue;
+        }
+
+        let close = match part.find('>') {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let value = part[close + 1..].trim();
+
+        if !value.is_empty() {
+            elements.push((part[..close].trim().to_ascii_uppercase(), decode_entities(value)));
+        }
+    }
+
+    elements
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+fn parse_transaction(elements: &[(String, String)], credit_kind: TransactionKind) -> Result<StatementEntry, String> {
+    let element = |tag: &str| {
+        elements
+            .iter()
+            .find(|(x, _)| x == tag)
+            .map(|(_, value)| value.as_str())
+    };
+
+    // Dates are written as YYYYMMDD, optionally followed by a time and timezone
+    let date = match element("DTPOSTED") {
+        Some(x) => match x.get(..8).map(|x| NaiveDate::parse_from_str(x, "%Y%m%d")) {
+            Some(Ok(date)) => to_sqlite_date(&date),
+            _ => return Err(format!("invalid date \"{}\"", x)),
+        },
+        None => return Err(String::from("missing DTPOSTED")),
+    };
+
+    // Some banks write amounts with a decimal comma
+    let amount = match element("TRNAMT") {
+        Some(x) => {
+            let normalised = if x.contains(',') && !x.contains('.') {
+                x.replace(',', ".")
+            } else {
+                x.to_string()
+            };
+
+            match parse_amount(&normalised) {
+                Some(amount) if amount != 0.0 => amount,
+                Some(_) => return Err(String::from("amount is zero")),
+                None => return Err(format!("invalid amount \"{}\"", x)),
+            }
+        }
+        None => return Err(String::from("missing TRNAMT")),
+    };
+
+    let memo = element("MEMO").unwrap_or("").to_string();
+
+    let (name, description) = match element("NAME") {
+        Some(x) => (x.to_string(), memo),
+        None if !memo.is_empty() => (memo, String::new()),
+        None => return Err(String::from("missing NAME and MEMO")),
+    };
+
+    Ok(StatementEntry {
+        date,
+        name,
+        description,
+        amount: amount.abs(),
+        kind: if amount < 0.0 { TransactionKind::Expense } else { credit_kind },
+        external_id: element("FITID").map(|x| x.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::statement_import::ImportStatus;
+
+    const SGML: &str = "OFXHEADER:100
+DATA:OFXSGML
+VERSION:102
+
+<OFX>
+<BANKMSGSRSV1><STMTTRNRS><STMTRS><BANKTRANLIST>
+<STMTTRN>
+<TRNTYPE>DEBIT
+<DTPOSTED>20200131120000[-5:EST]
+<TRNAMT>-12.50
+<FITID>1001
+<NAME>Corner Shop
+<MEMO>Milk &amp; bread
+<STMTTRN>
+<TRNTYPE>CREDIT
+<DTPOSTED>20200201
+<TRNAMT>1000,00
+<FITID>1002
+<MEMO>Salary
+</BANKTRANLIST></STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>
+";
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<?OFX OFXHEADER="200" VERSION="220"?>
+<ofx>
+  <bankmsgsrsv1><stmttrnrs><stmtrs><banktranlist>
+    <stmttrn>
+      <trntype>DEBIT</trntype>
+      <dtposted>20200131</dtposted>
+      <trnamt>-12.50</trnamt>
+      <fitid>1001</fitid>
+      <name>Corner Shop</name>
+      <memo>Milk &amp; bread</memo>
+    </stmttrn>
+    <stmttrn>
+      <trntype>CREDIT</trntype>
+      <dtposted>20200201</dtposted>
+      <trnamt>1000.00</trnamt>
+      <fitid>1002</fitid>
+      <memo>Salary</memo>
+    </stmttrn>
+  </banktranlist></stmtrs></stmttrnrs></bankmsgsrsv1>
+</ofx>
+"#;
+
+    #[test]
+    fn sgml_and_xml_read_the_same() {
+        for text in [SGML, XML].iter() {
+            let rows = parse_ofx(text, TransactionKind::Income).unwrap();
+
+            assert_eq!(rows.len(), 2);
+
+            let entries: Vec<&StatementEntry> = rows.iter().filter_map(|x| x.entry.as_ref()).collect();
+
+            assert_eq!(entries.len(), 2, "{:?}", rows);
+
+            assert_eq!(entries[0].date, "2020-01-31");
+            assert_eq!(entries[0].name, "Corner Shop");
+            assert_eq!(entries[0].description, "Milk & bread");
+            assert_eq!(entries[0].amount, 12.5);
+            assert_eq!(entries[0].kind, TransactionKind::Expense);
+            assert_eq!(entries[0].external_id, Some(String::from("1001")));
+
+            // Without a NAME the memo becomes the name
+            assert_eq!(entries[1].date, "2020-02-01");
+            assert_eq!(entries[1].name, "Salary");
+            assert_eq!(entries[1].description, "");
+            assert_eq!(entries[1].amount, 1000.0);
+            assert_eq!(entries[1].kind, TransactionKind::Income);
+            assert_eq!(entries[1].external_id, Some(String::from("1002")));
+        }
+    }
+
+    #[test]
+    fn malformed_transactions_are_rejected_on_their_line() {
+        let text = "<OFX>
+<STMTTRN><DTPOSTED>2020013<TRNAMT>-1<NAME>Short date
+<STMTTRN><TRNAMT>-1<NAME>No date
+<STMTTRN><DTPOSTED>20200131<TRNAMT>abc<NAME>Bad amount
+<STMTTRN><DTPOSTED>20200131<TRNAMT>0.00<NAME>Zero
+<STMTTRN><DTPOSTED>20200131<TRNAMT>-1
+<STMTTRN><DTPOSTED>20200131<NAME>No amount
+<STMTTRN><DTPOSTED>20200131<TRNAMT>-1<NAME>Fine
+</OFX>";
+
+        let rows = parse_ofx(text, TransactionKind::Refund).unwrap();
+
+        let expected = [
+            (2, Some("invalid date \"2020013\"")),
+            (3, Some("missing DTPOSTED")),
+            (4, Some("invalid amount \"abc\"")),
+            (5, Some("amount is zero")),
+            (6, Some("missing NAME and MEMO")),
+            (7, Some("missing TRNAMT")),
+            (8, None),
+        ];
+
+        assert_eq!(rows.len(), expected.len());
+
+        for (row, (line, reason)) in rows.iter().zip(expected.iter()) {
+            assert_eq!(row.line, *line);
+            assert_eq!(row.reason.as_ref().map(|x| x.as_str()), *reason, "line {}", line);
+            assert_eq!(row.status == ImportStatus::Rejected, reason.is_some(), "line {}", line);
+        }
+    }
+
+    #[test]
+    fn files_without_an_ofx_element_are_rejected() {
+        match parse_ofx("Date,Description,Amount\n", TransactionKind::Refund) {
+            Err(Error::InvalidStatement(_)) => (),
+            x => panic!("not an OFX file but accepted: {:?}", x),
+        }
+    }
+}
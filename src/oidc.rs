@@ -0,0 +1,741 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+
+use crate::util::{generate_token, uri_encode};
+
+/// Time a user has to sign in at the identity provider
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Unfinished sign ins kept at once, anyone can start one so this bounds memory use
+const MAX_PENDING_LOGINS: usize = 10_000;
+/// Allowed difference between our clock and the identity provider's
+const CLOCK_LEEWAY_SECONDS: i64 = 60;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Single sign-on through an OpenID Connect identity provider, see `Config`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    /// Issuer URL, the discovery document is read from
+    /// "<issuer>/.well-known/openid-configuration"
+    pub issuer: String,
+    pub client_id: String,
+    /// Leave out for public clients, PKCE is always used
+    pub client_secret: Option<String>,
+    /// Page that receives the authorization response and passes the code and
+    /// state on to `/api/oidc/callback`
+    pub redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+    /// Whether users with an account under the same verified email are linked
+    /// on their first sign in, instead of linking from their account
+    #[serde(default)]
+    pub link_existing_users: bool,
+    /// Whether users without an account get one on their first sign in
+    #[serde(default)]
+    pub create_users: bool
+}
+
+fn default_scopes() -> String {
+    String::from("openid email profile")
+}
+
+/// The parts of the discovery document that are used
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    n: Option<String>,
+    e: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String
+}
+
+/// Validated claims of an ID token
+#[derive(Debug, Clone)]
+pub struct IdTokenClaims {
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>
+}
+
+/// A sign in that has been sent to the identity provider, keyed by its state
+struct PendingLogin {
+    nonce: String,
+    code_verifier: String,
+    started: Instant
+}
+
+/// Runs the authorization code flow with PKCE against the configured provider
+///
+/// The discovery document and signing keys are fetched on first use and the
+/// keys are fetched again when a token is signed with an unknown key.
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    provider: Mutex<Option<(ProviderMetadata, Jwks)>>,
+    pending: Mutex<HashMap<String, PendingLogin>>
+}
+
+impl OidcClient {
+    pub fn new(config: &OidcConfig) -> Result<OidcClient, String> {
+        let http = reqwest::Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .map_err(|error| format!("failed creating HTTP client: {}", error))?;
+
+        Ok(OidcClient {
+            config: config.clone(),
+            http,
+            provider: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.config.issuer
+    }
+
+    pub fn link_existing_users(&self) -> bool {
+        self.config.link_existing_users
+    }
+
+    pub fn create_users(&self) -> bool {
+        self.config.create_users
+    }
+
+    /// Starts a sign in, returning the provider URL to send the user to
+    pub fn authorization_url(&self) -> Result<String, String> {
+        let (metadata, _) = self.get_provider(false)?;
+
+        let state = generate_token(32);
+        let nonce = generate_token(32);
+        let code_verifier = generate_token(64);
+
+        let url = format!(
+            "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}\
+            &code_challenge={}&code_challenge_method=S256",
+            metadata.authorization_endpoint,
+            if metadata.authorization_endpoint.contains('?') { "&" } else { "?" },
+            uri_encode(&self.config.client_id),
+            uri_encode(&self.config.redirect_uri),
+            uri_encode(&self.config.scopes),
+            state,
+            nonce,
+            code_challenge(&code_verifier)
+        );
+
+        let mut pending = self.pending.lock().unwrap();
+        let now = Instant::now();
+
+        // Sign ins that were never finished are dropped
+        pending.retain(|_, x| now.duration_since(x.started) < LOGIN_TIMEOUT);
+
+        // Past the limit the oldest sign in makes way, so a flood of requests
+        // can't grow memory use or stop new sign ins from starting
+        if pending.len() >= MAX_PENDING_LOGINS {
+            let oldest = pending.iter().min_by_key(|(_, x)| x.started).map(|(state, _)| state.clone());
+
+            if let Some(oldest) = oldest {
+                pending.remove(&oldest);
+            }
+        }
+
+        pending.insert(
+            state,
+            PendingLogin {
+                nonce,
+                code_verifier,
+                started: now,
+            },
+        );
+
+        Ok(url)
+    }
+
+    /// Finishes a sign in with the authorization response, exchanging the
+    /// code for an ID token and validating it
+    pub fn complete(&self, code: &str, state: &str) -> Result<IdTokenClaims, String> {
+        // Each state can only be used once
+        let login = match self.pending.lock().unwrap().remove(state) {
+            Some(x) => x,
+            None => return Err(String::from("unknown or already used state")),
+        };
+
+        if login.started.elapsed() >= LOGIN_TIMEOUT {
+            return Err(String::from("sign in took too long"));
+        }
+
+        let (metadata, jwks) = self.get_provider(false)?;
+
+        let mut form: Vec<(&str, &str)> = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &login.code_verifier),
+        ];
+
+        if let Some(client_secret) = &self.config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let token: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .and_then(|x| x.error_for_status())
+            .and_then(|mut x| x.json())
+            .map_err(|error| format!("failed exchanging code: {}", error))?;
+
+        let now = chrono::Utc::now().timestamp();
+
+        let claims =
+            validate_id_token(&token.id_token, &jwks, &metadata.issuer, &self.config.client_id, &login.nonce, now);
+
+        match claims {
+            Err(IdTokenError::UnknownKey) => {
+                // The provider may have rotated its keys since they were fetched
+                let (metadata, jwks) = self.get_provider(true)?;
+
+                validate_id_token(&token.id_token, &jwks, &metadata.issuer, &self.config.client_id, &login.nonce, now)
+                    .map_err(|error| error.to_string())
+            }
+            x => x.map_err(|error| error.to_string()),
+        }
+    }
+
+    fn get_provider(&self, refresh: bool) -> Result<(ProviderMetadata, Jwks), String> {
+        let mut provider = self.provider.lock().unwrap();
+
+        if let (Some(x), false) = (provider.as_ref(), refresh) {
+            return Ok(x.clone());
+        }
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+
+        let metadata: ProviderMetadata = self.get_json(&discovery_url)?;
+
+        // Stops a compromised discovery document from vouching for another issuer
+        if metadata.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/') {
+            return Err(format!(
+                "discovery document is for issuer \"{}\", not \"{}\"",
+                metadata.issuer, self.config.issuer
+            ));
+        }
+
+        let jwks: Jwks = self.get_json(&metadata.jwks_uri)?;
+
+        *provider = Some((metadata, jwks));
+
+        Ok(provider.clone().unwrap())
+    }
+
+    fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, String> {
+        self.http
+            .get(url)
+            .send()
+            .and_then(|x| x.error_for_status())
+            .and_then(|mut x| x.json())
+            .map_err(|error| format!("failed fetching \"{}\": {}", url, error))
+    }
+}
+
+#[derive(Debug)]
+pub enum IdTokenError {
+    Malformed,
+    UnsupportedAlgorithm(String),
+    UnknownKey,
+    InvalidSignature,
+    WrongIssuer,
+    WrongAudience,
+    Expired,
+    WrongNonce,
+    MissingSubject
+}
+
+impl std::fmt::Display for IdTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IdTokenError::Malformed => write!(f, "ID token is malformed"),
+            IdTokenError::UnsupportedAlgorithm(x) => write!(f, "ID token is signed with unsupported algorithm \"{}\"", x),
+            IdTokenError::UnknownKey => write!(f, "ID token is signed with an unknown key"),
+            IdTokenError::InvalidSignature => write!(f, "ID token signature is invalid"),
+            IdTokenError::WrongIssuer => write!(f, "ID token is from the wrong issuer"),
+            IdTokenError::WrongAudience => write!(f, "ID token is for another client"),
+            IdTokenError::Expired => write!(f, "ID token has expired"),
+            IdTokenError::WrongNonce => write!(f, "ID token nonce doesn't match"),
+            IdTokenError::MissingSubject => write!(f, "ID token has no subject"),
+        }
+    }
+}
+
+/// Checks the signature and claims of an RS256 ID token as described in
+/// OpenID Connect Core 3.1.3.7
+fn validate_id_token(
+    id_token: &str,
+    jwks: &Jwks,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+    now: i64,
+) -> Result<IdTokenClaims, IdTokenError> {
+    let parts: Vec<&str> = id_token.split('.').collect();
+
+    if parts.len() != 3 {
+        return Err(IdTokenError::Malformed);
+    }
+
+    let header = decode_json(parts[0])?;
+    let claims = decode_json(parts[1])?;
+    let signature = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).map_err(|_| IdTokenError::Malformed)?;
+
+    // Only RS256 is accepted, which rules out unsigned and HMAC tokens
+    match header["alg"].as_str() {
+        Some("RS256") => (),
+        Some(x) => return Err(IdTokenError::UnsupportedAlgorithm(x.to_string())),
+        None => return Err(IdTokenError::Malformed),
+    }
+
+    let kid = header["kid"].as_str();
+
+    let key = jwks
+        .keys
+        .iter()
+        .filter(|x| x.kty == "RSA" && x.alg.as_ref().map_or(true, |alg| alg == "RS256"))
+        .find(|x| kid.is_none() || x.kid.as_ref().map(|x| x.as_str()) == kid)
+        .ok_or(IdTokenError::UnknownKey)?;
+
+    let signing_input = format!("{}.{}", parts[0], parts[1]);
+
+    if !verify_rs256(key, signing_input.as_bytes(), &signature)? {
+        return Err(IdTokenError::InvalidSignature);
+    }
+
+    if claims["iss"].as_str().map(|x| x.trim_end_matches('/')) != Some(issuer.trim_end_matches('/')) {
+        return Err(IdTokenError::WrongIssuer);
+    }
+
+    let audiences: Vec<&str> = match &claims["aud"] {
+        Value::String(x) => vec![x.as_str()],
+        Value::Array(x) => x.iter().filter_map(|x| x.as_str()).collect(),
+        _ => return Err(IdTokenError::Malformed),
+    };
+
+    if !audiences.contains(&client_id) {
+        return Err(IdTokenError::WrongAudience);
+    }
+
+    // Tokens for several clients must name us as the authorized party
+    if audiences.len() > 1 && claims["azp"].as_str() != Some(client_id) {
+        return Err(IdTokenError::WrongAudience);
+    }
+
+    match claims["exp"].as_i64() {
+        Some(exp) => {
+            if exp + CLOCK_LEEWAY_SECONDS < now {
+                return Err(IdTokenError::Expired);
+            }
+        }
+        None => return Err(IdTokenError::Malformed),
+    }
+
+    if claims["nonce"].as_str() != Some(nonce) {
+        return Err(IdTokenError::WrongNonce);
+    }
+
+    let subject = match claims["sub"].as_str() {
+        Some(x) if !x.is_empty() => x.to_string(),
+        _ => return Err(IdTokenError::MissingSubject),
+    };
+
+    // Some providers send email_verified as a string
+    let email_verified = match &claims["email_verified"] {
+        Value::Bool(x) => *x,
+        Value::String(x) => x == "true",
+        _ => false,
+    };
+
+    Ok(IdTokenClaims {
+        issuer: issuer.to_string(),
+        subject,
+        email: claims["email"].as_str().map(|x| x.to_string()),
+        email_verified,
+        given_name: claims["given_name"].as_str().map(|x| x.to_string()),
+        family_name: claims["family_name"].as_str().map(|x| x.to_string()),
+    })
+}
+
+fn verify_rs256(key: &Jwk, data: &[u8], signature: &[u8]) -> Result<bool, IdTokenError> {
+    let component = |x: &Option<String>| -> Result<BigNum, IdTokenError> {
+        let bytes = base64::decode_config(x.as_ref().ok_or(IdTokenError::Malformed)?, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| IdTokenError::Malformed)?;
+
+        BigNum::from_slice(&bytes).map_err(|_| IdTokenError::Malformed)
+    };
+
+    let rsa = Rsa::from_public_components(component(&key.n)?, component(&key.e)?).map_err(|_| IdTokenError::Malformed)?;
+    let pkey = PKey::from_rsa(rsa).map_err(|_| IdTokenError::Malformed)?;
+
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey).map_err(|_| IdTokenError::Malformed)?;
+    verifier.update(data).map_err(|_| IdTokenError::Malformed)?;
+
+    Ok(verifier.verify(signature).unwrap_or(false))
+}
+
+fn decode_json(part: &str) -> Result<Value, IdTokenError> {
+    let bytes = base64::decode_config(part, base64::URL_SAFE_NO_PAD).map_err(|_| IdTokenError::Malformed)?;
+
+    serde_json::from_slice(&bytes).map_err(|_| IdTokenError::Malformed)
+}
+
+/// PKCE S256 challenge for a code verifier, see RFC 7636
+fn code_challenge(code_verifier: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(code_verifier);
+
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+
+    base64::encode_config(&hash, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    use serde_json::json;
+
+    const CLIENT_ID: &str = "budget-tracker";
+
+    /// What the mock identity provider does with the next code exchange
+    struct MockState {
+        claims: Value,
+        /// PKCE challenge the token endpoint checks the code verifier against
+        code_challenge: String
+    }
+
+    /// An identity provider serving discovery, JWKS and token endpoints on a
+    /// loopback port
+    struct MockIdp {
+        issuer: String,
+        state: Arc<Mutex<MockState>>
+    }
+
+    impl MockIdp {
+        fn start() -> MockIdp {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+            let rsa = Rsa::generate(2048).unwrap();
+
+            let jwks = json!({
+                "keys": [{
+                    "kty": "RSA",
+                    "kid": "test",
+                    "alg": "RS256",
+                    "use": "sig",
+                    "n": base64::encode_config(&rsa.n().to_vec(), base64::URL_SAFE_NO_PAD),
+                    "e": base64::encode_config(&rsa.e().to_vec(), base64::URL_SAFE_NO_PAD)
+                }]
+            });
+
+            let discovery = json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer)
+            });
+
+            let key = PKey::from_rsa(rsa).unwrap();
+
+            let state = Arc::new(Mutex::new(MockState {
+                claims: Value::Null,
+                code_challenge: String::new(),
+            }));
+            let thread_state = state.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut writer = stream.unwrap();
+                    let mut reader = BufReader::new(writer.try_clone().unwrap());
+
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+
+                    let mut content_length = 0;
+
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+
+                        if line.trim().is_empty() {
+                            break;
+                        }
+
+                        let line = line.to_lowercase();
+
+                        if line.starts_with("content-length:") {
+                            content_length = line["content-length:".len()..].trim().parse().unwrap();
+                        }
+                    }
+
+                    let mut body = vec![0u8; content_length];
+                    reader.read_exact(&mut body).unwrap();
+
+                    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+
+                    let (status, response) = match path {
+                        "/.well-known/openid-configuration" => ("200 OK", discovery.clone()),
+                        "/jwks" => ("200 OK", jwks.clone()),
+                        "/token" => {
+                            let state = thread_state.lock().unwrap();
+                            let form = String::from_utf8(body).unwrap();
+                            let code_verifier = query_param(&form, "code_verifier").unwrap_or_default();
+
+                            if code_challenge(&code_verifier) == state.code_challenge {
+                                let id_token = sign(&key, &state.claims);
+
+                                ("200 OK", json!({ "id_token": id_token, "token_type": "Bearer" }))
+                            } else {
+                                ("400 Bad Request", json!({ "error": "invalid_grant" }))
+                            }
+                        }
+                        _ => ("404 Not Found", json!({})),
+                    };
+
+                    let response = response.to_string();
+
+                    write!(
+                        writer,
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                        Connection: close\r\n\r\n{}",
+                        status,
+                        response.len(),
+                        response
+                    )
+                    .unwrap();
+                }
+            });
+
+            MockIdp { issuer, state }
+        }
+
+        fn client(&self) -> OidcClient {
+            OidcClient::new(&OidcConfig {
+                issuer: self.issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: None,
+                redirect_uri: String::from("http://localhost/oidc-callback"),
+                scopes: default_scopes(),
+                link_existing_users: false,
+                create_users: false,
+            })
+            .unwrap()
+        }
+
+        /// Runs a sign in, letting `tamper` change what the provider does
+        /// before the code is exchanged
+        fn sign_in<F>(&self, client: &OidcClient, tamper: F) -> Result<IdTokenClaims, String>
+        where
+            F: FnOnce(&mut MockState),
+        {
+            let url = client.authorization_url().unwrap();
+            let state = query_param(&url, "state").unwrap();
+            let now = chrono::Utc::now().timestamp();
+
+            {
+                let mut mock = self.state.lock().unwrap();
+
+                mock.code_challenge = query_param(&url, "code_challenge").unwrap();
+                mock.claims = json!({
+                    "iss": self.issuer,
+                    "sub": "user-1",
+                    "aud": CLIENT_ID,
+                    "iat": now,
+                    "exp": now + 300,
+                    "nonce": query_param(&url, "nonce").unwrap(),
+                    "email": "alice@example.com",
+                    "email_verified": true
+                });
+
+                tamper(&mut mock);
+            }
+
+            client.complete("code", &state)
+        }
+    }
+
+    fn sign(key: &PKey<Private>, claims: &Value) -> String {
+        let encode = |x: &Value| base64::encode_config(x.to_string().as_bytes(), base64::URL_SAFE_NO_PAD);
+
+        let header = json!({ "alg": "RS256", "typ": "JWT", "kid": "test" });
+        let signing_input = format!("{}.{}", encode(&header), encode(claims));
+
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(signing_input.as_bytes()).unwrap();
+
+        let signature = signer.sign_to_vec().unwrap();
+
+        format!("{}.{}", signing_input, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Value of a parameter in a URL or form body, none of the values used here
+    /// need decoding
+    fn query_param(s: &str, name: &str) -> Option<String> {
+        let query = s.splitn(2, '?').last()?;
+
+        query.split('&').find_map(|x| {
+            let mut parts = x.splitn(2, '=');
+
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => Some(value.to_string()),
+                _ => None,
+            }
+        })
+    }
+
+    #[test]
+    fn valid_sign_in_is_accepted() {
+        let idp = MockIdp::start();
+        let client = idp.client();
+
+        let claims = idp.sign_in(&client, |_| ()).unwrap();
+
+        assert_eq!(claims.issuer, idp.issuer);
+        assert_eq!(claims.subject, "user-1");
+        assert_eq!(claims.email.as_ref().map(|x| x.as_str()), Some("alice@example.com"));
+        assert!(claims.email_verified);
+
+        // Several audiences are fine when we're the authorized party
+        let claims = idp.sign_in(&client, |x| {
+            x.claims["aud"] = json!([CLIENT_ID, "other-client"]);
+            x.claims["azp"] = json!(CLIENT_ID);
+        });
+
+        assert!(claims.is_ok());
+    }
+
+    #[test]
+    fn invalid_claims_are_rejected() {
+        let idp = MockIdp::start();
+        let client = idp.client();
+
+        let cases: Vec<(&str, Box<dyn FnOnce(&mut MockState)>)> = vec![
+            ("wrong issuer", Box::new(|x: &mut MockState| x.claims["iss"] = json!("http://127.0.0.1:1"))),
+            ("another client", Box::new(|x: &mut MockState| x.claims["aud"] = json!("other-client"))),
+            ("another client", Box::new(|x: &mut MockState| x.claims["aud"] = json!([CLIENT_ID, "other-client"]))),
+            (
+                "another client",
+                Box::new(|x: &mut MockState| {
+                    x.claims["aud"] = json!([CLIENT_ID, "other-client"]);
+                    x.claims["azp"] = json!("other-client");
+                }),
+            ),
+            (
+                "expired",
+                Box::new(|x: &mut MockState| x.claims["exp"] = json!(chrono::Utc::now().timestamp() - CLOCK_LEEWAY_SECONDS - 60)),
+            ),
+            ("nonce", Box::new(|x: &mut MockState| x.claims["nonce"] = json!("replayed-nonce"))),
+            ("nonce", Box::new(|x: &mut MockState| x.claims["nonce"] = Value::Null)),
+            ("no subject", Box::new(|x: &mut MockState| x.claims["sub"] = json!(""))),
+        ];
+
+        for (expected, tamper) in cases {
+            let error = idp.sign_in(&client, tamper).unwrap_err();
+
+            assert!(error.contains(expected), "expected \"{}\", got \"{}\"", expected, error);
+        }
+    }
+
+    #[test]
+    fn pkce_verifier_mismatch_is_rejected() {
+        let idp = MockIdp::start();
+        let client = idp.client();
+
+        let error = idp
+            .sign_in(&client, |x| x.code_challenge = code_challenge("another-verifier"))
+            .unwrap_err();
+
+        assert!(error.contains("failed exchanging code"), "{}", error);
+    }
+
+    #[test]
+    fn state_is_single_use() {
+        let idp = MockIdp::start();
+        let client = idp.client();
+
+        let url = client.authorization_url().unwrap();
+        let state = query_param(&url, "state").unwrap();
+
+        // The first attempt fails at the provider but still uses up the state
+        assert!(client.complete("code", &state).is_err());
+
+        let error = client.complete("code", &state).unwrap_err();
+
+        assert!(error.contains("unknown or already used state"), "{}", error);
+    }
+
+    #[test]
+    fn pending_sign_ins_are_capped() {
+        let idp = MockIdp::start();
+        let client = idp.client();
+
+        let first_state = query_param(&client.authorization_url().unwrap(), "state").unwrap();
+
+        // Makes sure the first sign in is strictly the oldest
+        thread::sleep(Duration::from_millis(5));
+
+        for _ in 0..MAX_PENDING_LOGINS {
+            client.authorization_url().unwrap();
+        }
+
+        let pending = client.pending.lock().unwrap();
+
+        assert_eq!(pending.len(), MAX_PENDING_LOGINS);
+        assert!(!pending.contains_key(&first_state));
+    }
+}
@@ -0,0 +1,300 @@
+use serde::{Deserialize, Serialize};
+
+use chrono::{Datelike, Duration, NaiveDate};
+
+/// Longest periods allowed, about ten years, keeping period arithmetic well
+/// inside the range of dates
+const MAX_DAYS: i64 = 3660;
+const MAX_WEEKS: i64 = 520;
+const MAX_MONTHS: i64 = 120;
+
+/// Describes how a budget is divided up into periods
+///
+/// Calendar based rules are aligned so that the first period is the one
+/// containing the budget's start date, e.g. a monthly budget anchored to the
+/// 1st that starts on the 15th of March has its first period in March.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PeriodRule {
+    /// Every `count` days from the start date
+    Days { count: i64 },
+    /// Every `count` weeks, starting on `weekday` (0 = Monday, 6 = Sunday)
+    Weeks { count: i64, weekday: u32 },
+    /// Every `count` calendar months, starting on `anchor_day` of the month
+    ///
+    /// Months shorter than `anchor_day` start on their last day instead
+    Months { count: i64, anchor_day: u32 },
+    /// Every three calendar months, starting on `anchor_day` of the month
+    Quarters { anchor_day: u32 },
+    /// Every calendar year, starting on `anchor_day` of the start date's month
+    Years { anchor_day: u32 },
+}
+
+impl PeriodRule {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            PeriodRule::Days { count } => count > 0 && count <= MAX_DAYS,
+            PeriodRule::Weeks { count, weekday } => count > 0 && count <= MAX_WEEKS && weekday < 7,
+            PeriodRule::Months { count, anchor_day } => {
+                count > 0 && count <= MAX_MONTHS && is_valid_anchor_day(anchor_day)
+            }
+            PeriodRule::Quarters { anchor_day } => is_valid_anchor_day(anchor_day),
+            PeriodRule::Years { anchor_day } => is_valid_anchor_day(anchor_day),
+        }
+    }
+
+    /// Rough length of a period in days, kept in the `period_length` column for
+    /// clients that don't understand period rules
+    pub fn approximate_days(&self) -> i64 {
+        match *self {
+            PeriodRule::Days { count } => count,
+            PeriodRule::Weeks { count, .. } => count.saturating_mul(7),
+            PeriodRule::Months { count, .. } => count.saturating_mul(30),
+            PeriodRule::Quarters { .. } => 91,
+            PeriodRule::Years { .. } => 365,
+        }
+    }
+
+    /// First day of the period `period_id` of a budget starting on `start_date`
+    ///
+    /// `None` when the period falls outside the range of dates.
+    pub fn period_start(&self, start_date: NaiveDate, period_id: i64) -> Option<NaiveDate> {
+        match *self {
+            PeriodRule::Days { count } => add_days(start_date, count.checked_mul(period_id)?),
+            PeriodRule::Weeks { count, weekday } => {
+                let offset = (start_date.weekday().num_days_from_monday() + 7 - weekday % 7) % 7;
+                let first = add_days(start_date, -(offset as i64))?;
+
+                add_days(first, count.checked_mul(7)?.checked_mul(period_id)?)
+            }
+            PeriodRule::Months { count, anchor_day } => {
+                month_period_start(start_date, count, anchor_day, period_id)
+            }
+            PeriodRule::Quarters { anchor_day } => {
+                month_period_start(start_date, 3, anchor_day, period_id)
+            }
+            PeriodRule::Years { anchor_day } => {
+                month_period_start(start_date, 12, anchor_day, period_id)
+            }
+        }
+    }
+
+    /// Inclusive first and last day of the period `period_id`, `None` when
+    /// the period falls outside the range of dates
+    pub fn period_bounds(&self, start_date: NaiveDate, period_id: i64) -> Option<(NaiveDate, NaiveDate)> {
+        let start = self.period_start(start_date, period_id)?;
+        let next = self.period_start(start_date, period_id.checked_add(1)?)?;
+
+        Some((start, next.pred_opt()?))
+    }
+
+    /// ID of the period containing `date`, negative if `date` falls before the
+    /// first period
+    pub fn period_containing(&self, start_date: NaiveDate, date: NaiveDate) -> Option<i64> {
+        let first = self.period_start(start_date, 0)?;
+
+        // Estimate using the approximate length then correct for any drift
+        let mut period_id = date.signed_duration_since(first).num_days() / self.approximate_days().max(1);
+
+        while self.period_start(start_date, period_id)? > date {
+            period_id -= 1;
+        }
+
+        while self.period_start(start_date, period_id + 1)? <= date {
+            period_id += 1;
+        }
+
+        Some(period_id)
+    }
+}
+
+fn is_valid_anchor_day(anchor_day: u32) -> bool {
+    anchor_day >= 1 && anchor_day <= 31
+}
+
+fn add_days(date: NaiveDate, days: i64) -> Option<NaiveDate> {
+    // Larger offsets than this are out of range anyway and would overflow `Duration`
+    if days.abs() > 1_000_000_000 {
+        return None;
+    }
+
+    date.checked_add_signed(Duration::days(days))
+}
+
+fn month_period_start(start_date: NaiveDate, count: i64, anchor_day: u32, period_id: i64) -> Option<NaiveDate> {
+    let start_month = start_date.year() as i64 * 12 + start_date.month0() as i64;
+
+    // Align to the latest anchor day that isn't after the start date
+    let first_month = if anchor_date(start_month, anchor_day)? <= start_date {
+        start_month
+    } else {
+        start_month - 1
+    };
+
+    anchor_date(first_month.checked_add(count.checked_mul(period_id)?)?, anchor_day)
+}
+
+/// Gets `anchor_day` of a month counted from year 0, clamped to the end of the
+/// month, `None` when the month is out of range
+fn anchor_date(month: i64, anchor_day: u32) -> Option<NaiveDate> {
+    let year = month.div_euclid(12);
+    let month0 = month.rem_euclid(12) as u32;
+
+    if year < i32::min_value() as i64 || year > i32::max_value() as i64 {
+        return None;
+    }
+
+    let day = anchor_day.min(days_in_month(year as i32, month0 + 1)?);
+
+    NaiveDate::from_ymd_opt(year as i32, month0 + 1, day)
+}
+
+fn days_in_month(year: i32, month: u32) -> Option<u32> {
+    let next_month = if month == 12 {
+        NaiveDate::from_ymd_opt(year.checked_add(1)?, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)
+    }?;
+
+    Some(next_month.pred_opt()?.day())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const CASES: usize = 2000;
+    const PERIODS: i64 = 40;
+
+    fn random_date(rng: &mut StdRng) -> NaiveDate {
+        NaiveDate::from_ymd(2000, 1, 1) + Duration::days(rng.gen_range(0, 365 * 40))
+    }
+
+    fn random_rule(rng: &mut StdRng) -> PeriodRule {
+        // Anchor days 29 to 31 are picked often, they're clamped in short months
+        let anchor_day = if rng.gen_bool(0.5) {
+            rng.gen_range(29, 32)
+        } else {
+            rng.gen_range(1, 32)
+        };
+
+        match rng.gen_range(0, 5) {
+            0 => PeriodRule::Days { count: rng.gen_range(1, 60) },
+            1 => PeriodRule::Weeks {
+                count: rng.gen_range(1, 6),
+                weekday: rng.gen_range(0, 7),
+            },
+            2 => PeriodRule::Months {
+                count: rng.gen_range(1, 13),
+                anchor_day,
+            },
+            3 => PeriodRule::Quarters { anchor_day },
+            _ => PeriodRule::Years { anchor_day },
+        }
+    }
+
+    /// Checks consecutive periods from `first_period_id` on against each other
+    fn check_periods(rule: &PeriodRule, start_date: NaiveDate, first_period_id: i64) {
+        let mut previous: Option<(NaiveDate, NaiveDate)> = None;
+
+        for period_id in first_period_id..first_period_id + PERIODS {
+            let (start, end) = rule.period_bounds(start_date, period_id).unwrap();
+
+            assert!(start <= end, "{:?} from {} has empty period {}", rule, start_date, period_id);
+
+            // Contiguous and non-overlapping: each period starts the day after the last one ends
+            if let Some((_, previous_end)) = previous {
+                assert_eq!(
+                    previous_end.succ(),
+                    start,
+                    "{:?} from {} has a gap or overlap before period {}",
+                    rule,
+                    start_date,
+                    period_id
+                );
+            }
+
+            // Both ends belong to the period, the days either side don't
+            assert_eq!(rule.period_containing(start_date, start), Some(period_id));
+            assert_eq!(rule.period_containing(start_date, end), Some(period_id));
+            assert_eq!(rule.period_containing(start_date, start.pred()), Some(period_id - 1));
+            assert_eq!(rule.period_containing(start_date, end.succ()), Some(period_id + 1));
+
+            previous = Some((start, end));
+        }
+    }
+
+    #[test]
+    fn consecutive_periods_are_contiguous() {
+        let mut rng = StdRng::seed_from_u64(0x5eed_0033);
+
+        for _ in 0..CASES {
+            let rule = random_rule(&mut rng);
+            let start_date = random_date(&mut rng);
+
+            assert!(rule.is_valid());
+
+            check_periods(&rule, start_date, rng.gen_range(-5, 50));
+        }
+    }
+
+    #[test]
+    fn first_period_contains_start_date() {
+        let mut rng = StdRng::seed_from_u64(0x5eed_0034);
+
+        for _ in 0..CASES {
+            let rule = random_rule(&mut rng);
+            let start_date = random_date(&mut rng);
+
+            assert_eq!(rule.period_containing(start_date, start_date), Some(0), "{:?}", rule);
+        }
+    }
+
+    #[test]
+    fn late_anchor_days_clamp_to_month_end() {
+        for anchor_day in 29..=31 {
+            for &count in &[1, 2, 3, 12] {
+                let rule = PeriodRule::Months { count, anchor_day };
+
+                // Starts in every month of a leap year and the year after
+                for month in 0..24 {
+                    let start_date = NaiveDate::from_ymd(2024 + month / 12, month as u32 % 12 + 1, 15);
+
+                    check_periods(&rule, start_date, 0);
+                }
+            }
+        }
+
+        let rule = PeriodRule::Months { count: 1, anchor_day: 31 };
+        let start_date = NaiveDate::from_ymd(2024, 1, 31);
+
+        assert_eq!(
+            rule.period_bounds(start_date, 1),
+            Some((NaiveDate::from_ymd(2024, 2, 29), NaiveDate::from_ymd(2024, 3, 30)))
+        );
+    }
+
+    #[test]
+    fn out_of_range_periods_are_errors() {
+        let start_date = NaiveDate::from_ymd(2020, 1, 1);
+
+        let rules = [
+            PeriodRule::Days { count: i64::max_value() },
+            PeriodRule::Weeks { count: i64::max_value(), weekday: 9 },
+            PeriodRule::Months { count: i64::max_value(), anchor_day: 31 },
+        ];
+
+        for rule in &rules {
+            assert!(!rule.is_valid());
+            assert_eq!(rule.period_bounds(start_date, 2), None);
+        }
+
+        let rule = PeriodRule::Years { anchor_day: 1 };
+
+        assert_eq!(rule.period_start(start_date, i64::max_value()), None);
+        assert_eq!(rule.period_bounds(start_date, 10_000_000), None);
+    }
+}
@@ -0,0 +1,280 @@
+use chrono::NaiveDate;
+
+use crate::database::Error;
+use crate::statement_import::{parse_amount, ImportRow, StatementEntry};
+use crate::transaction::TransactionKind;
+use crate::util::to_sqlite_date;
+
+// Account types whose records are plain transactions, investment accounts
+// and lists of categories, classes or accounts are skipped
+const TRANSACTION_TYPES: &[&str] = &["bank", "cash", "ccard", "oth a", "oth l"];
+
+/// Reads the transactions of a QIF statement
+///
+/// QIF has no standard date format, `date_format` is a chrono format string
+/// such as "%m/%d/%Y". Two digit years and the "'" separator some programs
+/// write before the year are also accepted. QIF has no transaction IDs so
+/// duplicates are found by date, amount and kind alone.
+pub fn parse_qif(text: &str, date_format: &str, credit_kind: TransactionKind) -> Result<Vec<ImportRow>, Error> {
+    let mut rows: Vec<ImportRow> = Vec::new();
+
+    let mut has_header = false;
+    let mut in_transactions = false;
+
+    // Line of the first field of the current record and its fields
+    let mut record_line: u64 = 0;
+    let mut record: Vec<(char, String)> = Vec::new();
+
+    for (i, line) in text.trim_start_matches('\u{feff}').lines().enumerate() {
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('!') {
+            let header = line.to_lowercase();
+
+            has_header = true;
+            in_transactions = header.starts_with("!type:")
+                && TRANSACTION_TYPES.contains(&header["!type:".len()..].trim());
+
+            record.clear();
+            continue;
+        }
+
+        if line.starts_with('^') {
+            if in_transactions && !record.is_empty() {
+                rows.push(record_row(record_line, &record, date_format, credit_kind));
+            }
+
+            record.clear();
+            continue;
+        }
+
+        if record.is_empty() {
+            record_line = i as u64 + 1;
+        }
+
+        let mut chars = line.chars();
+
+        if let Some(code) = chars.next() {
+            record.push((code, chars.as_str().trim().to_string()));
+        }
+    }
+
+    if !has_header {
+        return Err(Error::InvalidStatement(String::from("missing !Type header")));
+    }
+
+    // The last record isn't always terminated
+    if in_transactions && !record.is_empty() {
+        rows.push(record_row(record_line, &record, date_format, credit_kind));
+    }
+
+    Ok(rows)
+}
+
+fn record_row(line: u64, record: &[(char, String)], date_format: &str, credit_kind: TransactionKind) -> ImportRow {
+    match parse_record(record, date_format, credit_kind) {
+        Ok(entry) => ImportRow::parsed(line, entry),
+        Err(reason) => ImportRow::rejected(line, reason),
+    }
+}
+
+fn parse_record(record: &[(char, String)], date_format: &str, credit_kind: TransactionKind) -> Result<StatementEntry, String> {
+    let field = |code: char| {
+        record
+            .iter()
+            .find(|(x, _)| *x == code)
+            .map(|(_, value)| value.as_str())
+            .filter(|x| !x.is_empty())
+    };
+
+    let date = match field('D') {
+        Some(x) => match parse_qif_date(x, date_format) {
+            Some(date) => to_sqlite_date(&date),
+            None => return Err(format!("invalid date \"{}\"", x)),
+        },
+        None => return Err(String::from("missing date")),
+    };
+
+    let amount = match field('T').or_else(|| field('U')) {
+        Some(x) => match parse_amount(x) {
+            Some(amount) if amount != 0.0 => amount,
+            Some(_) => return Err(String::from("amount is zero")),
+            None => return Err(format!("invalid amount \"{}\"", x)),
+        },
+        None => return Err(String::from("missing amount")),
+    };
+
+    let memo = field('M').unwrap_or("").to_string();
+
+    let (name, description) = match field('P') {
+        Some(x) => (x.to_string(), memo),
+        None if !memo.is_empty() => (memo, String::new()),
+        None => return Err(String::from("missing payee and memo")),
+    };
+
+    Ok(StatementEntry {
+        date,
+        name,
+        description,
+        amount: amount.abs(),
+        kind: if amount < 0.0 { TransactionKind::Expense } else { credit_kind },
+        external_id: None,
+    })
+}
+
+fn parse_qif_date(text: &str, date_format: &str) -> Option<NaiveDate> {
+    let normalised: String = text
+        .chars()
+        .filter(|x| !x.is_whitespace())
+        .map(|x| if x == '\'' { '/' } else { x })
+        .collect();
+
+    // Try a two digit year first, "%Y" would read "20" as the year 20
+    let short_format = date_format.replace("%Y", "%y");
+
+    NaiveDate::parse_from_str(&normalised, &short_format)
+        .or_else(|_| NaiveDate::parse_from_str(&normalised, date_format))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::statement_import::ImportStatus;
+
+    #[test]
+    fn only_transaction_accounts_are_read() {
+        let text = "!Type:Cat
+NGroceries
+E
+^
+!Type:Bank
+D01/31/2020
+T-12.50
+PCorner Shop
+MMilk
+^
+!Type:Invst
+D01/31/2020
+NBuy
+YACME
+T-100.00
+^
+!type:CCard
+D2/1'20
+U1,000.00
+MRefund
+";
+
+        let rows = parse_qif(text, "%m/%d/%Y", TransactionKind::Refund).unwrap();
+
+        assert_eq!(rows.len(), 2);
+
+        let first = rows[0].entry.as_ref().unwrap();
+        assert_eq!(rows[0].line, 6);
+        assert_eq!(first.date, "2020-01-31");
+        assert_eq!(first.name, "Corner Shop");
+        assert_eq!(first.description, "Milk");
+        assert_eq!(first.amount, 12.5);
+        assert_eq!(first.kind, TransactionKind::Expense);
+        assert_eq!(first.external_id, None);
+
+        // Headers are case insensitive and the last record needn't end with "^"
+        let second = rows[1].entry.as_ref().unwrap();
+        assert_eq!(rows[1].line, 18);
+        assert_eq!(second.date, "2020-02-01");
+        assert_eq!(second.name, "Refund");
+        assert_eq!(second.amount, 1000.0);
+        assert_eq!(second.kind, TransactionKind::Refund);
+    }
+
+    #[test]
+    fn date_formats() {
+        let cases = [
+            ("%m/%d/%Y", "01/31/2020"),
+            ("%m/%d/%Y", "1/31'20"),
+            ("%m/%d/%Y", "1/31' 2020"),
+            ("%d/%m/%Y", "31/01/20"),
+            ("%Y-%m-%d", "2020-01-31"),
+        ];
+
+        for (date_format, date) in cases.iter() {
+            let text = format!("!Type:Bank\nD{}\nT-1\nPShop\n^\n", date);
+
+            let rows = parse_qif(&text, date_format, TransactionKind::Refund).unwrap();
+
+            match &rows[0].entry {
+                Some(entry) => assert_eq!(entry.date, "2020-01-31", "{} {}", date_format, date),
+                None => panic!("{} {} was rejected: {:?}", date_format, date, rows[0].reason),
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_records_are_rejected_on_their_first_line() {
+        let text = "!Type:Bank
+D13/45/2020
+T-1
+PBad date
+^
+T-1
+PNo date
+^
+D01/31/2020
+Tabc
+PBad amount
+^
+D01/31/2020
+T0
+PZero
+^
+D01/31/2020
+PNo amount
+^
+D01/31/2020
+T-1
+^
+
+D01/31/2020
+T-1
+PFine
+^
+";
+
+        let rows = parse_qif(text, "%m/%d/%Y", TransactionKind::Refund).unwrap();
+
+        let expected = [
+            (2, Some("invalid date \"13/45/2020\"")),
+            (6, Some("missing date")),
+            (9, Some("invalid amount \"abc\"")),
+            (13, Some("amount is zero")),
+            (17, Some("missing amount")),
+            (20, Some("missing payee and memo")),
+            (24, None),
+        ];
+
+        assert_eq!(rows.len(), expected.len());
+
+        for (row, (line, reason)) in rows.iter().zip(expected.iter()) {
+            assert_eq!(row.line, *line);
+            assert_eq!(row.reason.as_ref().map(|x| x.as_str()), *reason, "line {}", line);
+            assert_eq!(row.status == ImportStatus::Rejected, reason.is_some(), "line {}", line);
+        }
+    }
+
+    #[test]
+    fn files_without_a_type_header_are_rejected() {
+        match parse_qif("D01/31/2020\nT-1\nPShop\n^\n", "%m/%d/%Y", TransactionKind::Refund) {
+            Err(Error::InvalidStatement(_)) => (),
+            x => panic!("no header but accepted: {:?}", x),
+        }
+
+        // A header alone is an empty statement rather than an error
+        assert!(parse_qif("!Type:Bank\n", "%m/%d/%Y", TransactionKind::Refund).unwrap().is_empty());
+    }
+}
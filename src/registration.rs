@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+
+/// Who may create an account through `/api/register_user`, see `Config`
+///
+/// Operators can always add users from the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    /// Anyone who can reach the server
+    Open,
+    /// Nobody
+    Disabled,
+    /// Anyone with an unused invite code from an admin
+    InviteOnly,
+    /// Anyone, but accounts can't sign in until an admin approves them
+    AdminApproval
+}
+
+impl Default for RegistrationMode {
+    fn default() -> RegistrationMode {
+        RegistrationMode::Open
+    }
+}
+
+/// A single-use code that lets someone register while registration is invite only
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteCode {
+    pub code: String,
+    pub created_by: String,
+    pub expires_at: String
+}
+
+/// An account waiting for an admin to approve or reject it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingUser {
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub email_verified: bool
+}
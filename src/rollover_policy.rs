@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+/// Decides how much of a period's leftover balance carries into the next period
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RolloverPolicy {
+    /// Every period starts fresh at the spend limit
+    None,
+    /// Unspent money is added to the next period
+    CarrySurplus,
+    /// Overspending is taken out of the next period
+    CarryDeficit,
+    /// Both unspent money and overspending carry over
+    Both,
+    /// Both carry over, limited to at most `max_surplus` and `max_deficit`
+    Capped { max_surplus: f64, max_deficit: f64 },
+}
+
+impl RolloverPolicy {
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            RolloverPolicy::Capped { max_surplus, max_deficit } => {
+                max_surplus >= 0.0 && max_deficit >= 0.0
+            }
+            _ => true,
+        }
+    }
+
+    /// Amount carried into the next period given the `leftover` of the
+    /// previous one, negative when the previous period was overspent
+    pub fn carry(&self, leftover: f64) -> f64 {
+        match *self {
+            RolloverPolicy::None => 0.0,
+            RolloverPolicy::CarrySurplus => leftover.max(0.0),
+            RolloverPolicy::CarryDeficit => leftover.min(0.0),
+            RolloverPolicy::Both => leftover,
+            RolloverPolicy::Capped { max_surplus, max_deficit } => {
+                leftover.min(max_surplus).max(-max_deficit)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carry() {
+        let capped = RolloverPolicy::Capped { max_surplus: 25.0, max_deficit: 5.0 };
+
+        // Policy, then the carry of a 40 surplus, a 10 surplus and a 50 deficit
+        let cases = [
+            (RolloverPolicy::None, 0.0, 0.0, 0.0),
+            (RolloverPolicy::CarrySurplus, 40.0, 10.0, 0.0),
+            (RolloverPolicy::CarryDeficit, 0.0, 0.0, -50.0),
+            (RolloverPolicy::Both, 40.0, 10.0, -50.0),
+            (capped, 25.0, 10.0, -5.0),
+        ];
+
+        for (policy, surplus, small_surplus, deficit) in cases.iter() {
+            assert_eq!(policy.carry(40.0), *surplus, "{:?}", policy);
+            assert_eq!(policy.carry(10.0), *small_surplus, "{:?}", policy);
+            assert_eq!(policy.carry(-50.0), *deficit, "{:?}", policy);
+            assert_eq!(policy.carry(0.0), 0.0, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn caps_cant_be_negative() {
+        assert!(RolloverPolicy::Capped { max_surplus: 0.0, max_deficit: 0.0 }.is_valid());
+        assert!(!RolloverPolicy::Capped { max_surplus: -1.0, max_deficit: 0.0 }.is_valid());
+        assert!(!RolloverPolicy::Capped { max_surplus: 0.0, max_deficit: -1.0 }.is_valid());
+        assert!(RolloverPolicy::Both.is_valid());
+    }
+}
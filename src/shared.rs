@@ -1,8 +1,23 @@
 use serde::{Deserialize, Serialize};
 
+use crate::account_archive::*;
+use crate::api_key::*;
+use crate::audit_entry::*;
 use crate::budget::*;
 use crate::budget_period::*;
+use crate::budget_period_balance::*;
+use crate::budget_period_summary::*;
+use crate::csv_import::*;
+use crate::export::*;
+use crate::login_throttle::*;
+use crate::period_rule::*;
+use crate::registration::*;
+use crate::rollover_policy::*;
+use crate::statement_import::*;
+use crate::totp::*;
 use crate::transaction::*;
+use crate::transaction_query::*;
+use crate::transaction_search_hit::*;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ResultStatus {
@@ -10,6 +25,7 @@ pub enum ResultStatus {
     InvalidCredentials,
     InvalidAccessToken,
     EntryDoesNotExist,
+    TwoFactorRequired,
     Error(String)
 }
 
@@ -28,12 +44,54 @@ pub struct ChangePasswordForm {
     pub new_password: String
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetRequestForm {
+    pub email: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetConfirmForm {
+    pub token: String,
+    pub new_password: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorLoginForm {
+    pub two_factor_token: String,
+    pub code: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorCodeForm {
+    pub access_token: String,
+    pub code: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcCallbackForm {
+    pub code: String,
+    pub state: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcLinkForm {
+    pub access_token: String,
+    pub code: String,
+    pub state: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenForm {
+    pub token: String
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterAccountForm {
     pub email: String,
     pub first_name: String,
     pub last_name: String,
-    pub password: String
+    pub password: String,
+    pub invite_code: Option<String>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,19 +99,92 @@ pub struct AccessTokenForm {
     pub access_token: String
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListBudgetsForm {
+    pub access_token: String,
+    pub include_archived: Option<bool>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SelectForm {
     pub access_token: String,
     pub id: i64
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListTransactionsForm {
+    pub access_token: String,
+    pub id: i64,
+    #[serde(flatten)]
+    pub query: TransactionQuery
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListTransactionsPeriodForm {
+    pub access_token: String,
+    pub budget_id: i64,
+    pub period_id: i64,
+    #[serde(flatten)]
+    pub query: TransactionQuery
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AddBudgetForm {
     pub access_token: String,
     pub budget_name: String,
     pub budget_spend_limit: f64,
     pub budget_period_length: i64,
-    pub budget_start_date: Option<String>
+    pub budget_start_date: Option<String>,
+    pub budget_timezone: Option<String>,
+    pub budget_period_rule: Option<PeriodRule>,
+    pub budget_rollover_policy: Option<RolloverPolicy>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BudgetRolloverForm {
+    pub access_token: String,
+    pub budget_id: i64,
+    pub rollover_policy: RolloverPolicy
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserTimezoneForm {
+    pub access_token: String,
+    pub timezone: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BudgetTimezoneForm {
+    pub access_token: String,
+    pub budget_id: i64,
+    pub timezone: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditLogForm {
+    pub access_token: String,
+    pub budget_id: Option<i64>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddApiKeyForm {
+    pub access_token: String,
+    pub name: String,
+    pub scope: ApiKeyScope,
+    /// Budgets the key is limited to, every accessible budget when omitted
+    pub budget_ids: Option<Vec<i64>>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserForm {
+    pub access_token: String,
+    pub email: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportAccountForm {
+    pub access_token: String,
+    pub archive: AccountArchive
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -71,6 +202,32 @@ pub struct AccessTokenResult {
     pub access_token: Option<String>
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginResult {
+    pub status: ResultStatus,
+    pub access_token: Option<String>,
+    // Given with `TwoFactorRequired`, exchanged with a code for the access token
+    pub two_factor_token: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcAuthorizeResult {
+    pub status: ResultStatus,
+    pub authorization_url: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorEnrollmentResult {
+    pub status: ResultStatus,
+    pub enrollment: Option<TwoFactorEnrollment>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodesResult {
+    pub status: ResultStatus,
+    pub recovery_codes: Option<Vec<String>>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StatusResult {
     pub status: ResultStatus
@@ -94,12 +251,87 @@ pub struct UserListResult {
     pub users: Option<Vec<String>>
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingUserListResult {
+    pub status: ResultStatus,
+    pub users: Option<Vec<PendingUser>>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockedAccountListResult {
+    pub status: ResultStatus,
+    pub accounts: Option<Vec<LockedAccount>>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewApiKeyResult {
+    pub status: ResultStatus,
+    pub api_key: Option<NewApiKey>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyListResult {
+    pub status: ResultStatus,
+    pub api_keys: Option<Vec<ApiKey>>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteCodeResult {
+    pub status: ResultStatus,
+    pub invite: Option<InviteCode>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupResult {
+    pub status: ResultStatus,
+    pub path: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountArchiveResult {
+    pub status: ResultStatus,
+    pub archive: Option<AccountArchive>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountImportResult {
+    pub status: ResultStatus,
+    pub import: Option<AccountImport>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditLogResult {
+    pub status: ResultStatus,
+    pub entries: Option<Vec<AuditEntry>>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TransactionListResult {
     pub status: ResultStatus,
     pub transactions: Option<Vec<Transaction>>
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransactionPageResult {
+    pub status: ResultStatus,
+    pub transactions: Option<Vec<Transaction>>,
+    pub total_count: Option<i64>,
+    pub next_cursor: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchTransactionsForm {
+    pub access_token: String,
+    pub query: String,
+    pub limit: Option<i64>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransactionSearchResult {
+    pub status: ResultStatus,
+    pub results: Option<Vec<TransactionSearchHit>>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AddTransactionForm {
     pub access_token: String,
@@ -108,7 +340,42 @@ pub struct AddTransactionForm {
     pub transaction_description: String,
     pub transaction_amount: f64,
     pub transaction_recur_days: Option<i64>,
-    pub transaction_recur_until: Option<String>
+    pub transaction_recur_until: Option<String>,
+    pub transaction_kind: Option<TransactionKind>,
+    pub transaction_date: Option<String> // Defaults to now, future dates are scheduled
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportCsvForm {
+    pub access_token: String,
+    pub budget_id: i64,
+    pub csv: String,
+    pub mapping: CsvMapping,
+    pub dry_run: Option<bool> // Preview the import without adding anything
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportStatementForm {
+    pub access_token: String,
+    pub budget_id: i64,
+    pub statement: String,
+    pub date_format: Option<String>, // QIF only, defaults to "%m/%d/%Y"
+    pub credit_kind: Option<TransactionKind>, // Kind given to money coming in, defaults to refund
+    pub dry_run: Option<bool>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportBudgetForm {
+    pub access_token: String,
+    pub budget_id: i64,
+    pub period_id: Option<i64>, // Export a single period instead of the whole budget
+    pub format: ExportFormat
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportResult {
+    pub status: ResultStatus,
+    pub summary: Option<ImportSummary>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -123,6 +390,12 @@ pub struct BudgetPeriodListResult {
     pub budget_periods: Option<Vec<BudgetPeriod>>
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BudgetPeriodBalanceListResult {
+    pub status: ResultStatus,
+    pub balances: Option<Vec<BudgetPeriodBalance>>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BudgetPeriodForm {
     pub access_token: String,
@@ -130,6 +403,23 @@ pub struct BudgetPeriodForm {
     pub period_id: i64
 }
 
+// Selects a single period with `period_id`, or a range of periods with
+// `first_period_id` and `last_period_id`. Defaults to the current period.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BudgetSummaryForm {
+    pub access_token: String,
+    pub budget_id: i64,
+    pub period_id: Option<i64>,
+    pub first_period_id: Option<i64>,
+    pub last_period_id: Option<i64>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BudgetSummaryResult {
+    pub status: ResultStatus,
+    pub summaries: Option<Vec<BudgetPeriodSummary>>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BudgetBalanceResult {
     pub status: ResultStatus,
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::TransactionKind;
+
+/// A single entry read from a bank statement, ready to become a transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatementEntry {
+    pub date: String, // "YYYY-MM-DD"
+    pub name: String,
+    pub description: String,
+    pub amount: f64, // Always positive, the direction is given by `kind`
+    pub kind: TransactionKind,
+    pub external_id: Option<String> // The bank's own ID for the entry, if it has one
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    /// Will be, or has been, added to the budget
+    New,
+    /// Already in the budget, see `duplicate_of`
+    Duplicate,
+    /// Could not be read or doesn't fit the budget, see `reason`
+    Rejected
+}
+
+/// Outcome of importing one line of a statement
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportRow {
+    pub line: u64,
+    pub entry: Option<StatementEntry>,
+    pub status: ImportStatus,
+    pub reason: Option<String>,
+    pub duplicate_of: Option<i64>, // Matching transaction already in the budget
+    pub transaction_id: Option<i64> // Set once the entry has been imported
+}
+
+impl ImportRow {
+    pub fn parsed(line: u64, entry: StatementEntry) -> ImportRow {
+        ImportRow {
+            line,
+            entry: Some(entry),
+            status: ImportStatus::New,
+            reason: None,
+            duplicate_of: None,
+            transaction_id: None
+        }
+    }
+
+    pub fn rejected(line: u64, reason: String) -> ImportRow {
+        ImportRow {
+            line,
+            entry: None,
+            status: ImportStatus::Rejected,
+            reason: Some(reason),
+            duplicate_of: None,
+            transaction_id: None
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportSummary {
+    pub dry_run: bool, // When set nothing was written
+    pub rows: Vec<ImportRow>,
+    pub new_entries: i64,
+    pub duplicates: i64,
+    pub rejected: i64
+}
+
+/// Parses an amount as written in a statement, e.g. "-1,234.50", "$12.00"
+/// or "(12.00)" for a negative amount
+pub fn parse_amount(text: &str) -> Option<f64> {
+    let text = text.trim();
+
+    let (negative, text) = if text.starts_with('(') && text.ends_with(')') {
+        (true, &text[1..text.len() - 1])
+    } else {
+        (false, text)
+    };
+
+    let cleaned: String = text
+        .chars()
+        .filter(|x| x.is_ascii_digit() || *x == '.' || *x == '-' || *x == '+')
+        .collect();
+
+    match cleaned.parse::<f64>() {
+        Ok(x) if x.is_finite() => Some(if negative { -x } else { x }),
+        _ => None
+    }
+}
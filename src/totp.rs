@@ -0,0 +1,316 @@
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+
+use rand::Rng;
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::{generate_token, uri_encode};
+
+/// Issuer shown next to codes in authenticator apps
+pub const TOTP_ISSUER: &str = "Budget Tracker";
+
+/// Seconds each code is valid for
+pub const TOTP_STEP: i64 = 30;
+/// Digits in each code
+pub const TOTP_DIGITS: u32 = 6;
+/// Steps either side of the current one that are still accepted, allowing
+/// for clock drift between the server and the user's device
+const TOTP_WINDOW: i64 = 1;
+/// Bytes in each new secret, the size recommended for HMAC-SHA1
+const SECRET_LENGTH: usize = 20;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// Recovery codes issued when two-factor authentication is enabled
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// Minutes a user has to enter their code after their password
+pub const TWO_FACTOR_LOGIN_MINUTES: i64 = 5;
+/// Wrong codes allowed against each login before the user has to enter their
+/// password again
+pub const TWO_FACTOR_ATTEMPTS: i64 = 5;
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// What a user needs to add their account to an authenticator app
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TwoFactorEnrollment {
+    /// Base32 secret for apps that can't scan the URI
+    pub secret: String,
+    pub otpauth_uri: String
+}
+
+/// Stored two-factor settings of a user, with the secret decrypted
+pub struct TwoFactor {
+    pub secret: Vec<u8>,
+    /// Whether the secret has been confirmed and codes are required to sign in
+    pub enabled: bool,
+    /// Time step of the last accepted code
+    pub last_counter: Option<i64>
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LENGTH];
+    rand::thread_rng().fill(&mut secret[..]);
+
+    secret
+}
+
+/// Single-use code for signing in without the authenticator app
+pub fn generate_recovery_code() -> String {
+    generate_token(10).to_lowercase()
+}
+
+/// Encodes bytes as unpadded RFC 4648 base32, the form authenticator apps expect
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+
+    for chunk in data.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+
+        let bits = buffer.iter().fold(0u64, |acc, x| (acc << 8) | u64::from(*x));
+
+        // Each character holds 5 bits, partial characters are zero filled
+        let chars = (chunk.len() * 8 + 4) / 5;
+
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            result.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+
+    result
+}
+
+/// Link that authenticator apps can import, usually shown as a QR code
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        uri_encode(issuer),
+        uri_encode(account),
+        base32_encode(secret),
+        uri_encode(issuer),
+        TOTP_DIGITS,
+        TOTP_STEP
+    )
+}
+
+/// The code for a time step, as described in RFC 6238
+pub fn code_at(secret: &[u8], counter: i64) -> String {
+    let mut mac = Hmac::new(Sha1::new(), secret);
+    mac.input(&counter.to_be_bytes());
+
+    let result = mac.result();
+    let hash = result.code();
+
+    // Dynamic truncation from RFC 4226
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = (u32::from(hash[offset] & 0x7f) << 24)
+        | (u32::from(hash[offset + 1]) << 16)
+        | (u32::from(hash[offset + 2]) << 8)
+        | u32::from(hash[offset + 3]);
+
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+/// Checks a code against the steps around `unix_time`, returning the step it
+/// belongs to
+///
+/// Steps up to and including `last_counter` are rejected so that each code
+/// can only be used once.
+pub fn verify(secret: &[u8], code: &str, unix_time: i64, last_counter: Option<i64>) -> Option<i64> {
+    let code: String = code.chars().filter(|x| !x.is_whitespace()).collect();
+
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+
+    let current = unix_time / TOTP_STEP;
+
+    ((current - TOTP_WINDOW)..=(current + TOTP_WINDOW))
+        .filter(|x| last_counter.map_or(true, |last| *x > last))
+        .find(|x| code_at(secret, *x) == code)
+}
+
+/// Encrypts a secret with AES-256-GCM using a key derived from the server
+/// secret, returning hex of the nonce, ciphertext and tag
+pub fn encrypt_secret(server_secret: &str, plaintext: &[u8]) -> String {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill(&mut nonce[..]);
+
+    let mut ciphertext = vec![0u8; plaintext.len()];
+    let mut tag = [0u8; TAG_LENGTH];
+
+    AesGcm::new(KeySize::KeySize256, &derive_key(server_secret), &nonce, &[])
+        .encrypt(plaintext, &mut ciphertext, &mut tag);
+
+    let mut result = Vec::with_capacity(NONCE_LENGTH + ciphertext.len() + TAG_LENGTH);
+    result.extend_from_slice(&nonce);
+    result.extend_from_slice(&ciphertext);
+    result.extend_from_slice(&tag);
+
+    result.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Reverses `encrypt_secret`, `None` when the data is corrupt or the server
+/// secret has changed
+pub fn decrypt_secret(server_secret: &str, encrypted: &str) -> Option<Vec<u8>> {
+    if encrypted.len() % 2 != 0 || !encrypted.is_ascii() {
+        return None;
+    }
+
+    let data = (0..encrypted.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&encrypted[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    if data.len() < NONCE_LENGTH + TAG_LENGTH {
+        return None;
+    }
+
+    let (nonce, rest) = data.split_at(NONCE_LENGTH);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+
+    let mut plaintext = vec![0u8; ciphertext.len()];
+
+    let valid = AesGcm::new(KeySize::KeySize256, &derive_key(server_secret), nonce, &[])
+        .decrypt(ciphertext, &mut plaintext, tag);
+
+    if valid {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+fn derive_key(server_secret: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    // Keeps the key distinct from other uses of the server secret
+    hasher.input_str("totp::::");
+    hasher.input_str(server_secret);
+
+    let mut key = [0u8; 32];
+    hasher.result(&mut key);
+
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Secret of the SHA1 test vectors in RFC 6238 appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    // Unix times and the last 6 digits of the 8 digit codes in appendix B
+    const RFC_VECTORS: &[(i64, &str)] = &[
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1111111111, "050471"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+        (20000000000, "353130"),
+    ];
+
+    #[test]
+    fn codes_match_rfc_6238() {
+        for (unix_time, code) in RFC_VECTORS.iter() {
+            assert_eq!(code_at(RFC_SECRET, unix_time / TOTP_STEP), *code, "time {}", unix_time);
+            assert_eq!(verify(RFC_SECRET, code, *unix_time, None), Some(unix_time / TOTP_STEP));
+        }
+    }
+
+    #[test]
+    fn codes_are_accepted_one_step_either_side() {
+        let (unix_time, code) = RFC_VECTORS[3];
+        let counter = unix_time / TOTP_STEP;
+
+        assert_eq!(verify(RFC_SECRET, code, unix_time - TOTP_STEP, None), Some(counter));
+        assert_eq!(verify(RFC_SECRET, code, unix_time + TOTP_STEP, None), Some(counter));
+        assert_eq!(verify(RFC_SECRET, code, unix_time + 2 * TOTP_STEP, None), None);
+
+        // Spaces are ignored, other lengths aren't
+        assert_eq!(verify(RFC_SECRET, "005 924", unix_time, None), Some(counter));
+        assert_eq!(verify(RFC_SECRET, "05924", unix_time, None), None);
+        assert_eq!(verify(RFC_SECRET, "89005924", unix_time, None), None);
+    }
+
+    #[test]
+    fn used_steps_are_rejected() {
+        let (unix_time, code) = RFC_VECTORS[3];
+        let counter = unix_time / TOTP_STEP;
+
+        assert_eq!(verify(RFC_SECRET, code, unix_time, Some(counter - 1)), Some(counter));
+        assert_eq!(verify(RFC_SECRET, code, unix_time, Some(counter)), None);
+
+        // A code from before the last accepted one is rejected even inside the window
+        let earlier = code_at(RFC_SECRET, counter - 1);
+        assert_eq!(verify(RFC_SECRET, &earlier, unix_time, Some(counter)), None);
+
+        let later = code_at(RFC_SECRET, counter + 1);
+        assert_eq!(verify(RFC_SECRET, &later, unix_time, Some(counter)), Some(counter + 1));
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"", ""),
+            (b"f", "MY"),
+            (b"fo", "MZXQ"),
+            (b"foo", "MZXW6"),
+            (b"foob", "MZXW6YQ"),
+            (b"fooba", "MZXW6YTB"),
+            (b"foobar", "MZXW6YTBOI"),
+        ];
+
+        for (data, encoded) in cases.iter() {
+            assert_eq!(base32_encode(data), *encoded);
+        }
+
+        assert_eq!(base32_encode(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn otpauth_uris_are_encoded() {
+        assert_eq!(
+            otpauth_uri(TOTP_ISSUER, "alice@example.com", b"foobar"),
+            "otpauth://totp/Budget%20Tracker:alice%40example.com?secret=MZXW6YTBOI\
+            &issuer=Budget%20Tracker&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn secrets_round_trip_only_with_the_same_server_secret() {
+        let secret = generate_secret();
+        let encrypted = encrypt_secret("server secret", &secret);
+
+        assert_eq!(decrypt_secret("server secret", &encrypted), Some(secret.clone()));
+        assert_eq!(decrypt_secret("other secret", &encrypted), None);
+
+        // Each encryption uses a new nonce
+        assert_ne!(encrypt_secret("server secret", &secret), encrypted);
+
+        // Tampering with any part is detected
+        let mut tampered = encrypted.clone().into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+
+        assert_eq!(decrypt_secret("server secret", &tampered), None);
+        assert_eq!(decrypt_secret("server secret", &encrypted[..encrypted.len() - 1]), None);
+        assert_eq!(decrypt_secret("server secret", "zz"), None);
+        assert_eq!(decrypt_secret("server secret", ""), None);
+    }
+}
@@ -1,6 +1,65 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+/// How a transaction affects its budget
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    /// Money spent, counts towards the amount spent
+    Expense,
+    /// Money returned from an earlier expense, reduces the amount spent
+    Refund,
+    /// Money added to the budget, raises the amount available
+    Income,
+    /// Money moved between budgets, positive amounts raise the amount
+    /// available and negative amounts lower it
+    Transfer
+}
+
+impl TransactionKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TransactionKind::Expense => "expense",
+            TransactionKind::Refund => "refund",
+            TransactionKind::Income => "income",
+            TransactionKind::Transfer => "transfer"
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<TransactionKind> {
+        match s {
+            "expense" => Some(TransactionKind::Expense),
+            "refund" => Some(TransactionKind::Refund),
+            "income" => Some(TransactionKind::Income),
+            "transfer" => Some(TransactionKind::Transfer),
+            _ => None
+        }
+    }
+}
+
+impl Default for TransactionKind {
+    fn default() -> TransactionKind {
+        TransactionKind::Expense
+    }
+}
+
+impl ToSql for TransactionKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for TransactionKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str().and_then(|s| match TransactionKind::from_str(s) {
+            Some(kind) => Ok(kind),
+            None => Err(FromSqlError::InvalidType)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub transaction_id: Option<i64>,
     pub budget_id: i64,
@@ -10,7 +69,10 @@ pub struct Transaction {
     pub date: Option<String>,
     pub amount: f64,
     pub recur_days: Option<i64>,
-    pub recur_until: Option<String>
+    pub recur_until: Option<String>,
+    pub deleted_at: Option<String>,
+    pub kind: TransactionKind,
+    pub external_id: Option<String> // Bank reference of imported transactions
 }
 
 impl Transaction {
@@ -25,7 +87,10 @@ impl Transaction {
             date: None,
             amount,
             recur_days,
-            recur_until
+            recur_until,
+            deleted_at: None,
+            kind: TransactionKind::Expense,
+            external_id: None
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::Transaction;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionSort {
+    Date,
+    Amount,
+    Name
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    Asc,
+    Desc
+}
+
+/// Sorting, filtering and pagination options for transaction listings
+///
+/// Every field is optional, by default the first 100 transactions are returned
+/// newest first, pages are never longer than 1000
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TransactionQuery {
+    pub sort: Option<TransactionSort>,
+    pub direction: Option<SortDirection>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>, // `next_cursor` of the previous page
+    pub date_from: Option<String>, // Note - these dates are inclusive
+    pub date_to: Option<String>,
+    pub amount_min: Option<f64>,
+    pub amount_max: Option<f64>,
+    pub email: Option<String>,
+    pub text: Option<String> // Matched against the name and description
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    pub total_count: i64, // Number of transactions matching the filters, across all pages
+    pub next_cursor: Option<String> // None on the last page
+}
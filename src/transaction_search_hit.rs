@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::Transaction;
+
+/// A transaction matching a search, with the matching parts of its name and
+/// description wrapped in `<mark>` tags
+///
+/// The snippets are HTML escaped, so they can be inserted into a page as is
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransactionSearchHit {
+    pub transaction: Transaction,
+    pub name_snippet: String,
+    pub description_snippet: String
+}
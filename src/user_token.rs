@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+use rusqlite::types::{ToSql, ToSqlOutput};
+
+/// What a single-use token sent to a user may be exchanged for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+    PasswordReset,
+    EmailVerification,
+    /// Issued after the password when signing in with two-factor authentication
+    TwoFactorLogin
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::EmailVerification => "email_verification",
+            TokenPurpose::TwoFactorLogin => "two_factor_login"
+        }
+    }
+}
+
+impl ToSql for TokenPurpose {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
@@ -1,34 +1,103 @@
-use std::time::SystemTime;
+use chrono::{DateTime, Utc, NaiveDate, NaiveDateTime, ParseResult, Datelike, Timelike};
+use chrono_tz::Tz;
 
-use chrono::{Date, DateTime, Utc, FixedOffset, ParseResult, Datelike, Timelike, TimeZone, Offset};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 
-pub fn get_now() -> DateTime<FixedOffset> {
-    // TODO: Don't hard code this to Tasmanian time
-    DateTime::from_utc(Utc::now().naive_utc(), TimeZone::from_offset(&FixedOffset::east(10*60*60)))
+pub fn get_now(tz: &Tz) -> DateTime<Tz> {
+    Utc::now().with_timezone(tz)
 }
 
-pub fn get_current_date() -> String {
-    let now = get_now();
+pub fn get_today(tz: &Tz) -> NaiveDate {
+    get_now(tz).naive_local().date()
+}
+
+pub fn get_current_date(tz: &Tz) -> String {
+    to_sqlite_date(&get_today(tz))
+}
+
+pub fn get_current_date_time(tz: &Tz) -> String {
+    to_sqlite_date_time(&get_now(tz).naive_local())
+}
+
+/// Generates a random string of letters and digits, suitable for passwords and tokens
+pub fn generate_token(length: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(length).collect()
+}
+
+/// Checks that an email address is plausible, a single "@" between a local
+/// part and a domain containing a dot, without whitespace
+pub fn is_valid_email(email: &str) -> bool {
+    let mut parts = email.split('@');
 
-    format!("{:0>2}-{:0>2}-{:0>2}", now.year(), now.month(), now.day())
+    let (local, domain) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => (local, domain),
+        _ => return false,
+    };
+
+    email.len() <= 254
+        && !local.is_empty()
+        && !email.chars().any(|x| x.is_whitespace() || x.is_control())
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains("..")
 }
 
-pub fn get_current_date_time() -> String {
-    let now = get_now();
+/// Percent-encodes everything but unreserved characters, for use in URL
+/// paths and query strings
+pub fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (x as char).to_string(),
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
 
-    format!("{}-{:0>2}-{:0>2} {:0>2}:{:0>2}:{:0>2}.{:0>3}", now.year(), now.month(), now.day(),
-        now.hour(), now.minute(), now.second(), now.timestamp_subsec_millis())
+/// Escapes text for inclusion in HTML element content or attribute values
+pub fn escape_html(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for x in s.chars() {
+        match x {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(x),
+        }
+    }
+    result
 }
 
-pub fn from_sqlite_date(sdate: &String) -> ParseResult<DateTime<FixedOffset>> {    
-    DateTime::parse_from_str(
-                &format!("{} 00:00:00 +0000", sdate),
-                "%Y-%-m-%-d %T %z"
-    )
+/// Parses an IANA timezone name, e.g. "Australia/Hobart"
+pub fn parse_timezone(name: &str) -> Option<Tz> {
+    name.parse::<Tz>().ok()
 }
 
-pub fn to_sqlite_date(cdate: &DateTime<FixedOffset>) -> String {
-    let mut sdate = String::with_capacity(11);
+pub fn from_sqlite_date(sdate: &String) -> ParseResult<NaiveDate> {
+    NaiveDate::parse_from_str(sdate, "%Y-%m-%d")
+}
 
+pub fn to_sqlite_date(cdate: &NaiveDate) -> String {
     format!("{}-{:0>2}-{:0>2}", cdate.year(), cdate.month(), cdate.day())
-}
\ No newline at end of file
+}
+
+/// Parses a timestamp as stored in the database, plain dates are taken as midnight
+pub fn from_sqlite_date_time(sdate: &str) -> ParseResult<NaiveDateTime> {
+    match NaiveDateTime::parse_from_str(sdate, "%Y-%m-%d %H:%M:%S%.f") {
+        Ok(x) => Ok(x),
+        Err(error) => match NaiveDate::parse_from_str(sdate, "%Y-%m-%d") {
+            Ok(x) => Ok(x.and_hms(0, 0, 0)),
+            Err(_) => Err(error)
+        }
+    }
+}
+
+/// Formats a timestamp in the canonical form stored in the database,
+/// "YYYY-MM-DD HH:MM:SS.mmm", which sorts and compares correctly as text
+pub fn to_sqlite_date_time(cdate: &NaiveDateTime) -> String {
+    format!("{}-{:0>2}-{:0>2} {:0>2}:{:0>2}:{:0>2}.{:0>3}", cdate.year(), cdate.month(), cdate.day(),
+        cdate.hour(), cdate.minute(), cdate.second(), cdate.timestamp_subsec_millis().min(999))
+}